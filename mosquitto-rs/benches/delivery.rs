@@ -0,0 +1,46 @@
+//! Measures the cost of queueing a received message for the subscriber
+//! when the sender is guarded by a mutex, as it used to be, versus
+//! using the `Sender` directly, as the client now does.
+use async_channel::unbounded;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use mosquitto_rs::{Event, Message};
+use std::sync::Mutex;
+
+const BATCH: usize = 1000;
+
+fn message_delivery(c: &mut Criterion) {
+    let msg = Message {
+        topic: "sensors/kitchen/temperature".into(),
+        payload: b"21.5".as_slice().into(),
+        ..Default::default()
+    };
+    let mut group = c.benchmark_group("message_delivery");
+
+    let (tx, rx) = unbounded();
+    let locked = Mutex::new(Some(tx));
+    group.bench_function("mutex", |b| {
+        b.iter(|| {
+            for _ in 0..BATCH {
+                if let Some(tx) = locked.lock().unwrap().as_ref() {
+                    black_box(tx.try_send(Event::Message(msg.clone())).is_ok());
+                }
+            }
+            while rx.try_recv().is_ok() {}
+        })
+    });
+
+    let (tx, rx) = unbounded();
+    group.bench_function("direct", |b| {
+        b.iter(|| {
+            for _ in 0..BATCH {
+                black_box(tx.try_send(Event::Message(msg.clone())).is_ok());
+            }
+            while rx.try_recv().is_ok() {}
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, message_delivery);
+criterion_main!(benches);
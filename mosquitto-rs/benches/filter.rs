@@ -0,0 +1,63 @@
+//! Compares testing every filter for each message against reusing
+//! the filters that matched an earlier message with the same topic,
+//! as done for `Client::filtered_subscriber`.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use mosquitto_rs::TopicFilter;
+use std::collections::HashMap;
+
+const ROOMS: &[&str] = &["kitchen", "garage", "bedroom", "office", "hall"];
+const TOPICS: &[&str] = &[
+    "sensors/kitchen/temperature",
+    "sensors/kitchen/humidity",
+    "sensors/garage/temperature",
+    "sensors/office/humidity",
+];
+
+fn filter_matching(c: &mut Criterion) {
+    let mut filters = vec![];
+    for room in ROOMS {
+        for measure in ["temperature", "humidity", "+", "#"] {
+            let filter = if measure == "#" {
+                format!("sensors/{room}/#")
+            } else {
+                format!("sensors/{room}/{measure}")
+            };
+            filters.push(TopicFilter::new(&filter).unwrap());
+        }
+    }
+    filters.push(TopicFilter::new("sensors/+/temperature").unwrap());
+    filters.push(TopicFilter::new("#").unwrap());
+
+    let mut group = c.benchmark_group("filter_matching");
+
+    group.bench_function("every_filter", |b| {
+        b.iter(|| {
+            for topic in TOPICS {
+                let matched = filters.iter().filter(|f| f.matches(topic)).count();
+                black_box(matched);
+            }
+        })
+    });
+
+    let mut cache: HashMap<&str, Vec<usize>> = HashMap::new();
+    group.bench_function("cached", |b| {
+        b.iter(|| {
+            for topic in TOPICS {
+                let matched = cache.entry(topic).or_insert_with(|| {
+                    filters
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, f)| f.matches(topic))
+                        .map(|(idx, _)| idx)
+                        .collect()
+                });
+                black_box(matched.len());
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, filter_matching);
+criterion_main!(benches);
@@ -0,0 +1,42 @@
+//! Compares the cost of delivering a large payload to several consumers
+//! when the payload is an owned `Vec<u8>` (one copy per clone) versus
+//! the `Arc<[u8]>` that `Message` uses (a single copy out of the C buffer).
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use mosquitto_rs::{Message, QoS};
+
+const FAN_OUT: usize = 4;
+
+fn payload_fan_out(c: &mut Criterion) {
+    let raw = vec![0x5au8; 1024 * 1024];
+    let mut group = c.benchmark_group("payload_fan_out");
+
+    group.bench_with_input(BenchmarkId::new("vec", "1MiB"), &raw, |b, raw| {
+        b.iter(|| {
+            let payload: Vec<u8> = raw.to_vec();
+            let copies: Vec<Vec<u8>> = (0..FAN_OUT).map(|_| payload.clone()).collect();
+            black_box(copies);
+        })
+    });
+
+    group.bench_with_input(BenchmarkId::new("message", "1MiB"), &raw, |b, raw| {
+        b.iter(|| {
+            let msg = Message {
+                topic: "firmware/image".into(),
+                payload: raw.as_slice().into(),
+                qos: QoS::AtMostOnce,
+                retain: false,
+                mid: 1,
+                dup: false,
+                sequence: 1,
+                properties: vec![],
+            };
+            let copies: Vec<Message> = (0..FAN_OUT).map(|_| msg.clone()).collect();
+            black_box(copies);
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, payload_fan_out);
+criterion_main!(benches);
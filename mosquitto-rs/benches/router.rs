@@ -0,0 +1,59 @@
+//! Compares dispatching messages via the path router, which finds the
+//! single matching route in a tree, against the wildcard router, which
+//! tests the topic against the filter of every route.
+use criterion::{criterion_group, criterion_main, Criterion};
+use mosquitto_rs::router::{MqttRouter, RouterFragment, Topic};
+use mosquitto_rs::{Client, Message};
+
+const BUILDINGS: usize = 50;
+const MEASURES: &[&str] = &["temperature", "humidity", "power", "state"];
+
+async fn handler(Topic(_topic): Topic) -> anyhow::Result<()> {
+    Ok(())
+}
+
+fn routes() -> RouterFragment {
+    let mut fragment = RouterFragment::new();
+    for building in 0..BUILDINGS {
+        for measure in MEASURES {
+            fragment.route(format!("building{building}/{{room}}/{measure}"), handler);
+        }
+    }
+    fragment
+}
+
+fn router_dispatch(c: &mut Criterion) {
+    let mut paths = <MqttRouter>::new(Client::with_auto_id().unwrap());
+    paths.register(routes()).unwrap();
+    let mut wildcard = <MqttRouter>::new_wildcard(Client::with_auto_id().unwrap());
+    wildcard.register(routes()).unwrap();
+
+    let messages: Vec<Message> = [
+        "building0/kitchen/temperature",
+        "building25/garage/power",
+        "building49/office/state",
+    ]
+    .into_iter()
+    .map(|topic| Message {
+        topic: topic.into(),
+        ..Default::default()
+    })
+    .collect();
+
+    let mut group = c.benchmark_group("router_dispatch");
+    for (name, router) in [("paths", &paths), ("wildcard", &wildcard)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                smol::block_on(async {
+                    for message in &messages {
+                        router.dispatch(message.clone(), ()).await.unwrap();
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, router_dispatch);
+criterion_main!(benches);
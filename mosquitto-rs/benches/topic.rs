@@ -0,0 +1,39 @@
+//! Compares allocating a fresh topic string for every message against
+//! sharing the allocation through a `TopicCache`.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use mosquitto_rs::TopicCache;
+use std::sync::Arc;
+
+const TOPICS: &[&str] = &[
+    "sensors/kitchen/temperature",
+    "sensors/kitchen/humidity",
+    "sensors/garage/temperature",
+    "sensors/garage/humidity",
+];
+
+fn topic_delivery(c: &mut Criterion) {
+    let mut group = c.benchmark_group("topic_delivery");
+
+    group.bench_function("allocate", |b| {
+        b.iter(|| {
+            for topic in TOPICS {
+                let topic: Arc<str> = (*topic).into();
+                black_box(topic);
+            }
+        })
+    });
+
+    let cache = TopicCache::new(TopicCache::DEFAULT_CAPACITY);
+    group.bench_function("interned", |b| {
+        b.iter(|| {
+            for topic in TOPICS {
+                black_box(cache.intern(topic));
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, topic_delivery);
+criterion_main!(benches);
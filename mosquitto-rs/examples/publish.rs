@@ -40,7 +40,7 @@ fn main() -> Result<(), Error> {
             self.bump_and_print();
         }
 
-        fn on_subscribe(&self, mosq: &mut Mosq, mid: MessageId, granted_qos: &[QoS]) {
+        fn on_subscribe(&self, mosq: &mut Mosq, mid: MessageId, granted_qos: &[GrantedQoS]) {
             println!("on_subscribe: mid={mid} {granted_qos:?}");
             let mid = mosq
                 .publish("test/topic", b"hello!", QoS::AtMostOnce, false)
@@ -52,7 +52,7 @@ fn main() -> Result<(), Error> {
             &self,
             mosq: &mut Mosq,
             mid: MessageId,
-            topic: String,
+            topic: &str,
             payload: &[u8],
             qos: QoS,
             retain: bool,
@@ -0,0 +1,154 @@
+use crate::Message;
+use std::time::Duration;
+
+/// Accumulates broker statistics published by the broker in the `$SYS`
+/// topic tree.
+///
+/// Subscribe to [BrokerStats::TOPIC] and feed each message received from
+/// the [subscriber](struct.Client.html#method.subscriber) channel into
+/// [update](#method.update). Fields are `None` until the broker has
+/// published the corresponding value.
+///
+/// ```no_run
+/// use mosquitto_rs::*;
+///
+/// async fn monitor(client: &Client) -> Result<(), Error> {
+///     let events = client.subscriber().unwrap();
+///     client.subscribe(BrokerStats::TOPIC, QoS::AtMostOnce).await?;
+///
+///     let mut stats = BrokerStats::default();
+///     while let Ok(Event::Message(msg)) = events.recv().await {
+///         if stats.update(&msg) {
+///             println!("{stats:?}");
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BrokerStats {
+    /// `$SYS/broker/version`
+    pub version: Option<String>,
+    /// `$SYS/broker/uptime`
+    pub uptime: Option<Duration>,
+    /// `$SYS/broker/clients/connected`
+    pub clients_connected: Option<u64>,
+    /// `$SYS/broker/clients/disconnected`
+    pub clients_disconnected: Option<u64>,
+    /// `$SYS/broker/clients/total`
+    pub clients_total: Option<u64>,
+    /// `$SYS/broker/clients/maximum`
+    pub clients_maximum: Option<u64>,
+    /// `$SYS/broker/messages/received`
+    pub messages_received: Option<u64>,
+    /// `$SYS/broker/messages/sent`
+    pub messages_sent: Option<u64>,
+    /// `$SYS/broker/messages/stored`
+    pub messages_stored: Option<u64>,
+    /// `$SYS/broker/retained messages/count`
+    pub retained_messages: Option<u64>,
+    /// `$SYS/broker/subscriptions/count`
+    pub subscriptions: Option<u64>,
+    /// `$SYS/broker/bytes/received`
+    pub bytes_received: Option<u64>,
+    /// `$SYS/broker/bytes/sent`
+    pub bytes_sent: Option<u64>,
+}
+
+impl BrokerStats {
+    /// The subscription pattern that covers all of the statistics
+    /// understood by `BrokerStats`.
+    pub const TOPIC: &'static str = "$SYS/broker/#";
+
+    /// Update the statistics from a message received from the `$SYS` tree.
+    /// Returns true if the message was recognized and parsed,
+    /// false if the topic is not one that is tracked, or if the payload
+    /// could not be parsed.
+    pub fn update(&mut self, msg: &Message) -> bool {
+        let Some(leaf) = msg.topic.strip_prefix("$SYS/broker/") else {
+            return false;
+        };
+        let Ok(value) = std::str::from_utf8(&msg.payload) else {
+            return false;
+        };
+        let value = value.trim();
+
+        let counter = match leaf {
+            "version" => {
+                self.version.replace(value.to_string());
+                return true;
+            }
+            "uptime" => {
+                // Mosquitto reports this as "1234 seconds"
+                let secs = value.split_whitespace().next().unwrap_or("");
+                return match secs.parse() {
+                    Ok(secs) => {
+                        self.uptime.replace(Duration::from_secs(secs));
+                        true
+                    }
+                    Err(_) => false,
+                };
+            }
+            "clients/connected" | "clients/active" => &mut self.clients_connected,
+            "clients/disconnected" | "clients/inactive" => &mut self.clients_disconnected,
+            "clients/total" => &mut self.clients_total,
+            "clients/maximum" => &mut self.clients_maximum,
+            "messages/received" => &mut self.messages_received,
+            "messages/sent" => &mut self.messages_sent,
+            "messages/stored" | "store/messages/count" => &mut self.messages_stored,
+            "retained messages/count" => &mut self.retained_messages,
+            "subscriptions/count" => &mut self.subscriptions,
+            "bytes/received" | "load/bytes/received" => &mut self.bytes_received,
+            "bytes/sent" | "load/bytes/sent" => &mut self.bytes_sent,
+            _ => return false,
+        };
+
+        match value.parse() {
+            Ok(v) => {
+                counter.replace(v);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn msg(topic: &str, payload: &str) -> Message {
+        Message {
+            topic: topic.into(),
+            payload: payload.as_bytes().into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_stats() {
+        let mut stats = BrokerStats::default();
+        assert!(stats.update(&msg("$SYS/broker/version", "mosquitto version 2.0.18")));
+        assert!(stats.update(&msg("$SYS/broker/uptime", "3600 seconds")));
+        assert!(stats.update(&msg("$SYS/broker/clients/connected", "4")));
+        assert!(stats.update(&msg("$SYS/broker/messages/received", "100")));
+        assert!(stats.update(&msg("$SYS/broker/bytes/sent", "2048")));
+        assert!(stats.update(&msg("$SYS/broker/load/bytes/received", "4096")));
+        assert!(!stats.update(&msg("$SYS/broker/load/messages/received/1min", "1.5")));
+        assert!(!stats.update(&msg("$SYS/broker/clients/connected", "lots")));
+        assert!(!stats.update(&msg("not/sys", "1")));
+
+        assert_eq!(
+            stats,
+            BrokerStats {
+                version: Some("mosquitto version 2.0.18".to_string()),
+                uptime: Some(Duration::from_secs(3600)),
+                clients_connected: Some(4),
+                messages_received: Some(100),
+                bytes_sent: Some(2048),
+                bytes_received: Some(4096),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use crate::lowlevel::sys::mosq_err_t;
 use std::collections::HashMap;
 use std::os::raw::c_int;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -15,10 +16,31 @@ pub enum Error {
     Mosq(mosq_err_t),
     #[error("mosq error code {0}")]
     UnknownMosq(c_int),
+    #[error("payload length {0} cannot be represented as a C int")]
+    PayloadLength(usize),
     #[error("hostname resolution error: {0}")]
     Resolution(String),
+    #[error("invalid broker URL: {0}")]
+    InvalidUrl(String),
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("server certificate fingerprint {presented} does not match any pinned fingerprint")]
+    CertificatePinMismatch { presented: String },
     #[error("broker rejected connection")]
     RejectedConnection(crate::ConnectionStatus),
+    #[error("failed to start the message loop thread: {0}")]
+    LoopStart(#[source] Box<Error>),
+    #[error("operation timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("publish rejected: {0}")]
+    PublishRejected(String),
+    #[cfg(feature = "json")]
+    #[error("failed to decode the payload of the message on {topic}: {source}")]
+    Decode {
+        topic: String,
+        #[source]
+        source: serde_json::Error,
+    },
 }
 
 lazy_static::lazy_static! {
@@ -0,0 +1,321 @@
+use crate::lowlevel::sys::mosq_err_t;
+use crate::{Error, Message};
+use async_channel::Sender;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A pre-parsed MQTT subscription filter, such as `sensors/+/temperature`
+/// or `sensors/#`.
+///
+/// The filter is split into its levels once, when it is created, so that
+/// testing a topic against it doesn't need to re-parse the filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicFilter {
+    filter: String,
+    levels: Vec<Level>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Level {
+    Literal(String),
+    /// `+`
+    SingleWildcard,
+    /// `#`
+    MultiWildcard,
+}
+
+impl TopicFilter {
+    /// Parse `filter`, returning `Error::Mosq(MOSQ_ERR_INVAL)` if it is
+    /// not a valid subscription filter.
+    pub fn new(filter: &str) -> Result<Self, Error> {
+        let invalid = || Error::Mosq(mosq_err_t::MOSQ_ERR_INVAL);
+        if filter.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut levels = vec![];
+        for level in filter.split('/') {
+            if levels.last() == Some(&Level::MultiWildcard) {
+                // `#` must be the final level
+                return Err(invalid());
+            }
+            levels.push(match level {
+                "+" => Level::SingleWildcard,
+                "#" => Level::MultiWildcard,
+                _ if level.contains(['+', '#']) => return Err(invalid()),
+                _ => Level::Literal(level.to_string()),
+            });
+        }
+
+        Ok(Self {
+            filter: filter.to_string(),
+            levels,
+        })
+    }
+
+    /// Returns the filter as it was originally specified
+    pub fn as_str(&self) -> &str {
+        &self.filter
+    }
+
+    /// Returns true if `topic` matches the filter.
+    /// As required by the MQTT specification, topics starting with `$`
+    /// are not matched by a filter that starts with a wildcard.
+    pub fn matches(&self, topic: &str) -> bool {
+        if topic.starts_with('$')
+            && matches!(
+                self.levels.first(),
+                Some(Level::SingleWildcard | Level::MultiWildcard)
+            )
+        {
+            return false;
+        }
+
+        let mut parts = topic.split('/');
+        for level in &self.levels {
+            match level {
+                Level::MultiWildcard => return true,
+                Level::SingleWildcard => {
+                    if parts.next().is_none() {
+                        return false;
+                    }
+                }
+                Level::Literal(literal) => {
+                    if parts.next() != Some(literal.as_str()) {
+                        return false;
+                    }
+                }
+            }
+        }
+        parts.next().is_none()
+    }
+
+    /// Returns true if every topic that matches `other` also matches
+    /// this filter, so that subscribing to both would deliver the
+    /// messages published to those topics twice
+    #[cfg(feature = "router")]
+    pub(crate) fn covers(&self, other: &TopicFilter) -> bool {
+        if matches!(
+            self.levels.first(),
+            Some(Level::SingleWildcard | Level::MultiWildcard)
+        ) && matches!(other.levels.first(), Some(Level::Literal(l)) if l.starts_with('$'))
+        {
+            return false;
+        }
+
+        let mut others = other.levels.iter();
+        for level in &self.levels {
+            match (level, others.next()) {
+                (Level::MultiWildcard, _) => return true,
+                (Level::SingleWildcard, Some(Level::SingleWildcard | Level::Literal(_))) => {}
+                (Level::Literal(literal), Some(Level::Literal(other))) if literal == other => {}
+                _ => return false,
+            }
+        }
+        others.next().is_none()
+    }
+}
+
+impl std::fmt::Display for TopicFilter {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(&self.filter)
+    }
+}
+
+impl std::str::FromStr for TopicFilter {
+    type Err = Error;
+    fn from_str(filter: &str) -> Result<Self, Error> {
+        Self::new(filter)
+    }
+}
+
+/// The channels created by `Client::filtered_subscriber`.
+///
+/// Messages are typically delivered to a small set of topics, so rather
+/// than testing every filter for every message, the indices of the filters
+/// that match a topic are remembered and reused for later messages with
+/// the same topic.  The cache is discarded whenever the set of filters
+/// changes, and is bounded in the same way as `TopicCache`.
+///
+/// The channels carry `Arc<Message>`, so that a message that matches
+/// several filters is shared between their receivers rather than cloned
+/// for each of them.
+pub(crate) struct FilterSet {
+    filters: Vec<(TopicFilter, Sender<Arc<Message>>)>,
+    matches: HashMap<Arc<str>, Vec<usize>>,
+}
+
+impl FilterSet {
+    const MATCH_CACHE_CAPACITY: usize = 1024;
+
+    pub(crate) fn new() -> Self {
+        Self {
+            filters: vec![],
+            matches: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, filter: TopicFilter, tx: Sender<Arc<Message>>) {
+        self.filters.push((filter, tx));
+        self.matches.clear();
+    }
+
+    /// Deliver `message` to each of the matching channels.
+    /// Returns true if it was delivered to at least one of them; a
+    /// channel whose receiver has been dropped doesn't count, so that
+    /// the message can be delivered elsewhere instead of being lost.
+    pub(crate) fn dispatch(&mut self, message: &Arc<Message>) -> bool {
+        if self.filters.is_empty() {
+            return false;
+        }
+
+        if !self.matches.contains_key(&message.topic) {
+            if self.matches.len() >= Self::MATCH_CACHE_CAPACITY {
+                self.matches.clear();
+            }
+            let indices = self
+                .filters
+                .iter()
+                .enumerate()
+                .filter(|(_, (filter, _))| filter.matches(&message.topic))
+                .map(|(idx, _)| idx)
+                .collect();
+            self.matches.insert(Arc::clone(&message.topic), indices);
+        }
+
+        let mut closed = false;
+        let mut delivered = false;
+        for &idx in &self.matches[&message.topic] {
+            if self.filters[idx].1.try_send(Arc::clone(message)).is_ok() {
+                delivered = true;
+            } else {
+                closed = true;
+            }
+        }
+
+        if closed {
+            // A receiver was dropped; stop matching against its filter
+            self.filters.retain(|(_, tx)| !tx.is_closed());
+            self.matches.clear();
+        }
+        delivered
+    }
+
+    /// Close all of the channels, signalling the end of the session
+    pub(crate) fn close(&mut self) {
+        for (_, tx) in self.filters.drain(..) {
+            tx.close();
+        }
+        self.matches.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_channel::unbounded;
+
+    #[test]
+    fn parse() {
+        for valid in ["a", "a/b", "+", "#", "a/+/c", "a/#", "+/+", "/a", "a//b"] {
+            assert!(TopicFilter::new(valid).is_ok(), "{valid}");
+        }
+        for invalid in ["", "a/#/b", "a+", "a/b#", "#/a", "a/+b/c"] {
+            assert!(TopicFilter::new(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn matching() {
+        for (filter, topic, expected) in [
+            ("a/b", "a/b", true),
+            ("a/b", "a/c", false),
+            ("a/b", "a/b/c", false),
+            ("a/+", "a/b", true),
+            ("a/+", "a/b/c", false),
+            ("a/+", "a", false),
+            ("a/+/c", "a/b/c", true),
+            ("a/#", "a", true),
+            ("a/#", "a/b/c", true),
+            ("a/#", "b/c", false),
+            ("#", "a/b", true),
+            ("+/+", "/a", true),
+            ("#", "$SYS/broker", false),
+            ("+/broker", "$SYS/broker", false),
+            ("$SYS/#", "$SYS/broker", true),
+        ] {
+            let f = TopicFilter::new(filter).unwrap();
+            assert_eq!(f.matches(topic), expected, "{filter} {topic}");
+        }
+    }
+
+    #[cfg(feature = "router")]
+    #[test]
+    fn covers() {
+        for (filter, other, expected) in [
+            ("a/b", "a/b", true),
+            ("a/+", "a/b", true),
+            ("a/b", "a/+", false),
+            ("a/+", "a/+/c", false),
+            ("a/#", "a", true),
+            ("a/#", "a/+/c/#", true),
+            ("a/+/#", "a/#", false),
+            ("a/+", "a/#", false),
+            ("#", "+/b", true),
+            ("+/+/c/#", "+/b/c", true),
+            ("#", "$SYS/broker", false),
+            ("$SYS/#", "$SYS/broker", true),
+        ] {
+            let f = TopicFilter::new(filter).unwrap();
+            let o = TopicFilter::new(other).unwrap();
+            assert_eq!(f.covers(&o), expected, "{filter} {other}");
+        }
+    }
+
+    #[test]
+    fn filter_set() {
+        let msg = |topic: &str| {
+            Arc::new(Message {
+                topic: topic.into(),
+                ..Default::default()
+            })
+        };
+
+        let mut set = FilterSet::new();
+        assert!(!set.dispatch(&msg("a/b")));
+
+        let (all_tx, all_rx) = unbounded();
+        let (b_tx, b_rx) = unbounded();
+        set.add(TopicFilter::new("a/#").unwrap(), all_tx);
+        set.add(TopicFilter::new("a/b").unwrap(), b_tx);
+
+        assert!(set.dispatch(&msg("a/b")));
+        assert!(set.dispatch(&msg("a/b")));
+        assert!(set.dispatch(&msg("a/c")));
+        assert!(!set.dispatch(&msg("x")));
+        assert_eq!(all_rx.len(), 3);
+        assert_eq!(b_rx.len(), 2);
+
+        // The matching channels share the same message
+        let (all_msg, b_msg) = (all_rx.try_recv().unwrap(), b_rx.try_recv().unwrap());
+        assert!(Arc::ptr_eq(&all_msg, &b_msg));
+        drop(all_msg);
+        assert_eq!(Message::into_owned(b_msg).topic.as_ref(), "a/b");
+
+        drop(b_rx);
+        assert!(set.dispatch(&msg("a/b")));
+        assert_eq!(set.filters.len(), 1);
+        assert_eq!(all_rx.len(), 3);
+
+        // A message that only matches dropped receivers is reported as
+        // undelivered, so that it goes to the default channel instead
+        let (x_tx, x_rx) = unbounded();
+        set.add(TopicFilter::new("x").unwrap(), x_tx);
+        drop(x_rx);
+        assert!(!set.dispatch(&msg("x")));
+        assert_eq!(set.filters.len(), 1);
+
+        set.close();
+        assert!(all_rx.is_closed());
+    }
+}
@@ -36,16 +36,39 @@
 //! The following feature flags are available:
 //!
 //! * `router` - include the router module and `MqttRouter` type. This is on by default.
+//! * `json` - allow publishing `serde_json::Value` payloads, and receiving deserialized payloads via `Client::typed_stream`. This is enabled by `router`.
 //! * `vendored-mosquitto` - use bundled libmosquitto 2.4 library. This is on by default.
 //! * `vendored-mosquitto-tls` - enable tls support in the bundled libmosquitto. This is on by default.
 //! * `vendored-openssl` - build openssl from source, rather than using the system library. Recommended for macOS and Windows users to enable this.
+//! * `log-bridge` - pass the messages logged by libmosquitto, and the diagnostics produced by this crate, on to the `log` crate. This is on by default. Without it the `log` crate isn't used, and libmosquitto messages are only delivered to `Callbacks::on_log`.
+//! * `openssl` - allow passing an `openssl::ssl::SslContext` to the client via `Client::set_ssl_context`, and include the reasons from the OpenSSL error queue in `Error::Tls` when a TLS handshake fails.
+mod broker_stats;
+mod broker_url;
 mod client;
 mod error;
+mod filter;
+mod log;
 mod lowlevel;
+mod payload;
+mod properties;
+mod recent;
 #[cfg_attr(docsrs, doc(cfg(feature = "router")))]
 #[cfg(feature = "router")]
 pub mod router;
+#[cfg(feature = "json")]
+mod stream;
+#[cfg(feature = "router")]
+mod timer;
+mod tls;
 
+pub use broker_stats::*;
+pub use broker_url::*;
 pub use client::*;
 pub use error::*;
+pub use filter::TopicFilter;
 pub use lowlevel::*;
+pub use payload::*;
+pub use properties::*;
+#[cfg(feature = "json")]
+pub use stream::*;
+pub use tls::*;
@@ -0,0 +1,16 @@
+//! The macros used to log the diagnostics produced by this crate.
+//! These are those of the `log` crate when the `log-bridge` feature
+//! is enabled, and discard their arguments otherwise.
+
+#[cfg(feature = "log-bridge")]
+pub(crate) use ::log::{debug, error, trace, warn};
+
+#[cfg(not(feature = "log-bridge"))]
+macro_rules! discard {
+    ($($arg:tt)+) => {{
+        let _ = format_args!($($arg)+);
+    }};
+}
+
+#[cfg(not(feature = "log-bridge"))]
+pub(crate) use {discard as debug, discard as error, discard as trace, discard as warn};
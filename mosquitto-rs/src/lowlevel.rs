@@ -1,21 +1,53 @@
-use crate::Error;
+use crate::properties::{properties_from_raw, PropertyList};
+use crate::{Error, Property};
 pub(crate) use libmosquitto_sys as sys;
 use std::convert::TryInto;
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int, c_void};
 use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
-use std::sync::Once;
+use std::sync::Mutex;
 use std::time::Duration;
+/// The libmosquitto option identifiers, for use with
+/// `Client::set_raw_int_option` and `Client::set_raw_string_option`
+pub use sys::mosq_opt_t;
 
-static INIT: Once = Once::new();
+/// Whether mosquitto_lib_init has been called without a subsequent
+/// mosquitto_lib_cleanup
+static INITIALIZED: Mutex<bool> = Mutex::new(false);
 
 fn init_library() {
-    // Note: we never call mosquitto_lib_cleanup as we can't ever
-    // know when it will be safe to do so.
-    INIT.call_once(|| unsafe {
-        sys::mosquitto_lib_init();
-    });
+    // Note: we never call mosquitto_lib_cleanup ourselves as we can't
+    // ever know when it will be safe to do so; see lib_cleanup.
+    let mut initialized = INITIALIZED.lock().unwrap();
+    if !*initialized {
+        unsafe {
+            sys::mosquitto_lib_init();
+        }
+        *initialized = true;
+    }
+}
+
+/// Release the global resources held by the mosquitto library.
+///
+/// This is never necessary, as the operating system reclaims them when
+/// the process exits, but calling it just before exiting keeps leak
+/// checkers such as valgrind and ASAN quiet.  It does nothing if the
+/// library isn't initialized.  The library is initialized again if
+/// another client is created afterwards.
+///
+/// # Safety
+///
+/// There must be no live clients (`Client` or `Mosq` instances,
+/// including clones held by other threads or tasks) when this is
+/// called, and none may be created concurrently with it.
+pub unsafe fn lib_cleanup() {
+    let mut initialized = INITIALIZED.lock().unwrap();
+    if *initialized {
+        sys::mosquitto_lib_cleanup();
+        *initialized = false;
+    }
 }
 
 /// Represents the version of the linked mosquitto client library
@@ -54,6 +86,30 @@ pub fn lib_version() -> LibraryVersion {
     vers
 }
 
+/// Convert a keepalive interval to whole seconds.  Partial seconds are
+/// rounded up so that a short, non-zero interval cannot be mistaken for
+/// `Duration::ZERO`, which disables keepalive.
+pub(crate) fn keep_alive_secs(interval: Duration) -> Result<c_int, Error> {
+    let secs = interval.as_secs() + u64::from(interval.subsec_nanos() > 0);
+    secs.try_into()
+        .map_err(|_| Error::Mosq(sys::mosq_err_t::MOSQ_ERR_INVAL))
+}
+
+/// The largest payload that can be sent in an MQTT packet
+const MAX_PAYLOAD_LEN: usize = 268_435_455;
+
+/// Convert the length of a payload to the integer type that libmosquitto
+/// accepts.  A payload that is too large for MQTT fails with
+/// `MOSQ_ERR_PAYLOAD_SIZE`, whatever the size of `usize`, while a length
+/// that MQTT allows but that doesn't fit the integer type fails with
+/// `Error::PayloadLength`.
+fn payload_len<T: TryFrom<usize>>(len: usize) -> Result<T, Error> {
+    if len > MAX_PAYLOAD_LEN {
+        return Err(Error::Mosq(sys::mosq_err_t::MOSQ_ERR_PAYLOAD_SIZE));
+    }
+    len.try_into().map_err(|_| Error::PayloadLength(len))
+}
+
 pub(crate) fn cstr(s: &str) -> Result<CString, Error> {
     Ok(CString::new(s)?)
 }
@@ -66,6 +122,20 @@ where
 {
     m: *mut sys::mosquitto,
     cb: Option<Arc<CallbackWrapper<CB>>>,
+    /// The client id, if one was specified at construction or when
+    /// the client was last reinitialised
+    id: Option<String>,
+}
+
+/// Prints the client id and whether the client currently has a
+/// network connection; the handle and credentials are not shown.
+impl<CB: Callbacks + Send + Sync> std::fmt::Debug for Mosq<CB> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("Mosq")
+            .field("client_id", &self.id)
+            .field("connected", &self.has_socket())
+            .finish_non_exhaustive()
+    }
 }
 
 // libmosquitto is internally thread safe, so tell the rust compiler
@@ -83,7 +153,11 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
             if m.is_null() {
                 Err(Error::Create(std::io::Error::last_os_error()))
             } else {
-                Ok(Self::set_callbacks(Self { m, cb: Some(cb) }))
+                Ok(Self::set_callbacks(Self {
+                    m,
+                    cb: Some(cb),
+                    id: None,
+                }))
             }
         }
     }
@@ -103,7 +177,11 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
             if m.is_null() {
                 Err(Error::Create(std::io::Error::last_os_error()))
             } else {
-                Ok(Self::set_callbacks(Self { m, cb: Some(cb) }))
+                Ok(Self::set_callbacks(Self {
+                    m,
+                    cb: Some(cb),
+                    id: Some(id.to_string()),
+                }))
             }
         }
     }
@@ -145,9 +223,11 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
     /// in your environment.
     ///
     /// `keep_alive_interval` specifies the interval at which
-    /// keepalive requests are sent.  mosquitto has a minimum value
-    /// of 5 for this and will generate an error if you use a smaller
-    /// value.
+    /// keepalive requests are sent.  Most brokers and versions of
+    /// mosquitto require a minimum value of 5 seconds for this and will
+    /// generate an error if you use a smaller value.  `Duration::ZERO`
+    /// disables keepalive; it is passed through as-is for those that
+    /// accept it.  Fractions of a second are rounded up.
     ///
     /// `bind_address` can be used to specify the outgoing interface
     /// for the connection.
@@ -172,14 +252,45 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
                 self.m,
                 host.as_ptr(),
                 port,
-                keep_alive_interval
-                    .as_secs()
-                    .try_into()
-                    .map_err(|_| Error::Mosq(sys::mosq_err_t::MOSQ_ERR_INVAL))?,
+                keep_alive_secs(keep_alive_interval)?,
                 bind_address,
             )
         };
-        Error::result(err, ())
+        self.connect_result(err)
+    }
+
+    /// Connect to the broker in the same way as [connect](#method.connect),
+    /// sending `properties` with the MQTT v5 CONNECT packet.
+    /// The client must have been configured to use MQTT v5.
+    pub fn connect_v5(
+        &self,
+        host: &str,
+        port: c_int,
+        keep_alive_interval: Duration,
+        bind_address: Option<&str>,
+        properties: &[Property],
+    ) -> Result<(), Error> {
+        let host = cstr(host)?;
+        let ba;
+        let bind_address = match bind_address {
+            Some(b) => {
+                ba = cstr(b)?;
+                ba.as_ptr()
+            }
+            None => std::ptr::null(),
+        };
+        let properties = PropertyList::new(properties)?;
+        let err = unsafe {
+            sys::mosquitto_connect_bind_v5(
+                self.m,
+                host.as_ptr(),
+                port,
+                keep_alive_secs(keep_alive_interval)?,
+                bind_address,
+                properties.as_ptr(),
+            )
+        };
+        self.connect_result(err)
     }
 
     /// Connect to the broker on the specified host and port,
@@ -194,9 +305,11 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
     /// in your environment.
     ///
     /// `keep_alive_interval` specifies the interval at which
-    /// keepalive requests are sent.  mosquitto has a minimum value
-    /// of 5 for this and will generate an error if you use a smaller
-    /// value.
+    /// keepalive requests are sent.  Most brokers and versions of
+    /// mosquitto require a minimum value of 5 seconds for this and will
+    /// generate an error if you use a smaller value.  `Duration::ZERO`
+    /// disables keepalive; it is passed through as-is for those that
+    /// accept it.  Fractions of a second are rounded up.
     ///
     /// `bind_address` can be used to specify the outgoing interface
     /// for the connection.
@@ -221,20 +334,52 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
                 self.m,
                 host.as_ptr(),
                 port,
-                keep_alive_interval
-                    .as_secs()
-                    .try_into()
-                    .map_err(|_| Error::Mosq(sys::mosq_err_t::MOSQ_ERR_INVAL))?,
+                keep_alive_secs(keep_alive_interval)?,
                 bind_address,
             )
         };
+        self.connect_result(err)
+    }
+
+    /// Maps the result of a connection attempt to an `Error`.
+    /// libmosquitto only returns `MOSQ_ERR_TLS` or `MOSQ_ERR_TLS_HANDSHAKE`
+    /// for TLS failures, so attach the reasons for the failure to the error.
+    fn connect_result(&self, err: c_int) -> Result<(), Error> {
+        if let Some(err) = self.tls_error(err) {
+            return Err(err);
+        }
+        if let Some(cb) = &self.cb {
+            cb.errors.lock().unwrap().clear();
+        }
         Error::result(err, ())
     }
 
+    /// If `err` is a TLS failure, returns an `Error::Tls` holding the
+    /// reasons for it: the errors that libmosquitto logged while draining
+    /// the OpenSSL error queue, followed by anything still left in the
+    /// queue.  Returns `None` for other errors, or if no reason is known.
+    fn tls_error(&self, err: c_int) -> Option<Error> {
+        if err != sys::mosq_err_t::MOSQ_ERR_TLS as c_int
+            && err != sys::mosq_err_t::MOSQ_ERR_TLS_HANDSHAKE as c_int
+        {
+            return None;
+        }
+        let mut errors = match &self.cb {
+            Some(cb) => std::mem::take(&mut *cb.errors.lock().unwrap()),
+            None => vec![],
+        };
+        errors.extend(crate::tls::drain_openssl_errors());
+        if errors.is_empty() {
+            None
+        } else {
+            Some(Error::Tls(errors.join("; ")))
+        }
+    }
+
     /// Reconnect a disconnected client using the same parameters
     /// as were originally used to connect it.
     pub fn reconnect(&self) -> Result<(), Error> {
-        Error::result(unsafe { sys::mosquitto_reconnect(self.m) }, ())
+        self.connect_result(unsafe { sys::mosquitto_reconnect(self.m) })
     }
 
     /// Disconnect the client.
@@ -245,7 +390,7 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
 
     /// Publish a message to the specified topic.
     ///
-    /// The payload size can be 0-283, 435 or 455 bytes; other values
+    /// The payload size can be 0-268,435,455 bytes; larger payloads
     /// will generate an error result.
     ///
     /// `retain` will set the message to be retained by the broker,
@@ -268,13 +413,44 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
                 self.m,
                 &mut mid,
                 cstr(topic)?.as_ptr(),
-                payload
-                    .len()
-                    .try_into()
-                    .map_err(|_| Error::Mosq(sys::mosq_err_t::MOSQ_ERR_PAYLOAD_SIZE))?,
+                payload_len(payload.len())?,
+                payload.as_ptr() as *const _,
+                qos as c_int,
+                retain,
+            )
+        };
+        Error::result(err, mid)
+    }
+
+    /// Publish a message to the specified topic, including MQTT v5
+    /// `properties`.  Properties can only be sent when connected using
+    /// MQTT v5; if `properties` is empty this behaves the same as `publish`
+    /// for any protocol version.
+    ///
+    /// Returns the assigned MessageId value for the publish.
+    /// The publish may not complete immediately.
+    /// Your `Callbacks::on_publish` handler will be called
+    /// when it completes.
+    pub fn publish_v5(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+        properties: &[Property],
+    ) -> Result<MessageId, Error> {
+        let properties = PropertyList::new(properties)?;
+        let mut mid = 0;
+        let err = unsafe {
+            sys::mosquitto_publish_v5(
+                self.m,
+                &mut mid,
+                cstr(topic)?.as_ptr(),
+                payload_len(payload.len())?,
                 payload.as_ptr() as *const _,
                 qos as c_int,
                 retain,
+                properties.as_ptr(),
             )
         };
         Error::result(err, mid)
@@ -284,7 +460,7 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
     /// By default, clients do not have a will.
     /// This must be called before calling `connect`.
     ///
-    /// The payload size can be 0-283, 435 or 455 bytes; other values
+    /// The payload size can be 0-268,435,455 bytes; larger payloads
     /// will generate an error result.
     ///
     /// `retain` will set the message to be retained by the broker,
@@ -300,10 +476,7 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
             sys::mosquitto_will_set(
                 self.m,
                 cstr(topic)?.as_ptr(),
-                payload
-                    .len()
-                    .try_into()
-                    .map_err(|_| Error::Mosq(sys::mosq_err_t::MOSQ_ERR_PAYLOAD_SIZE))?,
+                payload_len(payload.len())?,
                 payload.as_ptr() as *const _,
                 qos as c_int,
                 retain,
@@ -312,6 +485,44 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
         Error::result(err, ())
     }
 
+    /// Configure will information, including MQTT v5 `properties`,
+    /// for a mosquitto instance.
+    /// This must be called before calling `connect`.
+    ///
+    /// Properties can only be used when connecting with MQTT v5; if
+    /// `properties` is empty this behaves the same as `set_last_will`
+    /// for any protocol version.
+    pub fn set_last_will_v5(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+        properties: &[Property],
+    ) -> Result<(), Error> {
+        let properties = PropertyList::new(properties)?;
+        let topic = cstr(topic)?;
+        let payload_len = payload_len(payload.len())?;
+        let properties = properties.into_raw();
+        let err = unsafe {
+            sys::mosquitto_will_set_v5(
+                self.m,
+                topic.as_ptr(),
+                payload_len,
+                payload.as_ptr() as *const _,
+                qos as c_int,
+                retain,
+                properties,
+            )
+        };
+        if err != sys::mosq_err_t::MOSQ_ERR_SUCCESS as c_int {
+            // libmosquitto only takes ownership of the properties on success
+            let mut properties = properties;
+            unsafe { sys::mosquitto_property_free_all(&mut properties) };
+        }
+        Error::result(err, ())
+    }
+
     /// Remove a previously configured will.
     /// This must be called before calling connect
     pub fn clear_last_will(&self) -> Result<(), Error> {
@@ -335,6 +546,72 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
         Error::result(err, mid)
     }
 
+    /// Establish a subscription for topics that match `pattern`,
+    /// with the MQTT v5 subscription `options`.
+    /// The options are only supported for MQTT v5 connections.
+    ///
+    /// Returns the MessageId of the subscription request.
+    pub fn subscribe_with_options(
+        &self,
+        pattern: &str,
+        qos: QoS,
+        options: SubscriptionOptions,
+    ) -> Result<MessageId, Error> {
+        let mut mid = 0;
+        let err = unsafe {
+            sys::mosquitto_subscribe_v5(
+                self.m,
+                &mut mid,
+                cstr(pattern)?.as_ptr(),
+                qos as c_int,
+                options.as_int(),
+                std::ptr::null(),
+            )
+        };
+        Error::result(err, mid)
+    }
+
+    /// Establish subscriptions for topics that match any of `patterns`,
+    /// using a single SUBSCRIBE request.
+    ///
+    /// Returns the MessageId of the subscription request.
+    /// Your `on_subscribe` handler will receive the granted QoS for
+    /// each pattern, in the same order as `patterns`.
+    pub fn subscribe_multiple<P: AsRef<str>>(
+        &self,
+        patterns: &[P],
+        qos: QoS,
+    ) -> Result<MessageId, Error> {
+        let patterns = patterns
+            .iter()
+            .map(|p| cstr(p.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let ptrs: Vec<*mut c_char> = patterns.iter().map(|p| p.as_ptr() as *mut _).collect();
+        let count = ptrs
+            .len()
+            .try_into()
+            .map_err(|_| Error::Mosq(sys::mosq_err_t::MOSQ_ERR_INVAL))?;
+        let mut mid = 0;
+        let err = unsafe {
+            sys::mosquitto_subscribe_multiple(
+                self.m,
+                &mut mid,
+                count,
+                ptrs.as_ptr(),
+                qos as c_int,
+                0,
+                std::ptr::null(),
+            )
+        };
+        Error::result(err, mid)
+    }
+
+    /// Returns true if there is data buffered that is waiting to be
+    /// written to the network.
+    pub fn want_write(&self) -> bool {
+        unsafe { sys::mosquitto_want_write(self.m) }
+    }
+
     /// Remove subscription(s) for topics that match `pattern`.
     pub fn unsubscribe(&self, pattern: &str) -> Result<MessageId, Error> {
         let mut mid = 0;
@@ -343,19 +620,78 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
     }
 
     fn set_callbacks(self) -> Self {
+        self.register_callbacks();
+        self
+    }
+
+    fn register_callbacks(&self) {
         unsafe {
-            sys::mosquitto_connect_callback_set(self.m, Some(CallbackWrapper::<CB>::connect));
+            sys::mosquitto_connect_v5_callback_set(self.m, Some(CallbackWrapper::<CB>::connect));
             sys::mosquitto_disconnect_callback_set(self.m, Some(CallbackWrapper::<CB>::disconnect));
             sys::mosquitto_publish_callback_set(self.m, Some(CallbackWrapper::<CB>::publish));
             sys::mosquitto_subscribe_callback_set(self.m, Some(CallbackWrapper::<CB>::subscribe));
-            sys::mosquitto_message_callback_set(self.m, Some(CallbackWrapper::<CB>::message));
+            sys::mosquitto_message_v5_callback_set(self.m, Some(CallbackWrapper::<CB>::message));
             sys::mosquitto_unsubscribe_callback_set(
                 self.m,
                 Some(CallbackWrapper::<CB>::unsubscribe),
             );
-            sys::mosquitto_log_callback_set(self.m, Some(bridge_logs));
+            sys::mosquitto_log_callback_set(self.m, Some(CallbackWrapper::<CB>::log));
+        }
+    }
+
+    /// Reinitialise the client so that it can be reused as though it had
+    /// been newly created with the specified `id` (or a random id if `None`)
+    /// and `clean_session` values.
+    ///
+    /// The callbacks and log mask associated with this instance are
+    /// preserved, but libmosquitto resets all of its other state, including
+    /// credentials, TLS parameters, the will and any options that were set;
+    /// they must be configured again before calling `connect`.
+    ///
+    /// If `id` is `None` then `clean_session` must be true.
+    /// The loop thread must not be running when this is called.
+    /// Fails with `MOSQ_ERR_INVAL` for the `Mosq` that is passed to the
+    /// methods of [Callbacks], as those are called from the loop thread.
+    pub fn reinitialise(&mut self, id: Option<&str>, clean_session: bool) -> Result<(), Error> {
+        let Some(cb) = self.cb.as_ref() else {
+            return Err(Error::Mosq(sys::mosq_err_t::MOSQ_ERR_INVAL));
+        };
+        let c_id = id.map(cstr).transpose()?;
+        let err = unsafe {
+            // The callback wrapper is owned by self, so the user data
+            // pointer remains valid for the lifetime of the instance
+            sys::mosquitto_reinitialise(
+                self.m,
+                opt_cstring_to_ptr(&c_id),
+                clean_session,
+                Arc::as_ptr(cb) as *mut _,
+            )
+        };
+        Error::result(err, ())?;
+        self.id = id.map(str::to_string);
+        // libmosquitto clears the callbacks as part of reinitialising
+        self.register_callbacks();
+        Ok(())
+    }
+
+    /// Restrict the log messages produced by libmosquitto for this
+    /// client that are forwarded to `Callbacks::on_log` and the `log`
+    /// crate to those in `mask`.
+    /// The default is `LogLevelMask::ALL`.
+    ///
+    /// This is useful to suppress the chattier categories without
+    /// having to reconfigure the global logger:
+    ///
+    /// ```no_run
+    /// # use mosquitto_rs::*;
+    /// # fn setup(mosq: &Mosq) {
+    /// mosq.set_log_mask(LogLevelMask::WARNING | LogLevelMask::ERR);
+    /// # }
+    /// ```
+    pub fn set_log_mask(&self, mask: LogLevelMask) {
+        if let Some(cb) = &self.cb {
+            cb.log_mask.store(mask.0, Ordering::Relaxed);
         }
-        self
     }
 
     /// Returns a reference to the callbacks previously registered
@@ -375,22 +711,32 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
     /// `timeout` specifies the internal sleep duration between
     /// iterations.
     pub fn loop_until_explicitly_disconnected(&self, timeout: Duration) -> Result<(), Error> {
-        unsafe {
-            let max_packets = 1;
-            Error::result(
-                sys::mosquitto_loop_forever(
-                    self.m,
-                    timeout
-                        .as_millis()
-                        .try_into()
-                        .map_err(|_| Error::Mosq(sys::mosq_err_t::MOSQ_ERR_INVAL))?,
-                    max_packets,
-                ),
-                (),
+        let max_packets = 1;
+        let err = unsafe {
+            sys::mosquitto_loop_forever(
+                self.m,
+                timeout
+                    .as_millis()
+                    .try_into()
+                    .map_err(|_| Error::Mosq(sys::mosq_err_t::MOSQ_ERR_INVAL))?,
+                max_packets,
             )
+        };
+        match self.tls_error(err) {
+            Some(err) => Err(err),
+            None => Error::result(err, ()),
         }
     }
 
+    /// Returns the OpenSSL `SSL*` for the current connection, or null
+    /// if there is no connection or it is not using TLS.
+    /// The pointer is owned by libmosquitto and is freed by the loop
+    /// thread when the connection is closed, so it may only be
+    /// dereferenced from within a callback.
+    pub fn ssl_ptr(&self) -> *mut c_void {
+        unsafe { sys::mosquitto_ssl_get(self.m) }
+    }
+
     /// Starts a new thread to run the message loop for the client.
     /// The thread will run until the client is disconnected,
     /// or until `stop_loop_thread` is called.
@@ -398,6 +744,18 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
         unsafe { Error::result(sys::mosquitto_loop_start(self.m), ()) }
     }
 
+    /// Returns the client id that was passed to `with_id`, or None
+    /// if the id was generated by libmosquitto
+    pub fn client_id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Returns true if the client has a network socket, which is the
+    /// case while it is connected or in the process of connecting
+    fn has_socket(&self) -> bool {
+        unsafe { sys::mosquitto_socket(self.m) != -1 }
+    }
+
     /// Stops the message loop thread started via `start_loop_thread`
     pub fn stop_loop_thread(&self, force_cancel: bool) -> Result<(), Error> {
         unsafe { Error::result(sys::mosquitto_loop_stop(self.m, force_cancel), ()) }
@@ -463,6 +821,12 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
         CERTFILE: AsRef<Path>,
         KEYFILE: AsRef<Path>,
     {
+        let pw_callback = match (pw_callback, &self.cb) {
+            (None, Some(cb)) if cb.key_password.lock().unwrap().is_some() => {
+                Some(CallbackWrapper::<CB>::key_password as PasswdCallback)
+            }
+            _ => pw_callback,
+        };
         let ca_file = path_to_cstring(ca_file)?;
         let ca_path = path_to_cstring(ca_path)?;
         let cert_file = path_to_cstring(cert_file)?;
@@ -482,6 +846,95 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
         Error::result(err, ())
     }
 
+    /// Sets the password used to decrypt an encrypted client key.
+    /// It is used by subsequent calls to `configure_tls` that don't
+    /// specify a `pw_callback`, as a simpler alternative to providing one.
+    pub fn set_tls_key_password(&self, password: Option<&str>) -> Result<(), Error> {
+        let password = password.map(cstr).transpose()?;
+        if let Some(cb) = &self.cb {
+            *cb.key_password.lock().unwrap() = password;
+        }
+        Ok(())
+    }
+
+    /// Configures the client for pre-shared-key based TLS support.
+    /// This must be called before calling `connect`, and cannot be
+    /// used in conjunction with `configure_tls`.
+    ///
+    /// `psk` is the pre-shared key in hex format with no leading "0x".
+    ///
+    /// `identity` is the identity of this client, which may be used
+    /// as the username depending on the broker configuration.
+    ///
+    /// `ciphers` is an OpenSSL cipher list string.  `None` uses the
+    /// library default.
+    pub fn configure_tls_psk(
+        &self,
+        psk: &str,
+        identity: &str,
+        ciphers: Option<&str>,
+    ) -> Result<(), Error> {
+        let psk = cstr(psk)?;
+        let identity = cstr(identity)?;
+        let ciphers = ciphers.map(cstr).transpose()?;
+        let err = unsafe {
+            sys::mosquitto_tls_psk_set(
+                self.m,
+                psk.as_ptr(),
+                identity.as_ptr(),
+                opt_cstring_to_ptr(&ciphers),
+            )
+        };
+        Error::result(err, ())
+    }
+
+    /// Configures advanced TLS options for the client.
+    /// This must be called before calling `connect`.
+    ///
+    /// `cert_reqs` controls whether the server certificate is verified.
+    /// Disabling verification is insecure and should only be used for testing.
+    ///
+    /// `tls_version` sets the TLS protocol version to use.
+    /// `None` uses the library default.
+    ///
+    /// `ciphers` is an OpenSSL cipher list string, such as
+    /// `"ECDHE-ECDSA-AES256-GCM-SHA384"`.  `None` uses the library default.
+    /// The cipher list is only applied when connecting; an invalid list
+    /// causes `connect` to fail with an `Error::Tls` that includes the
+    /// reason reported by OpenSSL.
+    pub fn configure_tls_opts(
+        &self,
+        cert_reqs: CertificateRequirements,
+        tls_version: Option<TlsVersion>,
+        ciphers: Option<&str>,
+    ) -> Result<(), Error> {
+        let tls_version = tls_version.map(|v| cstr(v.as_str())).transpose()?;
+        let ciphers = ciphers.map(cstr).transpose()?;
+        let err = unsafe {
+            sys::mosquitto_tls_opts_set(
+                self.m,
+                cert_reqs as c_int,
+                opt_cstring_to_ptr(&tls_version),
+                opt_cstring_to_ptr(&ciphers),
+            )
+        };
+        Error::result(err, ())
+    }
+
+    /// Disable verification of the server hostname in the server certificate.
+    ///
+    /// **WARNING**: this makes the connection vulnerable to man-in-the-middle
+    /// attacks, as a malicious third party could impersonate the broker.
+    /// It is intended only for development and testing against brokers
+    /// that use self-signed certificates, and must never be enabled in
+    /// production.
+    ///
+    /// This must be called before calling `connect`.
+    pub fn set_tls_insecure(&self, insecure: bool) -> Result<(), Error> {
+        let err = unsafe { sys::mosquitto_tls_insecure_set(self.m, insecure) };
+        Error::result(err, ())
+    }
+
     /// Controls reconnection behavior when running in the message loop.
     /// By default, if a client is unexpectedly disconnected, mosquitto will
     /// try to reconnect.  The default reconnect parameters are to retry once
@@ -522,6 +975,37 @@ impl<CB: Callbacks + Send + Sync> Mosq<CB> {
     }
 }
 
+/// Whether the server certificate is verified.
+/// Used with `Mosq::configure_tls_opts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CertificateRequirements {
+    /// The server certificate is not verified.
+    /// The connection is not secure; use only for testing.
+    None = 0,
+    /// The server certificate must be verified. This is the default.
+    #[default]
+    Required = 1,
+}
+
+/// The TLS protocol version to use for a connection.
+/// Used with `Mosq::configure_tls_opts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    V1_1,
+    V1_2,
+    V1_3,
+}
+
+impl TlsVersion {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::V1_1 => "tlsv1.1",
+            Self::V1_2 => "tlsv1.2",
+            Self::V1_3 => "tlsv1.3",
+        }
+    }
+}
+
 fn opt_cstring_to_ptr(c: &Option<CString>) -> *const c_char {
     match c {
         Some(c) => c.as_ptr(),
@@ -618,6 +1102,18 @@ impl ConnectionStatus {
     }
 }
 
+/// The flags from the CONNACK sent by the broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectFlags(pub c_int);
+
+impl ConnectFlags {
+    /// Returns true if the broker resumed an existing session for this
+    /// client, rather than starting a clean one.
+    pub fn session_present(&self) -> bool {
+        self.0 & 1 != 0
+    }
+}
+
 struct CallbackWrapper<T: Callbacks> {
     /// This used to be RefCell, but I've observed that the underlying
     /// library can make recursive dispatches to the callbacks,
@@ -626,27 +1122,79 @@ struct CallbackWrapper<T: Callbacks> {
     /// immutable here and leaving it to the impl of Callbacks
     /// to appropriate scope any interior mutability
     cb: Box<T>,
+    /// The `LogLevelMask` of messages to pass through to `on_log` and
+    /// the `log` crate
+    log_mask: AtomicU32,
+    /// The most recent error messages logged by libmosquitto, used
+    /// to provide context for TLS errors
+    errors: Mutex<Vec<String>>,
+    /// The password for the client key, set via `set_tls_key_password`
+    key_password: Mutex<Option<CString>>,
 }
 
+/// The maximum number of logged error messages retained in
+/// `CallbackWrapper::errors`
+const MAX_LOGGED_ERRORS: usize = 8;
+
 fn with_transient_client<F: FnOnce(&mut Mosq)>(m: *mut sys::mosquitto, func: F) {
-    let mut client = Mosq { m, cb: None };
+    let mut client = Mosq {
+        m,
+        cb: None,
+        id: None,
+    };
     func(&mut client);
     std::mem::forget(client);
 }
 
 impl<T: Callbacks> CallbackWrapper<T> {
     fn new(cb: T) -> Self {
-        Self { cb: Box::new(cb) }
+        Self {
+            cb: Box::new(cb),
+            log_mask: AtomicU32::new(LogLevelMask::ALL.0),
+            errors: Mutex::new(vec![]),
+            key_password: Mutex::new(None),
+        }
     }
 
     unsafe fn resolve_self<'a>(cb: *mut c_void) -> &'a Self {
         &*(cb as *const Self)
     }
 
-    unsafe extern "C" fn connect(m: *mut sys::mosquitto, cb: *mut c_void, rc: c_int) {
+    /// The `PasswdCallback` used for the password set via
+    /// `set_tls_key_password`.  libmosquitto passes the `mosquitto`
+    /// instance rather than our user data to this callback.
+    unsafe extern "C" fn key_password(
+        buf: *mut c_char,
+        size: c_int,
+        _rwflag: c_int,
+        m: *mut c_void,
+    ) -> c_int {
+        let cb = Self::resolve_self(sys::mosquitto_userdata(m as *mut sys::mosquitto));
+        let password = cb.key_password.lock().unwrap();
+        let Some(password) = password.as_ref() else {
+            return 0;
+        };
+        let password = password.as_bytes();
+        let len = password.len().min(size.max(0) as usize);
+        std::ptr::copy_nonoverlapping(password.as_ptr() as *const c_char, buf, len);
+        len as c_int
+    }
+
+    unsafe extern "C" fn connect(
+        m: *mut sys::mosquitto,
+        cb: *mut c_void,
+        rc: c_int,
+        flags: c_int,
+        props: *const sys::mosquitto_property,
+    ) {
         let cb = Self::resolve_self(cb);
         with_transient_client(m, |client| {
-            cb.cb.on_connect(client, ConnectionStatus(rc));
+            cb.cb.on_connect_v5(
+                client,
+                ConnectionStatus(rc),
+                ConnectFlags(flags),
+                &properties_from_raw(props),
+            );
         });
     }
 
@@ -681,7 +1229,11 @@ impl<T: Callbacks> CallbackWrapper<T> {
         let cb = Self::resolve_self(cb);
         with_transient_client(m, |client| {
             let granted_qos = std::slice::from_raw_parts(granted_qos, qos_count as usize);
-            let granted_qos: Vec<QoS> = granted_qos.iter().map(QoS::from_int).collect();
+            let granted_qos: Vec<GrantedQoS> = granted_qos
+                .iter()
+                .copied()
+                .map(GrantedQoS::from_int)
+                .collect();
             cb.cb.on_subscribe(client, mid, &granted_qos);
         });
     }
@@ -690,22 +1242,117 @@ impl<T: Callbacks> CallbackWrapper<T> {
         m: *mut sys::mosquitto,
         cb: *mut c_void,
         msg: *const sys::mosquitto_message,
+        props: *const sys::mosquitto_property,
     ) {
         let cb = Self::resolve_self(cb);
         with_transient_client(m, |client| {
             let msg = &*msg;
-            let topic = CStr::from_ptr(msg.topic);
-            let topic = topic.to_string_lossy().to_string();
-            cb.cb.on_message(
+            let topic = CStr::from_ptr(msg.topic).to_string_lossy();
+            cb.cb.on_message_v5(
                 client,
                 msg.mid,
-                topic,
+                &topic,
                 std::slice::from_raw_parts(msg.payload as *const u8, msg.payloadlen as usize),
                 QoS::from_int(&msg.qos),
                 msg.retain,
+                &properties_from_raw(props),
             );
         });
     }
+
+    unsafe extern "C" fn log(
+        m: *mut sys::mosquitto,
+        cb: *mut c_void,
+        level: c_int,
+        message: *const c_char,
+    ) {
+        let cb = Self::resolve_self(cb);
+        let message = CStr::from_ptr(message).to_string_lossy();
+
+        if level as u32 == sys::MOSQ_LOG_ERR {
+            let mut errors = cb.errors.lock().unwrap();
+            if errors.len() >= MAX_LOGGED_ERRORS {
+                errors.remove(0);
+            }
+            errors.push(message.to_string());
+        }
+
+        if !LogLevelMask(cb.log_mask.load(Ordering::Relaxed)).allows(level) {
+            return;
+        }
+
+        with_transient_client(m, |client| {
+            cb.cb.on_log(client, LogLevelMask(level as u32), &message);
+        });
+
+        #[cfg(feature = "log-bridge")]
+        Self::bridge_log(level, &message);
+    }
+
+    /// Pass a message logged by libmosquitto on to the `log` crate
+    #[cfg(feature = "log-bridge")]
+    fn bridge_log(level: c_int, message: &str) {
+        use ::log::Level;
+        let level = match level as u32 {
+            libmosquitto_sys::MOSQ_LOG_NOTICE | libmosquitto_sys::MOSQ_LOG_INFO => Level::Info,
+            libmosquitto_sys::MOSQ_LOG_WARNING => Level::Warn,
+            libmosquitto_sys::MOSQ_LOG_ERR => Level::Error,
+            libmosquitto_sys::MOSQ_LOG_DEBUG => Level::Debug,
+            _ => Level::Trace,
+        };
+        ::log::log!(level, "{message}");
+    }
+}
+
+/// A set of libmosquitto log categories.
+/// Used with `Mosq::set_log_mask` to filter which of the log
+/// messages produced by libmosquitto are passed on to `Callbacks::on_log`
+/// and the `log` crate.
+/// Masks can be combined using the `|` operator.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LogLevelMask(pub u32);
+
+impl LogLevelMask {
+    /// Don't forward any log messages
+    pub const NONE: Self = Self(sys::MOSQ_LOG_NONE);
+    pub const INFO: Self = Self(sys::MOSQ_LOG_INFO);
+    pub const NOTICE: Self = Self(sys::MOSQ_LOG_NOTICE);
+    pub const WARNING: Self = Self(sys::MOSQ_LOG_WARNING);
+    pub const ERR: Self = Self(sys::MOSQ_LOG_ERR);
+    pub const DEBUG: Self = Self(sys::MOSQ_LOG_DEBUG);
+    pub const SUBSCRIBE: Self = Self(sys::MOSQ_LOG_SUBSCRIBE);
+    pub const UNSUBSCRIBE: Self = Self(sys::MOSQ_LOG_UNSUBSCRIBE);
+    pub const WEBSOCKETS: Self = Self(sys::MOSQ_LOG_WEBSOCKETS);
+    /// Forward all log messages. This is the default.
+    pub const ALL: Self = Self(sys::MOSQ_LOG_ALL);
+
+    /// Returns true if all of the categories in `other` are in this mask
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn allows(self, level: c_int) -> bool {
+        self.0 & (level as u32) != 0
+    }
+}
+
+impl Default for LogLevelMask {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl std::ops::BitOr for LogLevelMask {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitOrAssign for LogLevelMask {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
 }
 
 /// Represents an individual message identifier.
@@ -762,6 +1409,20 @@ pub trait Callbacks {
     /// successful.
     fn on_connect(&self, _client: &mut Mosq, _reason: ConnectionStatus) {}
 
+    /// Called when the connection has been acknowledged by the broker,
+    /// along with the CONNACK flags and its MQTT v5 properties.
+    /// The properties are empty for MQTT v3 connections.
+    /// The default implementation calls `on_connect`.
+    fn on_connect_v5(
+        &self,
+        client: &mut Mosq,
+        reason: ConnectionStatus,
+        _flags: ConnectFlags,
+        _properties: &[Property],
+    ) {
+        self.on_connect(client, reason)
+    }
+
     /// Called when the broker has received the DISCONNECT command
     fn on_disconnect(&self, _client: &mut Mosq, _reason: ReasonCode) {}
 
@@ -770,7 +1431,9 @@ pub trait Callbacks {
     fn on_publish(&self, _client: &mut Mosq, _mid: MessageId) {}
 
     /// Called when the broker responds to a subscription request.
-    fn on_subscribe(&self, _client: &mut Mosq, _mid: MessageId, _granted_qos: &[QoS]) {}
+    /// `granted_qos` holds the result for each of the requested
+    /// topic patterns, in the order in which they were requested.
+    fn on_subscribe(&self, _client: &mut Mosq, _mid: MessageId, _granted_qos: &[GrantedQoS]) {}
 
     /// Called when a message matching a subscription is received
     /// from the broker
@@ -778,15 +1441,43 @@ pub trait Callbacks {
         &self,
         _client: &mut Mosq,
         _mid: MessageId,
-        _topic: String,
+        _topic: &str,
         _payload: &[u8],
         _qos: QoS,
         _retain: bool,
     ) {
     }
 
+    /// Called when a message matching a subscription is received
+    /// from the broker, along with its MQTT v5 properties.
+    /// The properties are empty for MQTT v3 connections.
+    /// The default implementation calls `on_message`.
+    #[allow(clippy::too_many_arguments)]
+    fn on_message_v5(
+        &self,
+        client: &mut Mosq,
+        mid: MessageId,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+        _properties: &[Property],
+    ) {
+        self.on_message(client, mid, topic, payload, qos, retain)
+    }
+
     /// Called when the broker response to an unsubscription request
     fn on_unsubscribe(&self, _client: &mut Mosq, _mid: MessageId) {}
+
+    /// Called when libmosquitto produces a log message for this client
+    /// in one of the categories allowed by `Mosq::set_log_mask`.
+    /// `level` holds the single category of the message, such as
+    /// `LogLevelMask::WARNING`.
+    ///
+    /// When the `log-bridge` feature is enabled, which it is by default,
+    /// the message is also passed on to the `log` crate; disable that
+    /// feature to route the messages solely to this callback.
+    fn on_log(&self, _client: &mut Mosq, _level: LogLevelMask, _message: &str) {}
 }
 
 impl Callbacks for () {}
@@ -816,6 +1507,81 @@ impl Default for QoS {
     }
 }
 
+/// Controls whether the broker sends retained messages when an
+/// MQTT v5 subscription is established
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RetainHandling {
+    /// Send the retained messages for the subscription
+    #[default]
+    SendOnSubscribe = 0x00,
+    /// Send the retained messages only if the subscription did not
+    /// already exist
+    SendOnNewSubscribe = 0x10,
+    /// Don't send the retained messages for the subscription
+    DoNotSend = 0x20,
+}
+
+/// MQTT v5 subscription options
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SubscriptionOptions {
+    /// Don't deliver messages that were published by this client
+    pub no_local: bool,
+    /// Deliver messages with the retain flag that they were published
+    /// with, rather than clearing it
+    pub retain_as_published: bool,
+    /// Whether the broker sends retained messages
+    pub retain_handling: RetainHandling,
+}
+
+impl SubscriptionOptions {
+    fn as_int(&self) -> c_int {
+        let mut options = self.retain_handling as c_int;
+        if self.no_local {
+            options |= 0x04;
+        }
+        if self.retain_as_published {
+            options |= 0x08;
+        }
+        options
+    }
+}
+
+/// The broker's response to an individual topic pattern
+/// in a subscription request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrantedQoS {
+    /// The subscription was accepted at the specified QoS level,
+    /// which may be lower than the level that was requested.
+    Granted(QoS),
+    /// The subscription was refused. Holds the failure return code
+    /// (0x80 for MQTT v3.1.1) or MQTT v5 reason code.
+    Denied(c_int),
+}
+
+impl GrantedQoS {
+    fn from_int(i: c_int) -> Self {
+        match i {
+            0 => Self::Granted(QoS::AtMostOnce),
+            1 => Self::Granted(QoS::AtLeastOnce),
+            2 => Self::Granted(QoS::ExactlyOnce),
+            _ => Self::Denied(i),
+        }
+    }
+
+    /// Returns the granted QoS, or `None` if the subscription was refused
+    pub fn qos(&self) -> Option<QoS> {
+        match self {
+            Self::Granted(qos) => Some(*qos),
+            Self::Denied(_) => None,
+        }
+    }
+
+    /// Returns true if the subscription was accepted
+    pub fn is_granted(&self) -> bool {
+        self.qos().is_some()
+    }
+}
+
 impl QoS {
     fn from_int(i: &c_int) -> QoS {
         match i {
@@ -835,28 +1601,63 @@ impl<CB: Callbacks + Send + Sync> Drop for Mosq<CB> {
     }
 }
 
-unsafe extern "C" fn bridge_logs(
-    _m: *mut sys::mosquitto,
-    _: *mut c_void,
-    level: c_int,
-    message: *const c_char,
-) {
-    use log::Level;
-    let level = match level as u32 {
-        libmosquitto_sys::MOSQ_LOG_NOTICE | libmosquitto_sys::MOSQ_LOG_INFO => Level::Info,
-        libmosquitto_sys::MOSQ_LOG_WARNING => Level::Warn,
-        libmosquitto_sys::MOSQ_LOG_ERR => Level::Error,
-        libmosquitto_sys::MOSQ_LOG_DEBUG => Level::Debug,
-        _ => Level::Trace,
-    };
-    let message = CStr::from_ptr(message).to_string_lossy();
-    log::log!(level, "{message}");
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn subscription_options() {
+        assert_eq!(SubscriptionOptions::default().as_int(), 0);
+        assert_eq!(
+            SubscriptionOptions {
+                no_local: true,
+                retain_as_published: true,
+                retain_handling: RetainHandling::DoNotSend,
+            }
+            .as_int(),
+            0x2c
+        );
+    }
+
+    #[test]
+    fn connect_flags() {
+        assert!(!ConnectFlags(0).session_present());
+        assert!(ConnectFlags(1).session_present());
+    }
+
+    #[test]
+    fn payload_length() {
+        assert_eq!(payload_len::<c_int>(0).unwrap(), 0);
+        assert_eq!(
+            payload_len::<c_int>(MAX_PAYLOAD_LEN).unwrap(),
+            MAX_PAYLOAD_LEN as c_int
+        );
+        // Exceeding the MQTT limit is reported the same way on 32-bit
+        // and 64-bit targets
+        for len in [MAX_PAYLOAD_LEN + 1, u32::MAX as usize, usize::MAX] {
+            assert!(matches!(
+                payload_len::<c_int>(len),
+                Err(Error::Mosq(sys::mosq_err_t::MOSQ_ERR_PAYLOAD_SIZE))
+            ));
+        }
+        // A length within the limit that doesn't fit the integer type,
+        // as for a 16-bit c_int, is a conversion failure
+        assert_eq!(payload_len::<i16>(i16::MAX as usize).unwrap(), i16::MAX);
+        assert!(matches!(
+            payload_len::<i16>(i16::MAX as usize + 1),
+            Err(Error::PayloadLength(32768))
+        ));
+    }
+
+    #[test]
+    fn keep_alive_conversion() {
+        assert_eq!(keep_alive_secs(Duration::ZERO).unwrap(), 0);
+        assert_eq!(keep_alive_secs(Duration::from_millis(1)).unwrap(), 1);
+        assert_eq!(keep_alive_secs(Duration::from_secs(5)).unwrap(), 5);
+        assert_eq!(keep_alive_secs(Duration::from_millis(5500)).unwrap(), 6);
+        assert!(keep_alive_secs(Duration::from_secs(u64::MAX)).is_err());
+    }
+
     #[test]
     fn setting_auth() {
         let mosq = Mosq::with_auto_id(()).unwrap();
@@ -872,4 +1673,214 @@ mod test {
         mosq.set_int_option(sys::mosq_opt_t::MOSQ_OPT_PROTOCOL_VERSION, 3)
             .unwrap();
     }
+
+    #[test]
+    fn tls_opts() {
+        let mosq = Mosq::with_auto_id(()).unwrap();
+        mosq.configure_tls_opts(
+            CertificateRequirements::Required,
+            Some(TlsVersion::V1_3),
+            Some("TLS_AES_256_GCM_SHA384"),
+        )
+        .unwrap();
+        mosq.configure_tls_opts(CertificateRequirements::None, None, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn tls_error_context() {
+        let mosq = Mosq::with_auto_id(()).unwrap();
+        let cb = mosq.cb.as_ref().unwrap();
+        let message = cstr("OpenSSL Error[0]: no cipher match").unwrap();
+        unsafe {
+            CallbackWrapper::<()>::log(
+                mosq.m,
+                Arc::as_ptr(cb) as *mut _,
+                sys::MOSQ_LOG_ERR as c_int,
+                message.as_ptr(),
+            );
+        }
+        match mosq.connect_result(sys::mosq_err_t::MOSQ_ERR_TLS as c_int) {
+            Err(Error::Tls(reason)) => assert_eq!(reason, "OpenSSL Error[0]: no cipher match"),
+            other => panic!("unexpected {other:?}"),
+        }
+        // The errors are consumed by the connection attempt
+        assert!(matches!(
+            mosq.connect_result(sys::mosq_err_t::MOSQ_ERR_TLS as c_int),
+            Err(Error::Mosq(sys::mosq_err_t::MOSQ_ERR_TLS))
+        ));
+
+        // Handshake failures are annotated in the same way
+        let message = cstr("OpenSSL Error[0]: certificate verify failed").unwrap();
+        unsafe {
+            CallbackWrapper::<()>::log(
+                mosq.m,
+                Arc::as_ptr(cb) as *mut _,
+                sys::MOSQ_LOG_ERR as c_int,
+                message.as_ptr(),
+            );
+        }
+        match mosq.connect_result(sys::mosq_err_t::MOSQ_ERR_TLS_HANDSHAKE as c_int) {
+            Err(Error::Tls(reason)) => {
+                assert_eq!(reason, "OpenSSL Error[0]: certificate verify failed")
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn tls_psk_and_key_password() {
+        let mosq = Mosq::with_auto_id(()).unwrap();
+        mosq.configure_tls_psk("deadbeef", "me", None).unwrap();
+        mosq.set_tls_key_password(Some("secret")).unwrap();
+        mosq.configure_tls(
+            Some("ca.pem"),
+            None::<&str>,
+            Some("cert.pem"),
+            Some("key.pem"),
+            None,
+        )
+        .unwrap();
+        mosq.set_tls_key_password(None).unwrap();
+        assert!(mosq.configure_tls_psk("dead\0beef", "me", None).is_err());
+    }
+
+    #[test]
+    fn tls_insecure() {
+        let mosq = Mosq::with_auto_id(()).unwrap();
+        mosq.set_tls_insecure(true).unwrap();
+        mosq.set_tls_insecure(false).unwrap();
+    }
+
+    #[test]
+    fn reinitialise() {
+        let mut mosq = Mosq::with_auto_id(()).unwrap();
+        mosq.reinitialise(Some("reused"), false).unwrap();
+        assert_eq!(mosq.client_id(), Some("reused"));
+        assert!(format!("{mosq:?}").contains("\"reused\""));
+        mosq.reinitialise(None, true).unwrap();
+        assert_eq!(mosq.client_id(), None);
+
+        // The client passed to the callbacks can't be reinitialised
+        with_transient_client(mosq.m, |client| {
+            assert!(matches!(
+                client.reinitialise(None, true),
+                Err(Error::Mosq(sys::mosq_err_t::MOSQ_ERR_INVAL))
+            ));
+        });
+    }
+
+    #[test]
+    fn granted_qos() {
+        assert_eq!(
+            GrantedQoS::from_int(1),
+            GrantedQoS::Granted(QoS::AtLeastOnce)
+        );
+        assert_eq!(GrantedQoS::from_int(0x80), GrantedQoS::Denied(0x80));
+        assert!(!GrantedQoS::from_int(0x87).is_granted());
+        assert_eq!(GrantedQoS::from_int(2).qos(), Some(QoS::ExactlyOnce));
+    }
+
+    #[test]
+    fn log_mask() {
+        let mask = LogLevelMask::WARNING | LogLevelMask::ERR;
+        assert!(mask.contains(LogLevelMask::ERR));
+        assert!(!mask.contains(LogLevelMask::DEBUG));
+        assert!(mask.allows(sys::MOSQ_LOG_WARNING as c_int));
+        assert!(!mask.allows(sys::MOSQ_LOG_DEBUG as c_int));
+        assert!(!LogLevelMask::NONE.allows(sys::MOSQ_LOG_ERR as c_int));
+        assert!(LogLevelMask::default().allows(sys::MOSQ_LOG_DEBUG as c_int));
+
+        let mosq = Mosq::with_auto_id(()).unwrap();
+        mosq.set_log_mask(mask);
+    }
+
+    #[test]
+    fn message_v5_defaults_to_on_message() {
+        type Received = (MessageId, String, Vec<u8>, QoS, bool);
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<Received>>);
+
+        impl Callbacks for Recorder {
+            fn on_message(
+                &self,
+                _client: &mut Mosq,
+                mid: MessageId,
+                topic: &str,
+                payload: &[u8],
+                qos: QoS,
+                retain: bool,
+            ) {
+                self.0.lock().unwrap().push((
+                    mid,
+                    topic.to_string(),
+                    payload.to_vec(),
+                    qos,
+                    retain,
+                ));
+            }
+        }
+
+        let recorder = Recorder::default();
+        let mut mosq = Mosq::with_auto_id(()).unwrap();
+        recorder.on_message_v5(
+            &mut mosq,
+            3,
+            "a/b",
+            b"hello",
+            QoS::AtLeastOnce,
+            true,
+            &[Property::ContentType("text/plain".to_string())],
+        );
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![(
+                3,
+                "a/b".to_string(),
+                b"hello".to_vec(),
+                QoS::AtLeastOnce,
+                true
+            )]
+        );
+    }
+
+    #[test]
+    fn on_log() {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<(LogLevelMask, String)>>);
+
+        impl Callbacks for Recorder {
+            fn on_log(&self, _client: &mut Mosq, level: LogLevelMask, message: &str) {
+                self.0.lock().unwrap().push((level, message.to_string()));
+            }
+        }
+
+        let mosq = Mosq::with_auto_id(Recorder::default()).unwrap();
+        mosq.set_log_mask(LogLevelMask::WARNING | LogLevelMask::ERR);
+        let cb = mosq.cb.as_ref().unwrap();
+        for (level, message) in [
+            (sys::MOSQ_LOG_DEBUG, "chatter"),
+            (sys::MOSQ_LOG_WARNING, "careful"),
+            (sys::MOSQ_LOG_ERR, "oops"),
+        ] {
+            let message = CString::new(message).unwrap();
+            unsafe {
+                CallbackWrapper::<Recorder>::log(
+                    mosq.m,
+                    Arc::as_ptr(cb) as *mut _,
+                    level as c_int,
+                    message.as_ptr(),
+                );
+            }
+        }
+        assert_eq!(
+            *mosq.get_callbacks().0.lock().unwrap(),
+            vec![
+                (LogLevelMask::WARNING, "careful".to_string()),
+                (LogLevelMask::ERR, "oops".to_string())
+            ]
+        );
+        assert_eq!(*cb.errors.lock().unwrap(), vec!["oops".to_string()]);
+    }
 }
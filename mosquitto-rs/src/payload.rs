@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+/// The MQTT v5 content type of text payloads
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// The MQTT v5 content type of JSON payloads
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Converts a value into the payload bytes of a message, along with
+/// the MQTT v5 content type that describes it, if any, for use with
+/// [Client::publish_payload](crate::Client::publish_payload).
+///
+/// This is implemented for the common string and byte types, and
+/// for `serde_json::Value` when the `json` feature is enabled.
+/// Borrowed values are not copied.
+///
+/// ```no_run
+/// use mosquitto_rs::*;
+///
+/// async fn publish(client: &Client) -> Result<(), Error> {
+///     client.publish_payload("status", "online", QoS::AtMostOnce, false).await?;
+///     client.publish_payload("raw", vec![0xde, 0xad], QoS::AtMostOnce, false).await?;
+///     Ok(())
+/// }
+/// ```
+pub trait IntoPayload {
+    type Bytes: AsRef<[u8]>;
+
+    fn into_payload(self) -> (Self::Bytes, Option<&'static str>);
+}
+
+impl<'a> IntoPayload for &'a str {
+    type Bytes = &'a str;
+
+    fn into_payload(self) -> (Self::Bytes, Option<&'static str>) {
+        (self, Some(TEXT_CONTENT_TYPE))
+    }
+}
+
+impl<'a> IntoPayload for &'a String {
+    type Bytes = &'a str;
+
+    fn into_payload(self) -> (Self::Bytes, Option<&'static str>) {
+        (self.as_str(), Some(TEXT_CONTENT_TYPE))
+    }
+}
+
+impl IntoPayload for String {
+    type Bytes = String;
+
+    fn into_payload(self) -> (Self::Bytes, Option<&'static str>) {
+        (self, Some(TEXT_CONTENT_TYPE))
+    }
+}
+
+impl<'a> IntoPayload for &'a [u8] {
+    type Bytes = &'a [u8];
+
+    fn into_payload(self) -> (Self::Bytes, Option<&'static str>) {
+        (self, None)
+    }
+}
+
+impl<'a, const N: usize> IntoPayload for &'a [u8; N] {
+    type Bytes = &'a [u8];
+
+    fn into_payload(self) -> (Self::Bytes, Option<&'static str>) {
+        (self.as_slice(), None)
+    }
+}
+
+impl<const N: usize> IntoPayload for [u8; N] {
+    type Bytes = [u8; N];
+
+    fn into_payload(self) -> (Self::Bytes, Option<&'static str>) {
+        (self, None)
+    }
+}
+
+impl<'a> IntoPayload for &'a Vec<u8> {
+    type Bytes = &'a [u8];
+
+    fn into_payload(self) -> (Self::Bytes, Option<&'static str>) {
+        (self.as_slice(), None)
+    }
+}
+
+impl IntoPayload for Vec<u8> {
+    type Bytes = Vec<u8>;
+
+    fn into_payload(self) -> (Self::Bytes, Option<&'static str>) {
+        (self, None)
+    }
+}
+
+impl IntoPayload for Arc<[u8]> {
+    type Bytes = Arc<[u8]>;
+
+    fn into_payload(self) -> (Self::Bytes, Option<&'static str>) {
+        (self, None)
+    }
+}
+
+#[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+#[cfg(feature = "json")]
+impl IntoPayload for serde_json::Value {
+    type Bytes = Vec<u8>;
+
+    fn into_payload(self) -> (Self::Bytes, Option<&'static str>) {
+        (self.to_string().into_bytes(), Some(JSON_CONTENT_TYPE))
+    }
+}
+
+#[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+#[cfg(feature = "json")]
+impl IntoPayload for &serde_json::Value {
+    type Bytes = Vec<u8>;
+
+    fn into_payload(self) -> (Self::Bytes, Option<&'static str>) {
+        (self.to_string().into_bytes(), Some(JSON_CONTENT_TYPE))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn payload<P: IntoPayload>(p: P) -> (Vec<u8>, Option<&'static str>) {
+        let (bytes, content_type) = p.into_payload();
+        (bytes.as_ref().to_vec(), content_type)
+    }
+
+    #[test]
+    fn into_payload() {
+        let text = (b"hi".to_vec(), Some(TEXT_CONTENT_TYPE));
+        assert_eq!(payload("hi"), text);
+        let string = "hi".to_string();
+        assert_eq!(payload(&string), text);
+        assert_eq!(payload(string), text);
+
+        let bytes = (b"hi".to_vec(), None);
+        assert_eq!(payload(b"hi"), bytes);
+        assert_eq!(payload(*b"hi"), bytes);
+        assert_eq!(payload(b"hi".as_slice()), bytes);
+        let vec = b"hi".to_vec();
+        assert_eq!(payload(&vec), bytes);
+        assert_eq!(payload(vec), bytes);
+        assert_eq!(payload(Arc::<[u8]>::from(b"hi".as_slice())), bytes);
+
+        #[cfg(feature = "json")]
+        {
+            let value = serde_json::json!({"a": 1});
+            let json = (br#"{"a":1}"#.to_vec(), Some(JSON_CONTENT_TYPE));
+            assert_eq!(payload(&value), json);
+            assert_eq!(payload(value), json);
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use crate::lowlevel::{cstr, sys};
+use crate::Error;
+use std::convert::TryInto;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+use sys::mqtt5_property as prop;
+
+/// An MQTT v5 property.
+/// Properties can be attached to various MQTT v5 packets; which
+/// properties are valid depends on the packet type.
+/// See section 2.2.2.2 of <https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html>
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Property {
+    PayloadFormatIndicator(u8),
+    MessageExpiryInterval(u32),
+    ContentType(String),
+    ResponseTopic(String),
+    CorrelationData(Vec<u8>),
+    SubscriptionIdentifier(u32),
+    SessionExpiryInterval(u32),
+    AssignedClientIdentifier(String),
+    ServerKeepAlive(u16),
+    AuthenticationMethod(String),
+    AuthenticationData(Vec<u8>),
+    RequestProblemInformation(u8),
+    WillDelayInterval(u32),
+    RequestResponseInformation(u8),
+    ResponseInformation(String),
+    ServerReference(String),
+    ReasonString(String),
+    ReceiveMaximum(u16),
+    TopicAliasMaximum(u16),
+    TopicAlias(u16),
+    MaximumQoS(u8),
+    RetainAvailable(u8),
+    UserProperty(String, String),
+    MaximumPacketSize(u32),
+    WildcardSubAvailable(u8),
+    SubscriptionIdAvailable(u8),
+    SharedSubAvailable(u8),
+}
+
+impl Property {
+    /// Returns the number of bytes that the property occupies in an
+    /// encoded packet, including its one byte identifier
+    pub(crate) fn encoded_len(&self) -> usize {
+        1 + match self {
+            Self::PayloadFormatIndicator(_)
+            | Self::RequestProblemInformation(_)
+            | Self::RequestResponseInformation(_)
+            | Self::MaximumQoS(_)
+            | Self::RetainAvailable(_)
+            | Self::WildcardSubAvailable(_)
+            | Self::SubscriptionIdAvailable(_)
+            | Self::SharedSubAvailable(_) => 1,
+            Self::ServerKeepAlive(_)
+            | Self::ReceiveMaximum(_)
+            | Self::TopicAliasMaximum(_)
+            | Self::TopicAlias(_) => 2,
+            Self::MessageExpiryInterval(_)
+            | Self::SessionExpiryInterval(_)
+            | Self::WillDelayInterval(_)
+            | Self::MaximumPacketSize(_) => 4,
+            Self::SubscriptionIdentifier(v) => varint_len(*v as usize),
+            Self::ContentType(s)
+            | Self::ResponseTopic(s)
+            | Self::AssignedClientIdentifier(s)
+            | Self::AuthenticationMethod(s)
+            | Self::ResponseInformation(s)
+            | Self::ServerReference(s)
+            | Self::ReasonString(s) => 2 + s.len(),
+            Self::CorrelationData(v) | Self::AuthenticationData(v) => 2 + v.len(),
+            Self::UserProperty(k, v) => 4 + k.len() + v.len(),
+        }
+    }
+}
+
+/// Returns the number of bytes used to encode `n` as an MQTT
+/// variable byte integer
+pub(crate) fn varint_len(n: usize) -> usize {
+    match n {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    }
+}
+
+/// An owned libmosquitto property list
+pub(crate) struct PropertyList(*mut sys::mosquitto_property);
+
+impl PropertyList {
+    pub(crate) fn new(properties: &[Property]) -> Result<Self, Error> {
+        let mut list = Self(std::ptr::null_mut());
+        for p in properties {
+            list.add(p)?;
+        }
+        Ok(list)
+    }
+
+    fn add(&mut self, property: &Property) -> Result<(), Error> {
+        let list = &mut self.0;
+        let err = unsafe {
+            match property {
+                Property::PayloadFormatIndicator(v) => {
+                    byte(list, prop::MQTT_PROP_PAYLOAD_FORMAT_INDICATOR, *v)
+                }
+                Property::MessageExpiryInterval(v) => {
+                    int32(list, prop::MQTT_PROP_MESSAGE_EXPIRY_INTERVAL, *v)
+                }
+                Property::ContentType(v) => string(list, prop::MQTT_PROP_CONTENT_TYPE, v)?,
+                Property::ResponseTopic(v) => string(list, prop::MQTT_PROP_RESPONSE_TOPIC, v)?,
+                Property::CorrelationData(v) => binary(list, prop::MQTT_PROP_CORRELATION_DATA, v)?,
+                Property::SubscriptionIdentifier(v) => sys::mosquitto_property_add_varint(
+                    list,
+                    prop::MQTT_PROP_SUBSCRIPTION_IDENTIFIER as c_int,
+                    *v,
+                ),
+                Property::SessionExpiryInterval(v) => {
+                    int32(list, prop::MQTT_PROP_SESSION_EXPIRY_INTERVAL, *v)
+                }
+                Property::AssignedClientIdentifier(v) => {
+                    string(list, prop::MQTT_PROP_ASSIGNED_CLIENT_IDENTIFIER, v)?
+                }
+                Property::ServerKeepAlive(v) => int16(list, prop::MQTT_PROP_SERVER_KEEP_ALIVE, *v),
+                Property::AuthenticationMethod(v) => {
+                    string(list, prop::MQTT_PROP_AUTHENTICATION_METHOD, v)?
+                }
+                Property::AuthenticationData(v) => {
+                    binary(list, prop::MQTT_PROP_AUTHENTICATION_DATA, v)?
+                }
+                Property::RequestProblemInformation(v) => {
+                    byte(list, prop::MQTT_PROP_REQUEST_PROBLEM_INFORMATION, *v)
+                }
+                Property::WillDelayInterval(v) => {
+                    int32(list, prop::MQTT_PROP_WILL_DELAY_INTERVAL, *v)
+                }
+                Property::RequestResponseInformation(v) => {
+                    byte(list, prop::MQTT_PROP_REQUEST_RESPONSE_INFORMATION, *v)
+                }
+                Property::ResponseInformation(v) => {
+                    string(list, prop::MQTT_PROP_RESPONSE_INFORMATION, v)?
+                }
+                Property::ServerReference(v) => string(list, prop::MQTT_PROP_SERVER_REFERENCE, v)?,
+                Property::ReasonString(v) => string(list, prop::MQTT_PROP_REASON_STRING, v)?,
+                Property::ReceiveMaximum(v) => int16(list, prop::MQTT_PROP_RECEIVE_MAXIMUM, *v),
+                Property::TopicAliasMaximum(v) => {
+                    int16(list, prop::MQTT_PROP_TOPIC_ALIAS_MAXIMUM, *v)
+                }
+                Property::TopicAlias(v) => int16(list, prop::MQTT_PROP_TOPIC_ALIAS, *v),
+                Property::MaximumQoS(v) => byte(list, prop::MQTT_PROP_MAXIMUM_QOS, *v),
+                Property::RetainAvailable(v) => byte(list, prop::MQTT_PROP_RETAIN_AVAILABLE, *v),
+                Property::UserProperty(k, v) => {
+                    let k = cstr(k)?;
+                    let v = cstr(v)?;
+                    sys::mosquitto_property_add_string_pair(
+                        list,
+                        prop::MQTT_PROP_USER_PROPERTY as c_int,
+                        k.as_ptr(),
+                        v.as_ptr(),
+                    )
+                }
+                Property::MaximumPacketSize(v) => {
+                    int32(list, prop::MQTT_PROP_MAXIMUM_PACKET_SIZE, *v)
+                }
+                Property::WildcardSubAvailable(v) => {
+                    byte(list, prop::MQTT_PROP_WILDCARD_SUB_AVAILABLE, *v)
+                }
+                Property::SubscriptionIdAvailable(v) => {
+                    byte(list, prop::MQTT_PROP_SUBSCRIPTION_ID_AVAILABLE, *v)
+                }
+                Property::SharedSubAvailable(v) => {
+                    byte(list, prop::MQTT_PROP_SHARED_SUB_AVAILABLE, *v)
+                }
+            }
+        };
+        Error::result(err, ())
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const sys::mosquitto_property {
+        self.0
+    }
+
+    /// Relinquish ownership of the list, for use with the libmosquitto
+    /// functions that take ownership of the properties passed to them
+    pub(crate) fn into_raw(self) -> *mut sys::mosquitto_property {
+        let list = self.0;
+        std::mem::forget(self);
+        list
+    }
+}
+
+impl Drop for PropertyList {
+    fn drop(&mut self) {
+        unsafe {
+            sys::mosquitto_property_free_all(&mut self.0);
+        }
+    }
+}
+
+unsafe fn byte(list: &mut *mut sys::mosquitto_property, id: prop, v: u8) -> c_int {
+    sys::mosquitto_property_add_byte(list, id as c_int, v)
+}
+
+unsafe fn int16(list: &mut *mut sys::mosquitto_property, id: prop, v: u16) -> c_int {
+    sys::mosquitto_property_add_int16(list, id as c_int, v)
+}
+
+unsafe fn int32(list: &mut *mut sys::mosquitto_property, id: prop, v: u32) -> c_int {
+    sys::mosquitto_property_add_int32(list, id as c_int, v)
+}
+
+unsafe fn string(
+    list: &mut *mut sys::mosquitto_property,
+    id: prop,
+    v: &str,
+) -> Result<c_int, Error> {
+    let v = cstr(v)?;
+    Ok(sys::mosquitto_property_add_string(
+        list,
+        id as c_int,
+        v.as_ptr(),
+    ))
+}
+
+unsafe fn binary(
+    list: &mut *mut sys::mosquitto_property,
+    id: prop,
+    v: &[u8],
+) -> Result<c_int, Error> {
+    let len = v
+        .len()
+        .try_into()
+        .map_err(|_| Error::Mosq(sys::mosq_err_t::MOSQ_ERR_INVAL))?;
+    Ok(sys::mosquitto_property_add_binary(
+        list,
+        id as c_int,
+        v.as_ptr() as *const c_void,
+        len,
+    ))
+}
+
+/// Take ownership of a string allocated by libmosquitto
+unsafe fn take_string(s: *mut c_char) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    let result = CStr::from_ptr(s).to_string_lossy().into_owned();
+    libc::free(s as *mut c_void);
+    result
+}
+
+/// Convert a property list received from libmosquitto into
+/// the equivalent `Property` values
+pub(crate) unsafe fn properties_from_raw(list: *const sys::mosquitto_property) -> Vec<Property> {
+    let mut result = vec![];
+    let mut p = list;
+    while !p.is_null() {
+        if let Some(property) = read_property(p) {
+            result.push(property);
+        }
+        p = sys::mosquitto_property_next(p);
+    }
+    result
+}
+
+unsafe fn read_property(p: *const sys::mosquitto_property) -> Option<Property> {
+    let id = sys::mosquitto_property_identifier(p);
+
+    let byte = || {
+        let mut v = 0u8;
+        sys::mosquitto_property_read_byte(p, id, &mut v, false);
+        v
+    };
+    let int16 = || {
+        let mut v = 0u16;
+        sys::mosquitto_property_read_int16(p, id, &mut v, false);
+        v
+    };
+    let int32 = || {
+        let mut v = 0u32;
+        sys::mosquitto_property_read_int32(p, id, &mut v, false);
+        v
+    };
+    let string = || {
+        let mut v = std::ptr::null_mut();
+        sys::mosquitto_property_read_string(p, id, &mut v, false);
+        take_string(v)
+    };
+    let binary = || {
+        let mut v = std::ptr::null_mut();
+        let mut len = 0u16;
+        sys::mosquitto_property_read_binary(p, id, &mut v, &mut len, false);
+        if v.is_null() {
+            return vec![];
+        }
+        let data = std::slice::from_raw_parts(v as *const u8, len as usize).to_vec();
+        libc::free(v);
+        data
+    };
+
+    macro_rules! ids {
+        ($($name:ident),* $(,)?) => {
+            $(
+                #[allow(non_upper_case_globals)]
+                const $name: c_int = prop::$name as c_int;
+            )*
+        };
+    }
+    ids!(
+        MQTT_PROP_PAYLOAD_FORMAT_INDICATOR,
+        MQTT_PROP_MESSAGE_EXPIRY_INTERVAL,
+        MQTT_PROP_CONTENT_TYPE,
+        MQTT_PROP_RESPONSE_TOPIC,
+        MQTT_PROP_CORRELATION_DATA,
+        MQTT_PROP_SUBSCRIPTION_IDENTIFIER,
+        MQTT_PROP_SESSION_EXPIRY_INTERVAL,
+        MQTT_PROP_ASSIGNED_CLIENT_IDENTIFIER,
+        MQTT_PROP_SERVER_KEEP_ALIVE,
+        MQTT_PROP_AUTHENTICATION_METHOD,
+        MQTT_PROP_AUTHENTICATION_DATA,
+        MQTT_PROP_REQUEST_PROBLEM_INFORMATION,
+        MQTT_PROP_WILL_DELAY_INTERVAL,
+        MQTT_PROP_REQUEST_RESPONSE_INFORMATION,
+        MQTT_PROP_RESPONSE_INFORMATION,
+        MQTT_PROP_SERVER_REFERENCE,
+        MQTT_PROP_REASON_STRING,
+        MQTT_PROP_RECEIVE_MAXIMUM,
+        MQTT_PROP_TOPIC_ALIAS_MAXIMUM,
+        MQTT_PROP_TOPIC_ALIAS,
+        MQTT_PROP_MAXIMUM_QOS,
+        MQTT_PROP_RETAIN_AVAILABLE,
+        MQTT_PROP_USER_PROPERTY,
+        MQTT_PROP_MAXIMUM_PACKET_SIZE,
+        MQTT_PROP_WILDCARD_SUB_AVAILABLE,
+        MQTT_PROP_SUBSCRIPTION_ID_AVAILABLE,
+        MQTT_PROP_SHARED_SUB_AVAILABLE,
+    );
+
+    Some(match id {
+        MQTT_PROP_PAYLOAD_FORMAT_INDICATOR => Property::PayloadFormatIndicator(byte()),
+        MQTT_PROP_MESSAGE_EXPIRY_INTERVAL => Property::MessageExpiryInterval(int32()),
+        MQTT_PROP_CONTENT_TYPE => Property::ContentType(string()),
+        MQTT_PROP_RESPONSE_TOPIC => Property::ResponseTopic(string()),
+        MQTT_PROP_CORRELATION_DATA => Property::CorrelationData(binary()),
+        MQTT_PROP_SUBSCRIPTION_IDENTIFIER => {
+            let mut v = 0u32;
+            sys::mosquitto_property_read_varint(p, id, &mut v, false);
+            Property::SubscriptionIdentifier(v)
+        }
+        MQTT_PROP_SESSION_EXPIRY_INTERVAL => Property::SessionExpiryInterval(int32()),
+        MQTT_PROP_ASSIGNED_CLIENT_IDENTIFIER => Property::AssignedClientIdentifier(string()),
+        MQTT_PROP_SERVER_KEEP_ALIVE => Property::ServerKeepAlive(int16()),
+        MQTT_PROP_AUTHENTICATION_METHOD => Property::AuthenticationMethod(string()),
+        MQTT_PROP_AUTHENTICATION_DATA => Property::AuthenticationData(binary()),
+        MQTT_PROP_REQUEST_PROBLEM_INFORMATION => Property::RequestProblemInformation(byte()),
+        MQTT_PROP_WILL_DELAY_INTERVAL => Property::WillDelayInterval(int32()),
+        MQTT_PROP_REQUEST_RESPONSE_INFORMATION => Property::RequestResponseInformation(byte()),
+        MQTT_PROP_RESPONSE_INFORMATION => Property::ResponseInformation(string()),
+        MQTT_PROP_SERVER_REFERENCE => Property::ServerReference(string()),
+        MQTT_PROP_REASON_STRING => Property::ReasonString(string()),
+        MQTT_PROP_RECEIVE_MAXIMUM => Property::ReceiveMaximum(int16()),
+        MQTT_PROP_TOPIC_ALIAS_MAXIMUM => Property::TopicAliasMaximum(int16()),
+        MQTT_PROP_TOPIC_ALIAS => Property::TopicAlias(int16()),
+        MQTT_PROP_MAXIMUM_QOS => Property::MaximumQoS(byte()),
+        MQTT_PROP_RETAIN_AVAILABLE => Property::RetainAvailable(byte()),
+        MQTT_PROP_USER_PROPERTY => {
+            let mut k = std::ptr::null_mut();
+            let mut v = std::ptr::null_mut();
+            sys::mosquitto_property_read_string_pair(p, id, &mut k, &mut v, false);
+            Property::UserProperty(take_string(k), take_string(v))
+        }
+        MQTT_PROP_MAXIMUM_PACKET_SIZE => Property::MaximumPacketSize(int32()),
+        MQTT_PROP_WILDCARD_SUB_AVAILABLE => Property::WildcardSubAvailable(byte()),
+        MQTT_PROP_SUBSCRIPTION_ID_AVAILABLE => Property::SubscriptionIdAvailable(byte()),
+        MQTT_PROP_SHARED_SUB_AVAILABLE => Property::SharedSubAvailable(byte()),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn empty_list() {
+        assert_eq!(unsafe { properties_from_raw(std::ptr::null()) }, vec![]);
+        let list = PropertyList::new(&[]).unwrap();
+        assert!(list.as_ptr().is_null());
+    }
+}
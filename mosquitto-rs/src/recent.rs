@@ -0,0 +1,165 @@
+use crate::{Message, MessageId, QoS};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The most messages that are remembered by `RecentMessages`, regardless
+/// of its window, to bound its memory use when messages arrive quickly
+const MAX_RECENT_MESSAGES: usize = 65_536;
+
+/// How many bytes from each end of the payload are included in the
+/// fingerprint of a message
+const FINGERPRINT_SAMPLE: usize = 256;
+
+/// Identifies a received message for the purpose of spotting redeliveries.
+/// The broker reuses message ids once they have been acknowledged, so the
+/// payload length and a fingerprint of it are included to avoid mistaking
+/// a new message that reuses the id of a recent one on the same topic for
+/// a redelivery of it.  The payload itself isn't kept, as it may be large.
+type MessageKey = (MessageId, Arc<str>, usize, u64);
+
+/// Hash the start and the end of `payload`, so that the cost of checking
+/// a message doesn't grow with the size of its payload
+fn fingerprint(payload: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let head = payload.len().min(FINGERPRINT_SAMPLE);
+    let tail = payload.len().saturating_sub(FINGERPRINT_SAMPLE).max(head);
+    payload[..head].hash(&mut hasher);
+    payload[tail..].hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Remembers the QoS 1 and QoS 2 messages that were received within
+/// `window`, in order to spot redeliveries of them.
+/// This backs both `Message::dup` and `MqttRouter::deduplicate`.
+///
+/// Messages are forgotten in the order in which they were first seen,
+/// so checking a message costs amortized O(1) regardless of how many
+/// are remembered.
+pub(crate) struct RecentMessages {
+    window: Duration,
+    order: VecDeque<(Instant, MessageKey)>,
+    seen: HashSet<MessageKey>,
+}
+
+impl RecentMessages {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            order: VecDeque::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Returns true if `message` has the same message id, topic and
+    /// payload, as far as its length and fingerprint can tell, as one
+    /// that was seen within the window, otherwise
+    /// remembers it and returns false
+    pub(crate) fn is_repeat(&mut self, message: &Message) -> bool {
+        if message.qos == QoS::AtMostOnce {
+            return false;
+        }
+        let now = Instant::now();
+        while let Some((seen, key)) = self.order.front() {
+            if now.duration_since(*seen) < self.window && self.order.len() < MAX_RECENT_MESSAGES {
+                break;
+            }
+            self.seen.remove(key);
+            self.order.pop_front();
+        }
+
+        let key = (
+            message.mid,
+            Arc::clone(&message.topic),
+            message.payload.len(),
+            fingerprint(&message.payload),
+        );
+        if self.seen.contains(&key) {
+            return true;
+        }
+        if !self.window.is_zero() {
+            self.order.push_back((now, key.clone()));
+            self.seen.insert(key);
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn msg(mid: MessageId, topic: &str, payload: &str, qos: QoS) -> Message {
+        Message {
+            mid,
+            topic: topic.into(),
+            payload: payload.as_bytes().into(),
+            qos,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn repeats() {
+        let mut recent = RecentMessages::new(Duration::from_secs(60));
+        assert!(!recent.is_repeat(&msg(1, "a", "x", QoS::AtLeastOnce)));
+        assert!(recent.is_repeat(&msg(1, "a", "x", QoS::AtLeastOnce)));
+        assert!(!recent.is_repeat(&msg(1, "b", "x", QoS::AtLeastOnce)));
+        assert!(!recent.is_repeat(&msg(2, "a", "x", QoS::ExactlyOnce)));
+        // A reused message id with a different payload is a new message
+        assert!(!recent.is_repeat(&msg(1, "a", "y", QoS::AtLeastOnce)));
+        assert!(!recent.is_repeat(&msg(0, "a", "x", QoS::AtMostOnce)));
+        assert!(!recent.is_repeat(&msg(0, "a", "x", QoS::AtMostOnce)));
+
+        let mut recent = RecentMessages::new(Duration::ZERO);
+        assert!(!recent.is_repeat(&msg(1, "a", "x", QoS::AtLeastOnce)));
+        assert!(!recent.is_repeat(&msg(1, "a", "x", QoS::AtLeastOnce)));
+        assert!(recent.seen.is_empty());
+
+        // Large payloads are told apart by their ends and their length,
+        // without being kept
+        let large = |byte: u8, len: usize| {
+            let mut payload = vec![0u8; len];
+            payload[0] = byte;
+            Message {
+                mid: 1,
+                topic: "big".into(),
+                payload: payload.into(),
+                qos: QoS::AtLeastOnce,
+                ..Default::default()
+            }
+        };
+        let mut recent = RecentMessages::new(Duration::from_secs(60));
+        let message = large(1, 4 << 20);
+        assert!(!recent.is_repeat(&message));
+        assert_eq!(Arc::strong_count(&message.payload), 1);
+        assert!(recent.is_repeat(&large(1, 4 << 20)));
+        assert!(!recent.is_repeat(&large(2, 4 << 20)));
+        assert!(!recent.is_repeat(&large(1, (4 << 20) + 1)));
+    }
+
+    #[test]
+    fn expiry() {
+        let mut recent = RecentMessages::new(Duration::from_millis(20));
+        assert!(!recent.is_repeat(&msg(1, "a", "x", QoS::AtLeastOnce)));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!recent.is_repeat(&msg(1, "a", "x", QoS::AtLeastOnce)));
+        assert_eq!(recent.order.len(), 1);
+        assert_eq!(recent.seen.len(), 1);
+
+        let mut recent = RecentMessages::new(Duration::from_secs(60));
+        for mid in 0..MAX_RECENT_MESSAGES as u32 + 1 {
+            recent.is_repeat(&msg(
+                mid as MessageId,
+                &mid.to_string(),
+                "",
+                QoS::AtLeastOnce,
+            ));
+        }
+        assert_eq!(recent.order.len(), MAX_RECENT_MESSAGES);
+        assert_eq!(recent.seen.len(), MAX_RECENT_MESSAGES);
+        assert!(!recent.is_repeat(&msg(0, "0", "", QoS::AtLeastOnce)));
+    }
+}
@@ -1,12 +1,20 @@
-use crate::{Client, Message, QoS};
+use crate::log;
+use crate::recent::RecentMessages;
+use crate::{
+    Client, Event, GrantedQoS, IntoPayload, Message, MessageId, Property, ProtocolVersion,
+    PublishBuilder, QoS, RetainHandling, SubscriptionOptions, TopicFilter,
+};
 use matchit::Router;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde_json::Value as JsonValue;
+use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 /// An error returned from the Router and related types
@@ -14,10 +22,48 @@ use thiserror::Error;
 pub enum RouterError {
     #[error("payload is not utf8, cannot parse into string")]
     PayloadIsNotUtf8,
+    #[error("payload is not valid utf8: invalid byte at offset {offset}: {hexdump}")]
+    InvalidUtf8 { offset: usize, hexdump: String },
     #[error("failed to parse payload {text}: {error}")]
     PayloadParseFailed { text: String, error: String },
     #[error(transparent)]
     MqttError(#[from] crate::Error),
+    #[error("invalid route {route}: {reason}")]
+    InvalidRoute { route: String, reason: String },
+    #[error("cannot build a topic for route {route}: {reason}")]
+    FillRoute { route: String, reason: String },
+    #[error("cannot nest route {path} under {prefix}: {source}")]
+    NestInsertError {
+        prefix: String,
+        path: String,
+        #[source]
+        source: matchit::InsertError,
+    },
+    #[error("route {route} conflicts with the registered route {existing}")]
+    RouteConflict { route: String, existing: String },
+    #[error("invalid shared subscription group {group:?}: {reason}")]
+    InvalidSharedGroup { group: String, reason: String },
+    #[error("shared subscriptions require the client to use MQTT v5")]
+    SharedSubscriptionRequiresV5,
+    #[error("no route is registered for {0}")]
+    RouteNotFound(String),
+    #[error("the Rest extractor requires a route with a {{*catch-all}} parameter")]
+    NoCatchAll,
+    #[error("the Publisher extractor requires the message to be dispatched by an MqttRouter")]
+    NoClient,
+    #[error("the client's subscriber channel has already been taken")]
+    SubscriberTaken,
+    #[error(
+        "cannot reply to the message on {request_topic} as it has no response topic; \
+         use Response::publish to specify the topic"
+    )]
+    NoResponseTopic { request_topic: String },
+    #[error("failed to publish the response to {topic}: {source}")]
+    PublishResponse {
+        topic: String,
+        #[source]
+        source: crate::Error,
+    },
     #[error(transparent)]
     InsertError(#[from] matchit::InsertError),
     #[error(transparent)]
@@ -26,10 +72,80 @@ pub enum RouterError {
     JsonError(#[from] serde_json::Error),
     #[error(transparent)]
     Any(#[from] anyhow::Error),
+    #[error(transparent)]
+    Dispatch(Box<DispatchError>),
+    #[error(
+        "the handler{} panicked: {payload}",
+        route.as_ref().map(|route| format!(" for route {route}")).unwrap_or_default()
+    )]
+    HandlerPanicked {
+        /// The route whose handler panicked, or None for the fallback
+        route: Option<String>,
+        /// The panic message
+        payload: String,
+    },
+    #[error(
+        "the handler{} timed out after {elapsed:?}",
+        route.as_ref().map(|route| format!(" for route {route}")).unwrap_or_default()
+    )]
+    HandlerTimeout {
+        /// The route whose handler timed out, or None for the fallback
+        route: Option<String>,
+        /// How long the handler ran before it was cancelled
+        elapsed: Duration,
+    },
+    #[error("the message was skipped as a duplicate of one dispatched recently")]
+    Duplicate,
+}
+
+impl RouterError {
+    /// Returns true if the error indicates that the route itself is
+    /// misconfigured, rather than that the message being handled could
+    /// not be processed
+    pub fn is_misconfiguration(&self) -> bool {
+        match self {
+            Self::NoCatchAll
+            | Self::NoClient
+            | Self::InvalidRoute { .. }
+            | Self::InvalidSharedGroup { .. }
+            | Self::SharedSubscriptionRequiresV5
+            | Self::InsertError(_)
+            | Self::NestInsertError { .. }
+            | Self::RouteConflict { .. } => true,
+            Self::Dispatch(err) => err.error.is_misconfiguration(),
+            _ => false,
+        }
+    }
+}
+
+/// An error returned from [MqttRouter::dispatch], describing the
+/// message that could not be handled along with the underlying error
+#[derive(Error, Debug)]
+#[error(
+    "failed to handle message on {topic}{}: {error}",
+    route.as_ref().map(|route| format!(" for route {route}")).unwrap_or_default()
+)]
+pub struct DispatchError {
+    /// The route that matched the message, or None if no route matched
+    pub route: Option<String>,
+    /// The topic of the message
+    pub topic: String,
+    /// The delivery metadata of the message
+    pub meta: MessageMeta,
+    /// The error from the extractors, the handler or publishing
+    /// its responses
+    #[source]
+    pub error: RouterError,
+}
+
+impl From<DispatchError> for RouterError {
+    fn from(err: DispatchError) -> Self {
+        Self::Dispatch(Box::new(err))
+    }
 }
 
 pub type RouterResult<T> = Result<T, RouterError>;
-pub type MqttHandlerResult = anyhow::Result<()>;
+pub type MqttHandlerResult<R = ()> = anyhow::Result<R>;
 
 /// Represents the context for handling a "request", an incoming
 /// MQTT Message payload.
@@ -37,21 +153,425 @@ pub struct Request<S> {
     params: JsonValue,
     message: Message,
     state: S,
+    rest: Option<String>,
+    /// The client of the router that is dispatching the message
+    client: Option<Client>,
+    /// The route that matched the message
+    route: Option<Arc<str>>,
+}
+
+impl<S> Request<S> {
+    /// Returns the Message that is being handled
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+
+    /// Returns the parameters captured from the topic by the route,
+    /// as a JSON object keyed by parameter name
+    pub fn params(&self) -> &JsonValue {
+        &self.params
+    }
+
+    /// Returns the value of the route parameter `name`, or None if the
+    /// route has no such parameter
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).and_then(JsonValue::as_str)
+    }
+
+    /// Returns the router state
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Returns the route that matched the message, in the normalized
+    /// form returned by [MqttRouter::routes], or None if the message
+    /// is being handled by the [fallback](MqttRouter::fallback) handler
+    pub fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
 }
 
 /// FromRequest allows you to parse and extract information
-/// from a Request
+/// from a Request.  Extractors defined outside of this crate can
+/// use [Request::message], [Request::params] and [Request::state]:
+///
+/// ```rust
+/// use mosquitto_rs::router::{FromRequest, Request, RouterResult};
+///
+/// struct PayloadLen(usize);
+///
+/// impl<S> FromRequest<S> for PayloadLen {
+///   fn from_request(request: &Request<S>) -> RouterResult<Self> {
+///     Ok(Self(request.message().payload.len()))
+///   }
+/// }
+/// ```
+///
+/// Extractors that need to await something implement
+/// [FromRequestAsync] instead.
+///
+/// Tuples of extractors are themselves extractors, which allows
+/// building a custom extractor out of several existing ones:
+///
+/// ```rust
+/// use mosquitto_rs::router::{FromRequest, Payload, Request, RouterResult, Topic};
+///
+/// struct Reading {
+///   sensor: String,
+///   value: f64,
+/// }
+///
+/// impl<S> FromRequest<S> for Reading {
+///   fn from_request(request: &Request<S>) -> RouterResult<Self> {
+///     let (Topic(sensor), Payload(value)) = FromRequest::from_request(request)?;
+///     Ok(Self { sensor, value })
+///   }
+/// }
+/// ```
 pub trait FromRequest<S>: Sized {
     fn from_request(request: &Request<S>) -> RouterResult<Self>;
 }
 
+/// An extractor that needs to perform async work, such as looking up
+/// a record in a database, to produce its value.
+///
+/// The arguments of a handler are extracted using this trait, which is
+/// implemented for every [FromRequest] extractor, so implement whichever
+/// of the two traits suits the extractor.  As a consequence, an
+/// implementation of this trait must be for a specific state type `S`,
+/// rather than for any `S`.
+///
+/// The arguments are extracted one at a time, from left to right, each
+/// being awaited before the next is started; the first one that fails
+/// stops the extraction, and the handler is not called.
+/// Tuples, `Option` and `Result` only combine [FromRequest] extractors.
+///
+/// ```rust
+/// use mosquitto_rs::router::{FromRequestAsync, Request, RouterResult};
+/// use std::sync::Arc;
+///
+/// struct Database {}
+///
+/// impl Database {
+///   async fn lookup_device(&self, id: &str) -> Option<Device> {
+///     Some(Device { name: id.to_string() })
+///   }
+/// }
+///
+/// struct Device {
+///   name: String,
+/// }
+///
+/// impl FromRequestAsync<Arc<Database>> for Device {
+///   async fn from_request_async(request: &Request<Arc<Database>>) -> RouterResult<Self> {
+///     let id = request.param("id").unwrap_or_default();
+///     request
+///       .state()
+///       .lookup_device(id)
+///       .await
+///       .ok_or_else(|| anyhow::anyhow!("unknown device {id}").into())
+///   }
+/// }
+///
+/// async fn my_handler(device: Device) -> anyhow::Result<()> {
+///   println!("message for {}", device.name);
+///   Ok(())
+/// }
+/// ```
+pub trait FromRequestAsync<S>: Sized {
+    fn from_request_async(request: &Request<S>) -> impl Future<Output = RouterResult<Self>> + Send;
+}
+
+impl<S: Sync, T: FromRequest<S>> FromRequestAsync<S> for T {
+    async fn from_request_async(request: &Request<S>) -> RouterResult<Self> {
+        T::from_request(request)
+    }
+}
+
+/// Extracts a copy of the complete Message, for handlers that want
+/// the topic, payload and flags without any parsing.
+/// The topic and payload are shared rather than copied.
+impl<S> FromRequest<S> for Message {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        Ok(request.message.clone())
+    }
+}
+
+/// Makes an extractor optional: the value is `None` if the extraction
+/// fails, for example because the payload could not be parsed.
+///
+/// Errors that indicate that the route is misconfigured, such as using
+/// [Rest] with a route that has no `{*catch-all}` parameter, are still
+/// returned as errors; see [RouterError::is_misconfiguration].
+///
+/// ```rust
+/// use mosquitto_rs::router::{Payload, Utf8Lossy};
+/// async fn my_handler(
+///   number: Option<Payload<u32>>,
+///   Utf8Lossy(text): Utf8Lossy,
+/// ) -> anyhow::Result<()> {
+///   match number {
+///     Some(Payload(number)) => println!("number {number}"),
+///     None => println!("not a number: {text}"),
+///   }
+///   Ok(())
+/// }
+/// ```
+impl<S, T: FromRequest<S>> FromRequest<S> for Option<T> {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        match T::from_request(request) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.is_misconfiguration() => Err(err),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Captures the result of an extractor, so that the handler can
+/// inspect the error rather than having the dispatch fail with it.
+/// Unlike `Option<T>`, every error is captured.
+impl<S, T: FromRequest<S>> FromRequest<S> for Result<T, RouterError> {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        Ok(T::from_request(request))
+    }
+}
+
 /// An extractor for the topic portion of a Message
 pub struct Topic(pub String);
 
 /// Extracts the Message::topic from a Request and wraps it in a Topic.
 impl<S> FromRequest<S> for Topic {
     fn from_request(request: &Request<S>) -> RouterResult<Self> {
-        Ok(Self(request.message.topic.clone()))
+        Ok(Self(request.message.topic.to_string()))
+    }
+}
+
+/// An extractor for the MQTT message id of a Message.
+/// The id is only meaningful for QoS 1 and QoS 2 messages, and is
+/// only unique among the messages that are in flight at the same time;
+/// see [MqttRouter::deduplicate] for skipping redelivered messages.
+pub struct MessageIdExtractor(pub MessageId);
+
+impl<S> FromRequest<S> for MessageIdExtractor {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        Ok(Self(request.message.mid))
+    }
+}
+
+/// An extractor for the delivery metadata of a Message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MessageMeta {
+    pub qos: QoS,
+    pub retain: bool,
+    pub mid: MessageId,
+    pub dup: bool,
+}
+
+impl MessageMeta {
+    fn new(message: &Message) -> Self {
+        Self {
+            qos: message.qos,
+            retain: message.retain,
+            mid: message.mid,
+            dup: message.dup,
+        }
+    }
+}
+
+impl<S> FromRequest<S> for MessageMeta {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        Ok(Self::new(&request.message))
+    }
+}
+
+/// An extractor for the retain flag of a Message, which is set when
+/// the broker is delivering a retained message in response to a new
+/// subscription rather than relaying a live publish.
+///
+/// ```rust
+/// use mosquitto_rs::router::{Payload, Retained};
+/// async fn my_handler(
+///   Retained(retained): Retained,
+///   Payload(level): Payload<u8>,
+/// ) -> anyhow::Result<()> {
+///   if retained {
+///     // Only react to changes that happen while we are running
+///     return Ok(());
+///   }
+///   println!("level is now {level}");
+///   Ok(())
+/// }
+/// ```
+pub struct Retained(pub bool);
+
+impl<S> FromRequest<S> for Retained {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        Ok(Self(request.message.retain))
+    }
+}
+
+/// An extractor for the MQTT v5 Response Topic property of a Message.
+/// Together with [CorrelationData], this allows a handler to reply to
+/// a request; the value is `None` if the request did not specify a
+/// response topic.
+///
+/// ```rust
+/// use mosquitto_rs::Client;
+/// use mosquitto_rs::router::{CorrelationData, ResponseTopic, State};
+/// use mosquitto_rs::{PublishBuilder, QoS};
+///
+/// async fn my_handler(
+///   State(client): State<Client>,
+///   ResponseTopic(topic): ResponseTopic,
+///   CorrelationData(data): CorrelationData,
+/// ) -> anyhow::Result<()> {
+///   if let Some(topic) = topic {
+///     let mut reply = PublishBuilder::new(topic).payload("pong");
+///     if let Some(data) = data {
+///       reply = reply.correlation_data(data);
+///     }
+///     client.publish_with(reply).await?;
+///   }
+///   Ok(())
+/// }
+/// ```
+pub struct ResponseTopic(pub Option<String>);
+
+impl<S> FromRequest<S> for ResponseTopic {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        Ok(Self(request.message.properties.iter().find_map(
+            |p| match p {
+                Property::ResponseTopic(topic) => Some(topic.clone()),
+                _ => None,
+            },
+        )))
+    }
+}
+
+/// An extractor for the MQTT v5 Correlation Data property of a Message.
+/// See [ResponseTopic].
+pub struct CorrelationData(pub Option<Vec<u8>>);
+
+impl<S> FromRequest<S> for CorrelationData {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        Ok(Self(request.message.properties.iter().find_map(
+            |p| match p {
+                Property::CorrelationData(data) => Some(data.clone()),
+                _ => None,
+            },
+        )))
+    }
+}
+
+/// An extractor for the raw payload of a Message, for binary payloads
+/// such as CBOR or protobuf encoded data that cannot go through
+/// [Payload].  The bytes are shared with the Message rather than copied.
+///
+/// `Vec<u8>` and `String` can also be used directly as extractors, which
+/// copy the payload; `String` fails with `RouterError::PayloadIsNotUtf8`
+/// if the payload is not valid UTF-8.
+///
+/// Extractors don't consume the payload, so a handler that declares
+/// several payload extractors receives the complete payload in each of
+/// them.
+///
+/// ```rust
+/// use mosquitto_rs::router::Bytes;
+/// async fn my_handler(Bytes(data): Bytes) -> anyhow::Result<()> {
+///   println!("received {} bytes", data.len());
+///   Ok(())
+/// }
+/// ```
+pub struct Bytes(pub Arc<[u8]>);
+
+impl<S> FromRequest<S> for Bytes {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        Ok(Self(Arc::clone(&request.message.payload)))
+    }
+}
+
+/// Extracts a copy of the payload of a Message.  See [Bytes].
+impl<S> FromRequest<S> for Vec<u8> {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        Ok(request.message.payload.to_vec())
+    }
+}
+
+/// Extracts the payload of a Message as UTF-8 text.  See [Bytes].
+impl<S> FromRequest<S> for String {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        std::str::from_utf8(&request.message.payload)
+            .map(|s| s.to_string())
+            .map_err(|_| RouterError::PayloadIsNotUtf8)
+    }
+}
+
+/// An extractor for the payload of a Message as UTF-8 text.
+/// Unlike `Payload<String>`, which reports invalid UTF-8 without any
+/// detail, this fails with `RouterError::InvalidUtf8` which includes
+/// the offset of the first invalid byte and a hexdump of the bytes
+/// around it, with the invalid byte shown in brackets.
+///
+/// ```rust
+/// use mosquitto_rs::router::Utf8;
+/// async fn my_handler(Utf8(text): Utf8) -> anyhow::Result<()> {
+///   println!("{text}");
+///   Ok(())
+/// }
+/// ```
+pub struct Utf8(pub String);
+
+impl<S> FromRequest<S> for Utf8 {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        let payload = &request.message.payload;
+        match std::str::from_utf8(payload) {
+            Ok(s) => Ok(Self(s.to_string())),
+            Err(err) => {
+                let offset = err.valid_up_to();
+                Err(RouterError::InvalidUtf8 {
+                    offset,
+                    hexdump: hexdump_around(payload, offset),
+                })
+            }
+        }
+    }
+}
+
+/// Number of bytes either side of an invalid byte to include
+/// in the `RouterError::InvalidUtf8` hexdump
+const UTF8_ERROR_CONTEXT: usize = 8;
+
+fn hexdump_around(bytes: &[u8], offset: usize) -> String {
+    let start = offset.saturating_sub(UTF8_ERROR_CONTEXT);
+    let end = (offset + UTF8_ERROR_CONTEXT + 1).min(bytes.len());
+    let mut dump = vec![];
+    if start > 0 {
+        dump.push("..".to_string());
+    }
+    for (idx, b) in bytes.iter().enumerate().take(end).skip(start) {
+        if idx == offset {
+            dump.push(format!("[{b:02x}]"));
+        } else {
+            dump.push(format!("{b:02x}"));
+        }
+    }
+    if end < bytes.len() {
+        dump.push("..".to_string());
+    }
+    dump.join(" ")
+}
+
+/// An extractor for the payload of a Message as text, replacing any
+/// invalid UTF-8 sequences with `U+FFFD REPLACEMENT CHARACTER`.
+/// This never fails, which makes it suitable for logging payloads
+/// whose encoding is not trusted.
+pub struct Utf8Lossy(pub String);
+
+impl<S> FromRequest<S> for Utf8Lossy {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        Ok(Self(
+            String::from_utf8_lossy(&request.message.payload).into_owned(),
+        ))
     }
 }
 
@@ -88,6 +608,137 @@ where
     }
 }
 
+/// An extractor for the remainder of the topic matched by a
+/// `{*catch-all}` route parameter, which may span multiple topic
+/// levels.  The remainder is also available by name via [Params].
+///
+/// ```rust
+/// use mosquitto_rs::Client;
+/// use mosquitto_rs::router::{MqttRouter, Rest};
+///
+/// async fn my_handler(Rest(rest): Rest) -> anyhow::Result<()> {
+///   // For the topic `sensors/kitchen/temperature`, rest is
+///   // `kitchen/temperature`
+///   println!("sensor {rest}");
+///   Ok(())
+/// }
+///
+/// async fn setup_router() -> anyhow::Result<()> {
+///   let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+///   router.route("sensors/{*rest}", my_handler).await?;
+///   Ok(())
+/// }
+/// ```
+pub struct Rest(pub String);
+
+impl<S> FromRequest<S> for Rest {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        request
+            .rest
+            .clone()
+            .map(Self)
+            .ok_or(RouterError::NoCatchAll)
+    }
+}
+
+/// An extractor that gives the handler a handle to the router's
+/// [Client], so that it can publish replies or state updates without
+/// opening a second connection to the broker.
+///
+/// Publishing waits for the broker to acknowledge the message, which
+/// is processed by the client's own loop thread rather than by the
+/// dispatch loop, so it is safe to await from within a handler.
+///
+/// ```rust
+/// use mosquitto_rs::QoS;
+/// use mosquitto_rs::router::{Params, Payload, Publisher};
+/// use serde::Deserialize;
+///
+/// #[derive(Deserialize)]
+/// struct LightParams {
+///   name: String,
+/// }
+///
+/// async fn set_light(
+///   Params(params): Params<LightParams>,
+///   Payload(on): Payload<bool>,
+///   publisher: Publisher,
+/// ) -> anyhow::Result<()> {
+///   // ... switch the light ...
+///   publisher
+///     .publish_retained(format!("lights/{}/state", params.name), on.to_string(), QoS::AtLeastOnce)
+///     .await?;
+///   Ok(())
+/// }
+/// ```
+///
+/// Extraction fails with `RouterError::NoClient` if the handler was
+/// called directly via [Dispatcher::call] rather than by an `MqttRouter`.
+#[derive(Clone)]
+pub struct Publisher(pub Client);
+
+impl Publisher {
+    /// Publish `payload` to `topic`; see [Client::publish]
+    pub async fn publish<T: AsRef<str>, P: AsRef<[u8]>>(
+        &self,
+        topic: T,
+        payload: P,
+        qos: QoS,
+        retain: bool,
+    ) -> RouterResult<MessageId> {
+        Ok(self.0.publish(topic, payload, qos, retain).await?)
+    }
+
+    /// Publish `payload` to `topic` along with its content type;
+    /// see [Client::publish_payload]
+    pub async fn publish_payload<T: AsRef<str>, P: IntoPayload>(
+        &self,
+        topic: T,
+        payload: P,
+        qos: QoS,
+        retain: bool,
+    ) -> RouterResult<MessageId> {
+        Ok(self.0.publish_payload(topic, payload, qos, retain).await?)
+    }
+
+    /// Publish `payload` to `topic` as a retained message
+    pub async fn publish_retained<T: AsRef<str>, P: AsRef<[u8]>>(
+        &self,
+        topic: T,
+        payload: P,
+        qos: QoS,
+    ) -> RouterResult<MessageId> {
+        self.publish(topic, payload, qos, true).await
+    }
+
+    /// Serialize `value` as JSON and publish it to `topic`
+    pub async fn publish_json<T: AsRef<str>, V: serde::Serialize + ?Sized>(
+        &self,
+        topic: T,
+        value: &V,
+        qos: QoS,
+        retain: bool,
+    ) -> RouterResult<MessageId> {
+        let payload = serde_json::to_vec(value)?;
+        self.publish(topic, payload, qos, retain).await
+    }
+
+    /// Returns the underlying client
+    pub fn client(&self) -> &Client {
+        &self.0
+    }
+}
+
+impl<S> FromRequest<S> for Publisher {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        request
+            .client
+            .clone()
+            .map(Self)
+            .ok_or(RouterError::NoClient)
+    }
+}
+
 /// An extractor for the the topic portion of a Message.
 /// Any parameters defined by the Route are populated into a map
 /// and that map is deserialized into your type `T`.
@@ -109,7 +760,7 @@ where
 ///
 /// async fn setup_router() -> anyhow::Result<()> {
 ///   let mut router = <MqttRouter>::new(Client::with_auto_id()?);
-///   router.route("something/:user", my_handler).await?;
+///   router.route("something/{user}", my_handler).await?;
 ///   Ok(())
 /// }
 /// ```
@@ -124,6 +775,43 @@ where
     }
 }
 
+/// An extractor that yields the route parameters as a map from the
+/// parameter name to its value, without needing to define a type to
+/// deserialize them into as with [Params].
+/// The map is empty for a route that has no parameters.
+/// Use [Request::param] to access a single parameter from a custom
+/// extractor.
+///
+/// ```rust
+/// use mosquitto_rs::Client;
+/// use mosquitto_rs::router::{MqttRouter, ParamsMap};
+///
+/// async fn my_handler(ParamsMap(params): ParamsMap) -> anyhow::Result<()> {
+///   println!("the user from the topic is {}", params["user"]);
+///   Ok(())
+/// }
+///
+/// async fn setup_router() -> anyhow::Result<()> {
+///   let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+///   router.route("something/{user}", my_handler).await?;
+///   Ok(())
+/// }
+/// ```
+pub struct ParamsMap(pub HashMap<String, String>);
+
+impl<S> FromRequest<S> for ParamsMap {
+    fn from_request(request: &Request<S>) -> RouterResult<Self> {
+        let map = match &request.params {
+            JsonValue::Object(params) => params
+                .iter()
+                .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_string())))
+                .collect(),
+            _ => HashMap::new(),
+        };
+        Ok(Self(map))
+    }
+}
+
 /// An extractor that allows access to the State data associated with
 /// the router. The state value is passed down through `MqttRouter::dispatch`
 /// and will be cloned and passed to your handler.
@@ -148,45 +836,383 @@ where
     }
 }
 
-/// A helper struct to type-erase handler functions for the router.
-/// You do not normally need to consider the Dispatcher type directly,
-/// as it is an implementation detail managed via the `MakeDispatcher` trait.
-pub struct Dispatcher<S = ()>
-where
-    S: Clone + Send + Sync,
-{
-    func: Box<
-        dyn Fn(Request<S>) -> Pin<Box<dyn Future<Output = MqttHandlerResult> + Send>> + Send + Sync,
-    >,
+/// A message to be published by the router once a handler completes.
+/// A handler that returns a `Response`, or a `Vec<Response>`, has it
+/// published via the router's client:
+///
+/// ```rust
+/// use mosquitto_rs::QoS;
+/// use mosquitto_rs::router::{Payload, Response};
+///
+/// async fn set_level(Payload(level): Payload<u8>) -> anyhow::Result<Response> {
+///   // ... apply the level ...
+///   Ok(Response::publish("light/level", level.to_string())
+///     .qos(QoS::AtLeastOnce)
+///     .retain(true))
+/// }
+/// ```
+///
+/// For MQTT v5 request/response messaging, [Response::reply] creates a
+/// response that is published to the Response Topic of the message
+/// being handled, with its Correlation Data copied over:
+///
+/// ```rust
+/// use mosquitto_rs::router::{Payload, Response};
+///
+/// async fn add(Payload(number): Payload<u32>) -> anyhow::Result<Response> {
+///   Ok(Response::reply((number + 1).to_string()))
+/// }
+/// ```
+///
+/// Replying to a message that has no Response Topic fails with
+/// `RouterError::NoResponseTopic`.
+/// If publishing fails, dispatch fails with `RouterError::PublishResponse`
+/// naming the topic; any responses after it are not published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    /// The topic to publish to, or None to reply to the request
+    topic: Option<String>,
+    payload: Vec<u8>,
+    qos: QoS,
+    retain: bool,
 }
 
-impl<S: Clone + Send + Sync + 'static> Dispatcher<S> {
-    pub async fn call(&self, params: JsonValue, message: Message, state: S) -> MqttHandlerResult {
-        (self.func)(Request {
-            params,
-            message,
-            state,
-        })
-        .await
+impl Response {
+    /// Publish `payload` to `topic`, with `QoS::AtMostOnce` and
+    /// without retaining it by default
+    pub fn publish(topic: impl Into<String>, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            topic: Some(topic.into()),
+            payload: payload.into(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+        }
     }
 
-    pub fn new(
-        func: Box<
-            dyn Fn(Request<S>) -> Pin<Box<dyn Future<Output = MqttHandlerResult> + Send>>
+    /// Reply to the message being handled with `payload`, publishing it
+    /// to the message's MQTT v5 Response Topic along with its
+    /// Correlation Data, if any
+    pub fn reply(payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            topic: None,
+            payload: payload.into(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+        }
+    }
+
+    /// Build the message to publish in response to `request`
+    fn to_publish(&self, request: &Message) -> RouterResult<PublishBuilder> {
+        let (topic, correlation_data) = match &self.topic {
+            Some(topic) => (topic, None),
+            None => {
+                let topic = request
+                    .properties
+                    .iter()
+                    .find_map(|p| match p {
+                        Property::ResponseTopic(topic) => Some(topic),
+                        _ => None,
+                    })
+                    .ok_or_else(|| RouterError::NoResponseTopic {
+                        request_topic: request.topic.to_string(),
+                    })?;
+                let data = request.properties.iter().find_map(|p| match p {
+                    Property::CorrelationData(data) => Some(data),
+                    _ => None,
+                });
+                (topic, data)
+            }
+        };
+        let mut builder = PublishBuilder::new(topic)
+            .payload(self.payload.clone())
+            .qos(self.qos)
+            .retain(self.retain);
+        if let Some(data) = correlation_data {
+            builder = builder.correlation_data(data.clone());
+        }
+        Ok(builder)
+    }
+
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+}
+
+/// The types that can be returned from a handler to publish responses.
+/// [Response] and `Vec<Response>` are published by the router, while
+/// `()` publishes nothing.
+/// A handler returning `anyhow::Result<T>` fails the dispatch with
+/// the error, or publishes the `Ok` value.
+pub trait IntoResponse {
+    fn into_response(self) -> anyhow::Result<Vec<Response>>;
+}
+
+impl IntoResponse for () {
+    fn into_response(self) -> anyhow::Result<Vec<Response>> {
+        Ok(vec![])
+    }
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> anyhow::Result<Vec<Response>> {
+        Ok(vec![self])
+    }
+}
+
+impl IntoResponse for Vec<Response> {
+    fn into_response(self) -> anyhow::Result<Vec<Response>> {
+        Ok(self)
+    }
+}
+
+impl IntoResponse for Option<Response> {
+    fn into_response(self) -> anyhow::Result<Vec<Response>> {
+        Ok(self.into_iter().collect())
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for anyhow::Result<T> {
+    fn into_response(self) -> anyhow::Result<Vec<Response>> {
+        self?.into_response()
+    }
+}
+
+/// Converts the value returned by a handler into the result of
+/// dispatching to it, which has the type `R` of the [MqttRouter],
+/// along with any responses to publish.
+///
+/// For the default `R` of `()`, a handler may return any of the
+/// [IntoResponse] types.
+/// Otherwise, `R` must implement [HandlerValue], and the handler must
+/// return `anyhow::Result<R>`; the value is returned from
+/// [MqttRouter::dispatch] rather than published.
+pub trait IntoHandlerResult<R> {
+    fn into_handler_result(self) -> anyhow::Result<(R, Vec<Response>)>;
+}
+
+impl<T: IntoResponse> IntoHandlerResult<()> for T {
+    fn into_handler_result(self) -> anyhow::Result<((), Vec<Response>)> {
+        Ok(((), self.into_response()?))
+    }
+}
+
+impl<R: HandlerValue> IntoHandlerResult<R> for anyhow::Result<R> {
+    fn into_handler_result(self) -> anyhow::Result<(R, Vec<Response>)> {
+        Ok((self?, vec![]))
+    }
+}
+
+/// The types of value, other than the default `()`, that the handlers
+/// of an [MqttRouter] can produce; see [IntoHandlerResult].
+///
+/// It is implemented for the common standard types, and can be
+/// implemented for your own:
+///
+/// ```rust
+/// use mosquitto_rs::router::HandlerValue;
+///
+/// struct Reading {
+///   celsius: f64,
+/// }
+///
+/// impl HandlerValue for Reading {}
+/// ```
+///
+/// `()` doesn't implement it, as the handlers of a router with the
+/// default `R` return [IntoResponse] types instead, which include
+/// `anyhow::Result<()>`.
+pub trait HandlerValue {}
+
+macro_rules! impl_handler_value {
+    ($($ty:ty),*) => {
+        $(impl HandlerValue for $ty {})*
+    };
+}
+
+impl_handler_value!(
+    bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, String,
+    JsonValue, Response
+);
+
+impl<T> HandlerValue for Vec<T> {}
+impl<T> HandlerValue for Option<T> {}
+impl<T: ?Sized> HandlerValue for Box<T> {}
+impl<T: ?Sized> HandlerValue for Arc<T> {}
+
+/// Convert an error from a dispatcher into a RouterError, recovering
+/// the original RouterError from extractors and publishing responses
+fn handler_error(err: anyhow::Error) -> RouterError {
+    err.downcast().unwrap_or_else(RouterError::Any)
+}
+
+/// Run `future`, converting a panic while polling it into
+/// `RouterError::HandlerPanicked`.
+///
+/// Handlers aren't required to be `UnwindSafe`, so this asserts it on
+/// their behalf: after a panic the future is dropped without being
+/// polled again, but state that it shares with other handlers, such as
+/// the router state, may have been left inconsistent, and any `Mutex`
+/// that it held will be poisoned.
+async fn catch_unwind<T>(route: Option<&str>, future: impl Future<Output = T>) -> RouterResult<T> {
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(|cx| {
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(payload) => {
+                let payload = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "non-string panic payload".to_string());
+                std::task::Poll::Ready(Err(RouterError::HandlerPanicked {
+                    route: route.map(|route| route.to_string()),
+                    payload,
+                }))
+            }
+        }
+    })
+    .await
+}
+
+/// Publish the responses returned by a handler
+async fn publish_responses(
+    client: Option<&Client>,
+    request: &Message,
+    responses: Vec<Response>,
+) -> RouterResult<()> {
+    if responses.is_empty() {
+        return Ok(());
+    }
+    let client = client.ok_or(RouterError::NoClient)?;
+    for response in responses {
+        let message = response.to_publish(request)?;
+        let topic = message.topic().to_string();
+        client
+            .publish_with(message)
+            .await
+            .map_err(|source| RouterError::PublishResponse { topic, source })?;
+    }
+    Ok(())
+}
+
+type HandlerFuture<R> = Pin<Box<dyn Future<Output = MqttHandlerResult<R>> + Send>>;
+type HandlerFn<S, R> = dyn Fn(Request<S>) -> HandlerFuture<R> + Send + Sync;
+type LayerFn<S, R> = dyn Fn(Request<S>, Next<S, R>) -> HandlerFuture<R> + Send + Sync;
+
+/// A helper struct to type-erase handler functions for the router.
+/// You do not normally need to consider the Dispatcher type directly,
+/// as it is an implementation detail managed via the `MakeDispatcher` trait.
+/// `R` is the type of the value returned by the handler.
+pub struct Dispatcher<S = (), R = ()>
+where
+    S: Clone + Send + Sync,
+{
+    func: Arc<HandlerFn<S, R>>,
+    /// The name of the `{*catch-all}` parameter of the route, if any
+    catch_all: Option<String>,
+    /// The route that the dispatcher is registered for, if any
+    route: Option<Arc<str>>,
+    /// Shared with the route's RouteSubscription, for MqttRouter::metrics
+    stats: Arc<RouteStats>,
+    /// The timeout from the route's RouteOptions
+    timeout: Option<Duration>,
+}
+
+impl<S: Clone + Send + Sync + 'static, R: 'static> Dispatcher<S, R> {
+    pub async fn call(
+        &self,
+        params: JsonValue,
+        message: Message,
+        state: S,
+    ) -> MqttHandlerResult<R> {
+        (self.func)(self.request(params, message, state, None)).await
+    }
+
+    /// Build the request for the handler, making `client` available
+    /// to the [Publisher] extractor
+    fn request(
+        &self,
+        params: JsonValue,
+        message: Message,
+        state: S,
+        client: Option<Client>,
+    ) -> Request<S> {
+        let rest = self
+            .catch_all
+            .as_ref()
+            .and_then(|name| params.get(name))
+            .and_then(|rest| rest.as_str())
+            .map(|rest| rest.to_string());
+        Request {
+            params,
+            message,
+            state,
+            rest,
+            client,
+            route: self.route.clone(),
+        }
+    }
+
+    pub fn new(
+        func: Box<
+            dyn Fn(Request<S>) -> Pin<Box<dyn Future<Output = MqttHandlerResult<R>> + Send>>
                 + Send
                 + Sync,
         >,
     ) -> Self {
-        Self { func }
+        Self {
+            func: func.into(),
+            catch_all: None,
+            route: None,
+            stats: Arc::new(RouteStats::default()),
+            timeout: None,
+        }
+    }
+}
+
+/// The remainder of the middleware chain, passed to each of the
+/// functions registered via [MqttRouter::layer].
+/// Call [run](#method.run) to pass the request on to the next layer,
+/// and ultimately to the handler.
+pub struct Next<S, R = ()> {
+    /// All of the router's layers, shared rather than copied per hop
+    layers: Arc<[Arc<LayerFn<S, R>>]>,
+    /// The index of the next layer to run
+    index: usize,
+    handler: Arc<HandlerFn<S, R>>,
+}
+
+impl<S: Send + 'static, R: 'static> Next<S, R> {
+    /// Run the remaining layers and the handler for `request`
+    pub async fn run(self, request: Request<S>) -> MqttHandlerResult<R> {
+        match self.layers.get(self.index) {
+            Some(layer) => {
+                let layer = Arc::clone(layer);
+                let next = Next {
+                    layers: self.layers,
+                    index: self.index + 1,
+                    handler: self.handler,
+                };
+                layer(request, next).await
+            }
+            None => (self.handler)(request).await,
+        }
     }
 }
 
 /// A helper trait to adapt generic handler functions into `Dispatcher` instances
 /// that can be stored into a router.
 /// You do not normally need to consider the `MakeDispatcher` trait directly,
-/// as it is pre-registered for the compatible combinations of arguments.
-pub trait MakeDispatcher<T, S: Clone + Send + Sync> {
-    fn make_dispatcher(func: Self) -> Dispatcher<S>;
+/// as it is pre-registered for the compatible combinations of arguments
+/// and of return types; see [IntoHandlerResult].
+pub trait MakeDispatcher<T, S: Clone + Send + Sync, R = ()> {
+    fn make_dispatcher(func: Self) -> Dispatcher<S, R>;
 }
 
 macro_rules! impl_make_dispatcher {
@@ -194,28 +1220,32 @@ macro_rules! impl_make_dispatcher {
         [$($ty:ident),*], $last:ident
     ) => {
 
-impl<F, S, Fut, $($ty,)* $last> MakeDispatcher<($($ty,)* $last,), S> for F
+impl<F, S, R, Fut, Ret, $($ty,)* $last> MakeDispatcher<($($ty,)* $last,), S, R> for F
 where
     F: (Fn($($ty,)* $last) -> Fut) + Send + Sync + 'static,
-    Fut: Future<Output = MqttHandlerResult> + Send ,
+    Fut: Future<Output = Ret> + Send,
+    Ret: IntoHandlerResult<R>,
     S: Clone + Send + Sync + 'static,
-    $( $ty: FromRequest<S>, )*
-    $last: FromRequest<S>
+    R: Send + 'static,
+    $( $ty: FromRequestAsync<S> + Send, )*
+    $last: FromRequestAsync<S>
 {
     #[allow(non_snake_case)]
-    fn make_dispatcher(func: F) -> Dispatcher<S> {
+    fn make_dispatcher(func: F) -> Dispatcher<S, R> {
         let func = Arc::new(func);
-        let wrap: Box<dyn Fn(Request<S>) -> Pin<Box<dyn Future<Output = MqttHandlerResult> + Send>> + Send + Sync> =
+        let wrap: Box<dyn Fn(Request<S>) -> Pin<Box<dyn Future<Output = MqttHandlerResult<R>> + Send>> + Send + Sync> =
             Box::new(move |request: Request<S>| {
                 let func = func.clone();
                 Box::pin(async move {
                     $(
-                    let $ty = $ty::from_request(&request)?;
+                    let $ty = $ty::from_request_async(&request).await?;
                     )*
 
-                    let $last = $last::from_request(&request)?;
+                    let $last = $last::from_request_async(&request).await?;
 
-                    func($($ty,)* $last).await
+                    let (value, responses) = func($($ty,)* $last).await.into_handler_result()?;
+                    publish_responses(request.client.as_ref(), &request.message, responses).await?;
+                    Ok(value)
                 })
             });
 
@@ -250,6 +1280,27 @@ macro_rules! all_the_tuples {
 
 all_the_tuples!(impl_make_dispatcher);
 
+macro_rules! impl_from_request_tuple {
+    (
+        [$($ty:ident),*], $last:ident
+    ) => {
+        impl<S, $($ty,)* $last> FromRequest<S> for ($($ty,)* $last,)
+        where
+            $( $ty: FromRequest<S>, )*
+            $last: FromRequest<S>,
+        {
+            fn from_request(request: &Request<S>) -> RouterResult<Self> {
+                Ok((
+                    $( $ty::from_request(request)?, )*
+                    $last::from_request(request)?,
+                ))
+            }
+        }
+    };
+}
+
+all_the_tuples!(impl_from_request_tuple);
+
 /// The `MqttRouter` type helps to manage topic subscriptions and dispatching
 /// of matching messages to appropriate handler functions.
 ///
@@ -258,152 +1309,3887 @@ all_the_tuples!(impl_make_dispatcher);
 /// `Clone`. If you are using state, it is recommended that you use `Arc<S>`
 /// or otherwise internally use something like `Arc` where the clone operation
 /// is relatively cheap.
-pub struct MqttRouter<S = ()>
+///
+/// The router remembers the topic and QoS of each of its routes so that
+/// they can be re-subscribed after the client reconnects; feed the events
+/// from `Client::subscriber` into [handle_event](#method.handle_event)
+/// to have that happen automatically.
+///
+/// Routes are matched according to the [RoutingMode] of the router;
+/// see [new_wildcard](#method.new_wildcard) for the alternative to
+/// the default path-style routes.
+///
+/// The generic `R` parameter is the type of the value that the handlers
+/// produce, which is returned from [dispatch](#method.dispatch).
+/// It defaults to `()`, for handlers that act on messages and perhaps
+/// publish [responses](Response); a router with another `R` can be used
+/// to compute values from messages, with handlers that return
+/// `anyhow::Result<R>`, for an `R` that implements [HandlerValue]:
+///
+/// ```rust
+/// use mosquitto_rs::router::{MqttRouter, Payload, RouterResult};
+/// use mosquitto_rs::{Client, Message};
+///
+/// async fn double(Payload(number): Payload<u32>) -> anyhow::Result<u32> {
+///   Ok(number * 2)
+/// }
+///
+/// async fn compute(client: Client, message: Message) -> RouterResult<u32> {
+///   let mut router = MqttRouter::<(), u32>::new(client);
+///   router.route("math/double", double).await?;
+///   Ok(router.dispatch(message, ()).await?)
+/// }
+/// ```
+pub struct MqttRouter<S = (), R = ()>
 where
     S: Clone + Send + Sync,
 {
-    router: Router<Dispatcher<S>>,
+    mode: RoutingMode,
+    router: Router<Dispatcher<S, R>>,
+    /// The routes of a router in `RoutingMode::Wildcard`, in the order
+    /// in which they were registered
+    wildcard_routes: Vec<WildcardRoute<S, R>>,
     client: Client,
+    subscriptions: Vec<RouteSubscription>,
+    subscribed: Mutex<HashMap<String, SubscribedTopic>>,
+    next_route_id: u64,
+    connected: AtomicBool,
+    dedup: Option<Mutex<RecentMessages>>,
+    fallback: Option<Dispatcher<S, R>>,
+    error_hook: Option<ErrorHook>,
+    layers: Arc<[Arc<LayerFn<S, R>>]>,
+    metrics_enabled: bool,
+    metrics_sink: Option<Box<dyn MetricsSink>>,
+    default_timeout: Option<Duration>,
+    drain_timeout: Option<Duration>,
 }
 
-impl<S: Clone + Send + Sync + 'static> MqttRouter<S> {
-    /// Create a new router.
+/// How an [MqttRouter] matches the topics of messages to its routes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoutingMode {
+    /// Routes are written in a path syntax such as `sensors/{id}/temperature`,
+    /// as described for [MqttRouter::route], and each message is
+    /// dispatched to the single most specific route that matches it.
+    /// Routes that could match the same topics in the same way, such as
+    /// `a/{b}` and `a/{c}`, conflict.
+    #[default]
+    Paths,
+    /// Routes are mqtt subscription filters, such as
+    /// `sensors/+/temperature/#`, and each message is dispatched to every
+    /// route whose filter matches its topic, in the order in which the
+    /// routes were registered, using the same rules as the broker.
     ///
-    /// If you don't want to specify the state type, construct it using
-    /// this syntax, where the type name is enclosed in `<>`. That will
-    /// allow the compiler to use the default state type of `()` without
-    /// forcing you to write it out yourself.
-    ///
-    /// ```rust
-    /// use mosquitto_rs::router::MqttRouter;
-    /// use mosquitto_rs::Client;
+    /// A level of the filter may be written as `{name}` in place of `+`,
+    /// or `{*name}` in place of a final `#`, so that the value of that
+    /// level, or the remaining levels, is available to the [Params] and
+    /// [Rest] extractors; those extractors fail for routes that have no
+    /// named levels.  There is no escape syntax, as filters can't
+    /// contain the `{` and `}` characters of a named level, nor
+    /// subscribe to a literal `+` or `#`.
     ///
-    /// fn setup() -> anyhow::Result<()> {
-    ///   let router = <MqttRouter>::new(Client::with_auto_id()?);
-    ///   Ok(())
-    /// }
-    /// ```
+    /// Routes don't conflict with each other, except when the same route
+    /// is registered twice, which fails with `RouterError::RouteConflict`.
     ///
-    /// <https://www.reddit.com/r/rust/comments/ek6w5g/comment/fd91a0u/>
-    pub fn new(client: Client) -> Self {
-        Self {
-            router: Router::new(),
-            client,
+    /// Every message is tested against the filter of every route, so with
+    /// many routes this is considerably slower than `Paths`, which finds
+    /// the matching route in a tree.
+    Wildcard,
+}
+
+/// A route of a router in `RoutingMode::Wildcard`
+struct WildcardRoute<S: Clone + Send + Sync, R> {
+    id: RouteId,
+    /// The route as it was registered
+    path: String,
+    filter: TopicFilter,
+    /// The parameter name of each level of the filter that has one
+    names: Vec<Option<String>>,
+    dispatcher: Dispatcher<S, R>,
+}
+
+impl<S: Clone + Send + Sync, R> WildcardRoute<S, R> {
+    /// Extract the values of the named levels from `topic`, which
+    /// must match the filter
+    fn params(&self, topic: &str) -> JsonValue {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let last = self.names.len() - 1;
+        let mut value_map = serde_json::Map::new();
+        for (idx, name) in self.names.iter().enumerate() {
+            let Some(name) = name else { continue };
+            let value = if idx == last && self.dispatcher.catch_all.is_some() {
+                levels.get(idx..).unwrap_or_default().join("/")
+            } else {
+                levels.get(idx).copied().unwrap_or_default().to_string()
+            };
+            value_map.insert(name.clone(), value.into());
+        }
+        if value_map.is_empty() {
+            JsonValue::Null
+        } else {
+            JsonValue::Object(value_map)
         }
     }
+}
 
-    /// Register a route from a path like `foo/:bar` to a handler function.
-    /// The corresponding mqtt topic pattern (`foo/+` in this case) will be subscribed to.
-    /// When a message is received with that topic (say `foo/hello`) it will generate
-    /// a Request with an associated parameter map like `{"bar": "hello"}`.
-    /// Any extractors that you may have declared for your handler function parameters
-    /// will be applied to the request to parse out the needed information.
-    pub async fn route<'a, P, T, F>(&mut self, path: P, handler: F) -> RouterResult<()>
-    where
-        P: Into<String>,
-        F: MakeDispatcher<T, S>,
-    {
-        let path = path.into();
-        self.client
-            .subscribe(&route_to_topic(&path), QoS::AtMostOnce)
-            .await?;
-        let dispatcher = F::make_dispatcher(handler);
-        self.router.insert(path, dispatcher)?;
-        Ok(())
+/// A function that is called with the errors that occur in [MqttRouter::run]
+type ErrorHook = Box<dyn Fn(DispatchError) + Send + Sync>;
+
+/// A slot held by a handler that [MqttRouter::run_concurrent] has
+/// spawned, which is released when the handler completes or when its
+/// future is dropped, such as by an executor that is shutting down,
+/// so that the loop never waits for a handler that will never finish
+struct InFlightSlot(async_channel::Receiver<()>);
+
+impl Drop for InFlightSlot {
+    fn drop(&mut self) {
+        let _ = self.0.try_recv();
     }
+}
 
-    /// Dispatch an mqtt message to a registered handler.
-    pub async fn dispatch(&self, message: Message, state: S) -> RouterResult<()> {
-        let topic = message.topic.to_string();
-        let matched = self.router.at(&topic)?;
+/// Receive the next event, or None if `shutdown` has resolved or
+/// the channel has been closed
+async fn next_event<F: Future<Output = ()>>(
+    events: &async_channel::Receiver<Event>,
+    shutdown: Pin<&mut F>,
+) -> Option<Event> {
+    until_shutdown(events.recv(), shutdown)
+        .await
+        .and_then(Result::ok)
+}
 
-        let params = {
-            let mut value_map = serde_json::Map::new();
+/// How many times the run loops try to re-subscribe a route after
+/// a reconnect before giving up on it
+const RESUBSCRIBE_ATTEMPTS: usize = 6;
+/// The delay before the first retry of a failed re-subscription,
+/// which is doubled for each of the following retries
+const RESUBSCRIBE_INITIAL_DELAY: Duration = Duration::from_millis(500);
 
-            for (k, v) in matched.params.iter() {
-                value_map.insert(k.into(), v.into());
-            }
+/// How long each attempt to re-subscribe a route after a reconnect
+/// waits for the broker to respond, so that a SUBACK that will never
+/// arrive, because the connection was lost again, doesn't stall it
+const RESUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
 
-            if value_map.is_empty() {
-                serde_json::Value::Null
-            } else {
-                serde_json::Value::Object(value_map)
+/// Re-subscribing after a reconnect, which the run loops drive
+/// alongside the messages rather than holding them up
+type Resubscribe<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Await `future`, while also driving `resubscribe` until it completes
+async fn alongside<T>(
+    future: impl Future<Output = T>,
+    resubscribe: &mut Option<Resubscribe<'_>>,
+) -> T {
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(|cx| {
+        if let Some(pending) = resubscribe {
+            if pending.as_mut().poll(cx).is_ready() {
+                *resubscribe = None;
             }
-        };
+        }
+        future.as_mut().poll(cx)
+    })
+    .await
+}
+
+/// The delays between the attempts to re-subscribe after a reconnect
+fn resubscribe_delays() -> impl Iterator<Item = Duration> {
+    std::iter::successors(Some(RESUBSCRIBE_INITIAL_DELAY), |delay| Some(*delay * 2))
+        .take(RESUBSCRIBE_ATTEMPTS - 1)
+}
+
+/// Await `future`, or return None if `shutdown` resolves first
+async fn until_shutdown<T, F: Future<Output = ()>>(
+    future: impl Future<Output = T>,
+    mut shutdown: Pin<&mut F>,
+) -> Option<T> {
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(|cx| {
+        if shutdown.as_mut().poll(cx).is_ready() {
+            return std::task::Poll::Ready(None);
+        }
+        future.as_mut().poll(cx).map(Some)
+    })
+    .await
+}
 
-        Ok(matched.value.call(params, message, state).await?)
+/// Identifies a route registered with [MqttRouter::route], so that it
+/// can later be removed with [MqttRouter::unroute]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RouteId(u64);
+
+impl std::fmt::Display for RouteId {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "route #{}", self.0)
     }
+}
 
-    pub fn client(&self) -> &Client {
-        &self.client
+/// Selects the route to remove with [MqttRouter::unroute]: either the
+/// [RouteId] returned when it was registered, or its path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteSelector<'a> {
+    Id(RouteId),
+    Path(&'a str),
+}
+
+impl From<RouteId> for RouteSelector<'_> {
+    fn from(id: RouteId) -> Self {
+        Self::Id(id)
     }
 }
 
-/// A helper to deserialize from a string into any type that
-/// implements FromStr
-pub fn parse_deser<'de, D, T: FromStr>(d: D) -> Result<T, D::Error>
-where
-    D: serde::Deserializer<'de>,
-    <T as FromStr>::Err: std::fmt::Display,
-{
-    use serde::de::Error;
-    let s = String::deserialize(d)?;
-    s.parse::<T>()
-        .map_err(|err| D::Error::custom(format!("parsing {s}: {err:#}")))
+impl<'a> From<&'a str> for RouteSelector<'a> {
+    fn from(path: &'a str) -> Self {
+        Self::Path(path)
+    }
+}
+
+impl<'a> From<&'a String> for RouteSelector<'a> {
+    fn from(path: &'a String) -> Self {
+        Self::Path(path)
+    }
+}
+
+/// Describes a registered route; see [MqttRouter::routes]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteInfo {
+    pub id: RouteId,
+    /// The route, in its normalized form
+    pub path: String,
+    /// The mqtt topic pattern that was subscribed to for the route
+    pub topic: String,
+    /// The QoS that was requested for the subscription
+    pub qos: QoS,
+}
+
+/// The topic pattern and QoS that a route subscribes to
+struct RouteSubscription {
+    id: RouteId,
+    /// The route, in the syntax used by matchit
+    path: String,
+    /// The topic that was subscribed to
+    topic: String,
+    /// The parsed `topic`, unless it is a shared subscription, which
+    /// doesn't receive the same messages as the others
+    filter: Option<TopicFilter>,
+    options: RouteOptions,
+    stats: Arc<RouteStats>,
+}
+
+impl RouteSubscription {
+    /// Returns true if this route's subscription receives every message
+    /// that a subscription to `topic`, parsed as `filter`, with `options`
+    /// would, so that the broker would send those messages twice if
+    /// both were subscribed to
+    fn covers(&self, topic: &str, filter: &TopicFilter, options: &RouteOptions) -> bool {
+        self.topic != topic
+            && self.options.qos as u8 >= options.qos as u8
+            && self.options.subscription_options() == options.subscription_options()
+            && self
+                .filter
+                .as_ref()
+                .is_some_and(|cover| cover.covers(filter))
+    }
+}
+
+/// A topic pattern that the broker has accepted a subscription for.
+/// Several routes may share a topic pattern, as with `sensor-{id}` and
+/// `light-{id}`, which are both subscribed to as `+`.
+struct SubscribedTopic {
+    qos: QoS,
+    options: SubscriptionOptions,
+    granted: GrantedQoS,
 }
 
-/// Convert a Router route into the corresponding mqtt topic.
-/// `:foo` is replaced by `+`.
-fn route_to_topic(route: &str) -> String {
-    let mut result = String::new();
-    let mut in_param = false;
-    for c in route.chars() {
-        if c == ':' {
-            in_param = true;
-            result.push('+');
-            continue;
+/// The upper bounds of the buckets of a [LatencyHistogram]
+pub const LATENCY_BUCKETS: [Duration; 8] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+
+/// The metrics recorded for a route; see [MqttRouter::metrics]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteMetrics {
+    /// The route, in the normalized form returned by
+    /// [MqttRouter::routes], or None for the fallback handler
+    pub route: Option<String>,
+    /// The number of messages dispatched to the route
+    pub dispatches: u64,
+    /// The number of those dispatches that failed
+    pub errors: u64,
+    /// How long the dispatches took, including the middleware layers
+    pub latency: LatencyHistogram,
+}
+
+/// A histogram of handler latencies with fixed buckets
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LatencyHistogram {
+    /// `counts[i]` is the number of dispatches that took longer than
+    /// `LATENCY_BUCKETS[i - 1]` and at most `LATENCY_BUCKETS[i]`;
+    /// the final entry counts those that took longer than 5 seconds
+    pub counts: [u64; LATENCY_BUCKETS.len() + 1],
+    /// The total time taken by all of the dispatches
+    pub total: Duration,
+}
+
+impl LatencyHistogram {
+    /// Returns the mean latency, or None if nothing has been recorded
+    pub fn mean(&self) -> Option<Duration> {
+        let count: u64 = self.counts.iter().sum();
+        if count == 0 {
+            return None;
         }
-        if c == '/' {
-            in_param = false;
+        Some(Duration::from_nanos(
+            (self.total.as_nanos() / count as u128) as u64,
+        ))
+    }
+}
+
+/// Receives the measurements taken by the router, so that they can be
+/// forwarded to a metrics system such as prometheus; see
+/// [MqttRouter::set_metrics_sink]
+pub trait MetricsSink: Send + Sync {
+    /// Called after a message has been dispatched to `route`, which is
+    /// in the normalized form returned by [MqttRouter::routes], or None
+    /// for the fallback handler.  `error` is the reason that the
+    /// dispatch failed, if it did.
+    fn record(&self, route: Option<&str>, elapsed: Duration, error: Option<&RouterError>);
+}
+
+/// The counters behind RouteMetrics
+#[derive(Default)]
+struct RouteStats {
+    dispatches: AtomicU64,
+    errors: AtomicU64,
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    total_nanos: AtomicU64,
+}
+
+impl RouteStats {
+    fn record(&self, elapsed: Duration, failed: bool) {
+        self.dispatches.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
         }
-        if in_param {
-            continue;
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| elapsed <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = elapsed.as_nanos().try_into().unwrap_or(u64::MAX);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn metrics(&self, route: Option<String>) -> RouteMetrics {
+        RouteMetrics {
+            route,
+            dispatches: self.dispatches.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            latency: LatencyHistogram {
+                counts: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+                total: Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed)),
+            },
         }
-        result.push(c)
     }
-    result
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+/// A collection of routes that is built without a client, so that a
+/// module of an application can define its routes independently of the
+/// router, and optionally relative to a topic prefix that is chosen when
+/// they are added to the router via [MqttRouter::nest].
+/// A fragment is added without a prefix via [MqttRouter::merge].
+///
+/// ```rust
+/// use mosquitto_rs::router::{MqttRouter, Params, RouterFragment, RouterResult};
+///
+/// async fn set_light(Params(id): Params<String>) -> anyhow::Result<()> {
+///   Ok(())
+/// }
+///
+/// fn light_routes() -> RouterFragment {
+///   let mut lights = RouterFragment::new();
+///   lights.route("{id}/set", set_light);
+///   lights
+/// }
+///
+/// async fn setup(router: &mut MqttRouter) -> RouterResult<()> {
+///   // Handles home/lights/{id}/set
+///   router.nest("home/lights", light_routes()).await?;
+///   // Handles {id}/set
+///   router.merge(light_routes()).await?;
+///   Ok(())
+/// }
+/// ```
+pub struct RouterFragment<S = (), R = ()>
+where
+    S: Clone + Send + Sync,
+{
+    routes: Vec<(String, RouteOptions, Dispatcher<S, R>)>,
+}
 
-    #[test]
-    fn test_route_to_topic() {
-        for (route, expected_topic) in [
-            ("hello/:there", "hello/+"),
-            ("a/:b/foo", "a/+/foo"),
-            ("hello", "hello"),
-            ("who:", "who+"),
-        ] {
-            let topic = route_to_topic(route);
-            assert_eq!(
-                topic, expected_topic,
-                "route={route}, expected={expected_topic} actual={topic}"
-            );
-        }
+impl<S: Clone + Send + Sync + 'static, R: 'static> RouterFragment<S, R> {
+    pub fn new() -> Self {
+        Self { routes: vec![] }
     }
 
-    #[test]
-    fn routing() -> RouterResult<()> {
-        let mut router = Router::new();
-        router.insert("pv2mqtt/home", "Welcome!")?;
-        router.insert("pv2mqtt/users/:name/:id", "A User")?;
+    /// Add a route, relative to the prefix given to [MqttRouter::nest],
+    /// that is subscribed to with the default [RouteOptions]
+    pub fn route<T, F>(&mut self, path: impl Into<String>, handler: F) -> &mut Self
+    where
+        F: MakeDispatcher<T, S, R>,
+    {
+        self.route_with(path, RouteOptions::default(), handler)
+    }
 
-        let matched = router.at("pv2mqtt/users/foo/978")?;
-        assert_eq!(matched.params.get("id"), Some("978"));
-        assert_eq!(*matched.value, "A User");
+    /// Add a route, relative to the prefix given to [MqttRouter::nest],
+    /// that is subscribed to with `options`
+    pub fn route_with<T, F>(
+        &mut self,
+        path: impl Into<String>,
+        options: RouteOptions,
+        handler: F,
+    ) -> &mut Self
+    where
+        F: MakeDispatcher<T, S, R>,
+    {
+        self.routes
+            .push((path.into(), options, F::make_dispatcher(handler)));
+        self
+    }
+}
+
+/// A route of a [RouterFragment] that has been validated, and prefixed
+/// if it is being nested, but not yet registered
+struct PendingRoute<S: Clone + Send + Sync, R> {
+    /// The path of the route within the fragment
+    path: String,
+    route: ParsedRoute,
+    options: RouteOptions,
+    dispatcher: Dispatcher<S, R>,
+}
+
+impl<S: Clone + Send + Sync + 'static, R: 'static> Default for RouterFragment<S, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The subscription options for a route, used with
+/// [MqttRouter::route_with].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RouteOptions {
+    /// The QoS to subscribe with.  The default is `QoS::AtMostOnce`.
+    pub qos: QoS,
+    /// Whether the broker sends retained messages when subscribing.
+    /// Only supported for MQTT v5 connections.
+    pub retain_handling: RetainHandling,
+    /// Don't deliver messages that were published by this client.
+    /// Only supported for MQTT v5 connections.
+    pub no_local: bool,
+    /// How long the handler may run before it is cancelled; see
+    /// [timeout](#method.timeout).  The default is None, which uses the
+    /// router's [default timeout](MqttRouter::set_default_timeout).
+    pub timeout: Option<Duration>,
+    /// The MQTT v5 shared subscription group; see
+    /// [shared_group](#method.shared_group)
+    pub shared_group: Option<String>,
+}
+
+impl RouteOptions {
+    /// Subscribe to the route's topic as part of the shared subscription
+    /// `group`, so that each matching message is delivered to only one
+    /// of the clients in the group, spreading the load between several
+    /// instances of a service.
+    ///
+    /// The router subscribes to `$share/{group}/{topic}`, and matches
+    /// the messages that arrive, which don't have that prefix, against
+    /// the route as usual.  Requires the client to use MQTT v5;
+    /// registering the route fails with
+    /// `RouterError::SharedSubscriptionRequiresV5` otherwise.
+    ///
+    /// ```rust
+    /// use mosquitto_rs::router::{MqttRouter, Payload, RouteOptions, RouterResult};
+    ///
+    /// async fn handle_command(Payload(command): Payload<String>) -> anyhow::Result<()> {
+    ///   Ok(())
+    /// }
+    ///
+    /// async fn setup(router: &mut MqttRouter) -> RouterResult<()> {
+    ///   let options = RouteOptions::default().shared_group("workers");
+    ///   router.route_with("commands/{id}", options, handle_command).await?;
+    ///   Ok(())
+    /// }
+    /// ```
+    pub fn shared_group(mut self, group: impl Into<String>) -> Self {
+        self.shared_group.replace(group.into());
+        self
+    }
+
+    /// Check that the shared subscription group, if any, can be used
+    /// by `client`
+    fn check_shared_group(&self, client: &Client) -> RouterResult<()> {
+        let Some(group) = &self.shared_group else {
+            return Ok(());
+        };
+        let invalid = |reason: &str| RouterError::InvalidSharedGroup {
+            group: group.to_string(),
+            reason: reason.to_string(),
+        };
+        if group.is_empty() {
+            return Err(invalid("the group name must not be empty"));
+        }
+        if group.contains(['/', '+', '#']) {
+            return Err(invalid("the group name must not contain /, + or #"));
+        }
+        if client.protocol_version() != ProtocolVersion::V5 {
+            return Err(RouterError::SharedSubscriptionRequiresV5);
+        }
+        Ok(())
+    }
+
+    /// Returns the topic to subscribe to for the route's `topic` pattern
+    fn subscription_topic(&self, topic: &str) -> String {
+        match &self.shared_group {
+            Some(group) => format!("$share/{group}/{topic}"),
+            None => topic.to_string(),
+        }
+    }
+
+    /// Cancel the handler if it hasn't completed within `timeout`, in
+    /// which case dispatch fails with `RouterError::HandlerTimeout`.
+    /// This keeps a handler that is stuck awaiting an unresponsive
+    /// service from holding up the router indefinitely.
+    ///
+    /// The handler is cancelled by dropping its future at whichever
+    /// `.await` it is suspended in, so handlers need to be cancel safe:
+    /// for example, a handler that is cancelled while holding an async
+    /// lock or part way through a multi-step update can leave the shared
+    /// state half-updated.  The middleware [layers](MqttRouter::layer)
+    /// are included in the time limit.
+    ///
+    /// ```rust
+    /// use mosquitto_rs::router::RouteOptions;
+    /// use std::time::Duration;
+    ///
+    /// let options = RouteOptions::default().timeout(Duration::from_secs(5));
+    /// ```
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout.replace(timeout);
+        self
+    }
+
+    fn subscription_options(&self) -> SubscriptionOptions {
+        SubscriptionOptions {
+            no_local: self.no_local,
+            retain_handling: self.retain_handling,
+            ..Default::default()
+        }
+    }
+}
+
+impl<S: Clone + Send + Sync + 'static, R: Send + 'static> std::fmt::Debug for MqttRouter<S, R> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("MqttRouter")
+            .field("routes", &self.routes())
+            .field("fallback", &self.fallback.is_some())
+            .field("layers", &self.layers.len())
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+impl<S: Clone + Send + Sync + 'static, R: Send + 'static> MqttRouter<S, R> {
+    /// Create a new router.
+    ///
+    /// If you don't want to specify the state and result types, construct
+    /// it using this syntax, where the type name is enclosed in `<>`.
+    /// That will allow the compiler to use the default types of `()`
+    /// without forcing you to write them out yourself.
+    ///
+    /// ```rust
+    /// use mosquitto_rs::router::MqttRouter;
+    /// use mosquitto_rs::Client;
+    ///
+    /// fn setup() -> anyhow::Result<()> {
+    ///   let router = <MqttRouter>::new(Client::with_auto_id()?);
+    ///   Ok(())
+    /// }
+    /// ```
+    ///
+    /// <https://www.reddit.com/r/rust/comments/ek6w5g/comment/fd91a0u/>
+    pub fn new(client: Client) -> Self {
+        Self {
+            mode: RoutingMode::Paths,
+            router: Router::new(),
+            wildcard_routes: vec![],
+            client,
+            subscriptions: vec![],
+            subscribed: Mutex::new(HashMap::new()),
+            next_route_id: 1,
+            connected: AtomicBool::new(false),
+            dedup: None,
+            fallback: None,
+            error_hook: None,
+            layers: Arc::new([]),
+            metrics_enabled: false,
+            metrics_sink: None,
+            default_timeout: None,
+            drain_timeout: None,
+        }
+    }
+
+    /// Create a new router in [RoutingMode::Wildcard], whose routes are
+    /// mqtt subscription filters, and which dispatches each message to
+    /// all of the routes that match it.
+    ///
+    /// ```no_run
+    /// use mosquitto_rs::router::{MqttRouter, Params, RouterResult};
+    /// use mosquitto_rs::Client;
+    ///
+    /// async fn temperature(Params(sensor): Params<String>) -> anyhow::Result<()> {
+    ///   Ok(())
+    /// }
+    ///
+    /// async fn log_sensor(msg: mosquitto_rs::Message) -> anyhow::Result<()> {
+    ///   println!("{msg:?}");
+    ///   Ok(())
+    /// }
+    ///
+    /// async fn setup(client: Client) -> RouterResult<MqttRouter> {
+    ///   let mut router = <MqttRouter>::new_wildcard(client);
+    ///   // Both handlers are called for sensors/kitchen/temperature/celsius
+    ///   router.route("sensors/{sensor}/temperature/#", temperature).await?;
+    ///   router.route("sensors/#", log_sensor).await?;
+    ///   Ok(router)
+    /// }
+    /// ```
+    pub fn new_wildcard(client: Client) -> Self {
+        Self {
+            mode: RoutingMode::Wildcard,
+            ..Self::new(client)
+        }
+    }
+
+    /// Returns the [RoutingMode] of the router
+    pub fn routing_mode(&self) -> RoutingMode {
+        self.mode
+    }
+
+    /// Parse `route` according to the routing mode
+    fn parse(&self, route: &str) -> RouterResult<ParsedRoute> {
+        match self.mode {
+            RoutingMode::Paths => parse_route(route),
+            RoutingMode::Wildcard => Ok(parse_wildcard_route(route)?.0),
+        }
+    }
+
+    /// Register a route from a path like `foo/{bar}` to a handler function.
+    /// The corresponding mqtt topic pattern (`foo/+` in this case) will be subscribed to.
+    /// When a message is received with that topic (say `foo/hello`) it will generate
+    /// a Request with an associated parameter map like `{"bar": "hello"}`.
+    /// Any extractors that you may have declared for your handler function parameters
+    /// will be applied to the request to parse out the needed information.
+    ///
+    /// A route may end with a `{*rest}` catch-all parameter, which is
+    /// subscribed to as `#` and matches all of the remaining topic
+    /// levels; see [Rest].  Note that unlike the `#` wildcard, a
+    /// catch-all does not match the parent level, so `foo/{*rest}`
+    /// does not match the topic `foo`.
+    ///
+    /// The route syntax is:
+    ///
+    /// * `{name}` matches a single topic level, and is subscribed to as `+`.
+    ///   It may share the level with other characters, as in `{name}.json`,
+    ///   in which case the whole level is subscribed to as `+`
+    /// * `{*name}` as the final level matches the remaining levels,
+    ///   and is subscribed to as `#`
+    /// * `{{` and `}}` are a literal `{` and `}`
+    /// * `\` escapes the following character, so `\:` is a literal `:`
+    ///   and `\\` a literal backslash
+    /// * `+` and `#` are rejected, as they would be interpreted as
+    ///   wildcards by the broker but as literals by the router.
+    ///   Use `\+` and `\#` to match topics that literally contain them;
+    ///   mqtt has no way to subscribe to those characters, so the level
+    ///   that contains them is subscribed to as `+`, and messages that
+    ///   don't match the literal level are passed to the fallback
+    /// * all other characters match themselves
+    ///
+    /// The older `foo/:bar` parameter syntax is still accepted, but is
+    /// deprecated and logs a warning; as a result, a literal `:` must
+    /// be escaped as `\:`, as in `device\:{id}/state`, which is
+    /// subscribed to as `+/state`.
+    ///
+    /// The subscription is made with `QoS::AtMostOnce`; use
+    /// [route_with](#method.route_with) to specify the QoS and other
+    /// subscription options.
+    ///
+    /// The syntax above applies to the default [RoutingMode::Paths];
+    /// in [RoutingMode::Wildcard], `path` is an mqtt subscription filter.
+    ///
+    /// Yields the [RouteId] of the new route, which can be passed to
+    /// [unroute](#method.unroute) to remove it again.
+    pub async fn route<'a, P, T, F>(&mut self, path: P, handler: F) -> RouterResult<RouteId>
+    where
+        P: Into<String>,
+        F: MakeDispatcher<T, S, R>,
+    {
+        let (id, _granted) = self
+            .add_route(path.into(), RouteOptions::default(), handler)
+            .await?;
+        Ok(id)
+    }
+
+    /// Register a route in the same way as [route](#method.route), but
+    /// subscribing to the corresponding mqtt topic pattern with `options`.
+    ///
+    /// Yields the QoS granted by the broker for the subscription; a
+    /// warning is logged if it is lower than the requested QoS.
+    ///
+    /// If another route has already subscribed to the same topic pattern
+    /// with at least the same QoS and the same options, no new subscription
+    /// is made and the QoS that was granted for it is returned; this
+    /// prevents the broker from sending its retained messages again, which
+    /// would otherwise be dispatched a second time.  A subscription with a
+    /// higher QoS replaces the existing one for all of the routes that
+    /// share it.  The topic pattern is only unsubscribed from when the last
+    /// of those routes is [removed](#method.unroute).
+    ///
+    /// Likewise, a route whose topic pattern is covered by that of
+    /// another route, with at least the same QoS and the same options,
+    /// as `sensors/+/temperature` is by `sensors/#`, doesn't subscribe
+    /// on its own, and subscribing to the broader pattern unsubscribes
+    /// from the patterns that it covers.  The broker sends a copy of a
+    /// message for each of the subscriptions that match it, so
+    /// overlapping subscriptions would otherwise dispatch it twice.
+    /// Shared subscriptions are always made separately.
+    pub async fn route_with<P, T, F>(
+        &mut self,
+        path: P,
+        options: RouteOptions,
+        handler: F,
+    ) -> RouterResult<GrantedQoS>
+    where
+        P: Into<String>,
+        F: MakeDispatcher<T, S, R>,
+    {
+        let (_id, granted) = self.add_route(path.into(), options, handler).await?;
+        Ok(granted)
+    }
+
+    async fn add_route<T, F>(
+        &mut self,
+        path: String,
+        options: RouteOptions,
+        handler: F,
+    ) -> RouterResult<(RouteId, GrantedQoS)>
+    where
+        F: MakeDispatcher<T, S, R>,
+    {
+        let route = self.parse(&path)?;
+        options.check_shared_group(&self.client)?;
+        let topic = options.subscription_topic(&route.topic);
+        let granted = self.subscribe(&topic, &options).await?;
+        let id = self.insert_route(route, F::make_dispatcher(handler), options)?;
+        self.unsubscribe_covered().await?;
+        Ok((id, granted))
+    }
+
+    /// Register the routes of `fragment`, with `prefix` prepended to
+    /// each of their paths.  The prefix may contain parameters, which
+    /// are extracted along with those of the nested route, as in
+    /// `home/{room}` for a route `lights/{id}`.
+    ///
+    /// All of the routes are validated and registered before any of them
+    /// is subscribed to.  A route that conflicts with one that is already
+    /// registered fails with `RouterError::NestInsertError`, naming the
+    /// prefix and the path of the nested route, and leaves none of the
+    /// routes of `fragment` registered.  If subscribing fails, the routes
+    /// remain registered, and [resubscribe](#method.resubscribe) can be
+    /// used to retry.
+    ///
+    /// Yields the [RouteId] of each route, in the order in which they
+    /// were added to `fragment`.
+    pub async fn nest(
+        &mut self,
+        prefix: &str,
+        fragment: RouterFragment<S, R>,
+    ) -> RouterResult<Vec<RouteId>> {
+        let routes = self.pending_routes(prefix, fragment)?;
+        let ids = self.register_pending(Some(prefix), routes)?;
+        self.subscribe_routes(&ids).await?;
+        Ok(ids)
+    }
+
+    /// Register the routes of `fragment` and subscribe to them, so that
+    /// routes that were built separately, such as one fragment per module
+    /// of an application, can be combined into a single router.
+    ///
+    /// This behaves like [nest](#method.nest) with an empty prefix,
+    /// except that a route that conflicts with one that is already
+    /// registered fails with `RouterError::RouteConflict`, naming both
+    /// of the routes.
+    pub async fn merge(&mut self, other: RouterFragment<S, R>) -> RouterResult<()> {
+        let ids = self.register(other)?;
+        self.subscribe_routes(&ids).await
+    }
+
+    /// Register the routes of `fragment` without subscribing to them,
+    /// yielding the [RouteId] of each route, in the order in which they
+    /// were added to `fragment`.
+    ///
+    /// This doesn't require the client to be connected, so it can be used
+    /// to exercise the routes of a fragment via [dispatch](#method.dispatch)
+    /// in a unit test, or to defer subscribing until the client has
+    /// connected, by calling [resubscribe](#method.resubscribe).
+    /// Conflicts are reported as they are by [merge](#method.merge),
+    /// and leave none of the routes of `fragment` registered.
+    pub fn register(&mut self, fragment: RouterFragment<S, R>) -> RouterResult<Vec<RouteId>> {
+        let routes = self.pending_routes("", fragment)?;
+        self.register_pending(None, routes)
+    }
+
+    /// Prepend `prefix`, if it is not empty, to the routes of `fragment`
+    /// and validate them
+    fn pending_routes(
+        &self,
+        prefix: &str,
+        fragment: RouterFragment<S, R>,
+    ) -> RouterResult<Vec<PendingRoute<S, R>>> {
+        let prefix = prefix.strip_suffix('/').unwrap_or(prefix);
+        fragment
+            .routes
+            .into_iter()
+            .map(|(path, options, dispatcher)| {
+                let full_path = if prefix.is_empty() {
+                    path.clone()
+                } else if path.is_empty() {
+                    prefix.to_string()
+                } else {
+                    format!("{prefix}/{path}")
+                };
+                let route = self.parse(&full_path)?;
+                options.check_shared_group(&self.client)?;
+                Ok(PendingRoute {
+                    path,
+                    route,
+                    options,
+                    dispatcher,
+                })
+            })
+            .collect()
+    }
+
+    /// Register `routes`, removing those that were registered again
+    /// if any of them fails.  Conflicts are reported as nested under
+    /// `prefix` if it is set, or as merged otherwise.
+    fn register_pending(
+        &mut self,
+        prefix: Option<&str>,
+        routes: Vec<PendingRoute<S, R>>,
+    ) -> RouterResult<Vec<RouteId>> {
+        let mut ids = vec![];
+        for pending in routes {
+            let full_path = pending.route.path.clone();
+            match self.insert_route(pending.route, pending.dispatcher, pending.options) {
+                Ok(id) => ids.push(id),
+                Err(err) => {
+                    for id in ids {
+                        self.remove_route(id)?;
+                    }
+                    return Err(match (err, prefix) {
+                        (RouterError::InsertError(source), Some(prefix)) => {
+                            RouterError::NestInsertError {
+                                prefix: prefix.to_string(),
+                                path: pending.path,
+                                source,
+                            }
+                        }
+                        (
+                            RouterError::InsertError(matchit::InsertError::Conflict { with }),
+                            None,
+                        ) => RouterError::RouteConflict {
+                            route: full_path,
+                            existing: with,
+                        },
+                        (err, _) => err,
+                    });
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Subscribe to the topics of the routes identified by `ids`
+    async fn subscribe_routes(&self, ids: &[RouteId]) -> RouterResult<()> {
+        for sub in self
+            .subscriptions
+            .iter()
+            .filter(|sub| ids.contains(&sub.id))
+        {
+            self.subscribe(&sub.topic, &sub.options).await?;
+        }
+        self.unsubscribe_covered().await
+    }
+
+    async fn subscribe(&self, topic: &str, options: &RouteOptions) -> RouterResult<GrantedQoS> {
+        let (topic, options) = self.covering_subscription(topic, options);
+        if let Some(granted) = self.subscribed_topic(topic, options) {
+            return Ok(granted);
+        }
+        let granted = self
+            .client
+            .subscribe_with_options(topic, options.qos, options.subscription_options())
+            .await?;
+        if granted != GrantedQoS::Granted(options.qos) {
+            log::warn!(
+                "subscription to {topic} requested {:?} but the broker responded {granted:?}",
+                options.qos
+            );
+        }
+        if let GrantedQoS::Granted(_) = granted {
+            self.subscribed.lock().unwrap().insert(
+                topic.to_string(),
+                SubscribedTopic {
+                    qos: options.qos,
+                    options: options.subscription_options(),
+                    granted,
+                },
+            );
+        }
+        Ok(granted)
+    }
+
+    /// Returns the topic and options to subscribe to in order to receive
+    /// the messages for `topic`: those of the broadest route whose
+    /// subscription covers it, or else `topic` itself.
+    /// Overlapping subscriptions would have the broker send a copy of
+    /// each message for each of them, which would then be dispatched
+    /// once per copy.
+    fn covering_subscription<'a>(
+        &'a self,
+        topic: &'a str,
+        options: &'a RouteOptions,
+    ) -> (&'a str, &'a RouteOptions) {
+        let mut cover = (topic, options);
+        while cover.1.shared_group.is_none() {
+            let Ok(filter) = TopicFilter::new(cover.0) else {
+                break;
+            };
+            match self
+                .subscriptions
+                .iter()
+                .find(|sub| sub.covers(cover.0, &filter, cover.1))
+            {
+                Some(sub) => cover = (&sub.topic, &sub.options),
+                None => break,
+            }
+        }
+        cover
+    }
+
+    /// Unsubscribe from the topics whose messages are now received
+    /// through the subscription of a broader route
+    async fn unsubscribe_covered(&self) -> RouterResult<()> {
+        let covered: Vec<String> = {
+            let subscribed = self.subscribed.lock().unwrap();
+            subscribed
+                .keys()
+                .filter(|topic| {
+                    self.subscriptions
+                        .iter()
+                        .filter(|sub| &sub.topic == *topic)
+                        .all(|sub| {
+                            let (cover, _) = self.covering_subscription(&sub.topic, &sub.options);
+                            cover != sub.topic && subscribed.contains_key(cover)
+                        })
+                })
+                .cloned()
+                .collect()
+        };
+        for topic in covered {
+            self.client.unsubscribe(&topic).await?;
+            self.subscribed.lock().unwrap().remove(&topic);
+        }
+        Ok(())
+    }
+
+    /// Returns the QoS granted for `topic`, if it has already been
+    /// subscribed to in a way that satisfies `options`
+    fn subscribed_topic(&self, topic: &str, options: &RouteOptions) -> Option<GrantedQoS> {
+        let subscribed = self.subscribed.lock().unwrap();
+        let existing = subscribed.get(topic)?;
+        (existing.qos as u8 >= options.qos as u8
+            && existing.options == options.subscription_options())
+        .then_some(existing.granted)
+    }
+
+    fn insert_route(
+        &mut self,
+        route: ParsedRoute,
+        mut dispatcher: Dispatcher<S, R>,
+        options: RouteOptions,
+    ) -> RouterResult<RouteId> {
+        dispatcher.catch_all = route.catch_all;
+        dispatcher.route = Some(route.path.as_str().into());
+        dispatcher.timeout = options.timeout;
+        let stats = Arc::clone(&dispatcher.stats);
+        let id = RouteId(self.next_route_id);
+        match self.mode {
+            RoutingMode::Paths => self.router.insert(route.path.clone(), dispatcher)?,
+            RoutingMode::Wildcard => {
+                if self.wildcard_routes.iter().any(|r| r.path == route.path) {
+                    return Err(RouterError::RouteConflict {
+                        route: route.path.clone(),
+                        existing: route.path,
+                    });
+                }
+                let (_, names) = parse_wildcard_route(&route.path)?;
+                self.wildcard_routes.push(WildcardRoute {
+                    id,
+                    path: route.path.clone(),
+                    filter: TopicFilter::new(&route.topic)?,
+                    names,
+                    dispatcher,
+                });
+            }
+        }
+        self.next_route_id += 1;
+        self.subscriptions.push(RouteSubscription {
+            id,
+            path: route.path,
+            topic: options.subscription_topic(&route.topic),
+            filter: match options.shared_group {
+                Some(_) => None,
+                None => TopicFilter::new(&route.topic).ok(),
+            },
+            options,
+            stats,
+        });
+        Ok(id)
+    }
+
+    /// Returns the registered routes, in the order in which they were
+    /// registered, along with the mqtt topic pattern that each of them
+    /// subscribes to, including the `$share/{group}/` prefix for a
+    /// [shared](RouteOptions::shared_group) subscription.  A pattern that
+    /// is covered by that of another route is received through that
+    /// route's subscription.
+    ///
+    /// The routes are returned in their normalized form, so a route
+    /// registered with the deprecated `:name` syntax is shown as
+    /// `{name}`, and backslash escapes are removed.
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.subscriptions
+            .iter()
+            .map(|sub| RouteInfo {
+                id: sub.id,
+                path: sub.path.clone(),
+                topic: sub.topic.clone(),
+                qos: sub.options.qos,
+            })
+            .collect()
+    }
+
+    /// Build a concrete topic for the registered route `path` from
+    /// `params`, as described for [fill_route].
+    /// Fails with `RouterError::RouteNotFound` if `path` isn't registered,
+    /// which catches typos that would otherwise yield a topic that no
+    /// route handles.
+    pub fn topic_for(&self, path: &str, params: &impl serde::Serialize) -> RouterResult<String> {
+        let route = self.parse(path)?;
+        if !self.subscriptions.iter().any(|sub| sub.path == route.path) {
+            return Err(RouterError::RouteNotFound(path.to_string()));
+        }
+        fill_route(path, params)
+    }
+
+    /// Remove a route, so that its handler is no longer called.
+    /// The route is selected either by the [RouteId] returned by
+    /// [route](#method.route), or by its path, which must be written in
+    /// the same way as when it was registered.
+    /// The corresponding mqtt topic pattern is unsubscribed from, unless
+    /// another of the remaining routes also subscribes to it.  The routes
+    /// whose patterns it covered, as described for
+    /// [route_with](#method.route_with), are subscribed to first.
+    /// Fails with `RouterError::RouteNotFound` if there is no such route.
+    pub async fn unroute<'a>(&mut self, route: impl Into<RouteSelector<'a>>) -> RouterResult<()> {
+        let idx = self.route_index(route.into())?;
+        let removed = &self.subscriptions[idx];
+        // The routes whose messages were received through the removed
+        // route's subscription need subscriptions of their own
+        let covered: Vec<(String, RouteOptions)> =
+            if self.subscribed.lock().unwrap().contains_key(&removed.topic) {
+                self.subscriptions
+                    .iter()
+                    .filter(|sub| sub.id != removed.id)
+                    .filter(|sub| {
+                        self.covering_subscription(&sub.topic, &sub.options).0 == removed.topic
+                    })
+                    .map(|sub| (sub.topic.clone(), sub.options.clone()))
+                    .collect()
+            } else {
+                vec![]
+            };
+        let removed = self.remove_route(removed.id)?;
+        for (topic, options) in covered {
+            self.subscribe(&topic, &options).await?;
+        }
+        if let Some(topic) = removed {
+            self.client.unsubscribe(&topic).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the selected route, returning its topic pattern if
+    /// that is no longer subscribed to by any other route
+    fn remove_route<'a>(
+        &mut self,
+        route: impl Into<RouteSelector<'a>>,
+    ) -> RouterResult<Option<String>> {
+        let idx = self.route_index(route.into())?;
+        let removed = self.subscriptions.remove(idx);
+        match self.mode {
+            RoutingMode::Paths => {
+                self.router.remove(&removed.path);
+            }
+            RoutingMode::Wildcard => self.wildcard_routes.retain(|route| route.id != removed.id),
+        }
+        if self
+            .subscriptions
+            .iter()
+            .any(|sub| sub.topic == removed.topic)
+        {
+            Ok(None)
+        } else {
+            self.subscribed.get_mut().unwrap().remove(&removed.topic);
+            Ok(Some(removed.topic))
+        }
+    }
+
+    /// Returns the index of the selected route in `subscriptions`
+    fn route_index(&self, route: RouteSelector) -> RouterResult<usize> {
+        Ok(match route {
+            RouteSelector::Id(id) => self
+                .subscriptions
+                .iter()
+                .position(|sub| sub.id == id)
+                .ok_or_else(|| RouterError::RouteNotFound(id.to_string()))?,
+            RouteSelector::Path(path) => {
+                let route = self.parse(path)?;
+                self.subscriptions
+                    .iter()
+                    .position(|sub| sub.path == route.path)
+                    .ok_or_else(|| RouterError::RouteNotFound(path.to_string()))?
+            }
+        })
+    }
+
+    /// Re-issue the subscription for every registered route.
+    /// This is needed after the client has reconnected with a clean
+    /// session, as the broker will have discarded the subscriptions
+    /// along with the rest of the session state.
+    /// Routes that share a topic pattern are subscribed to it once.
+    pub async fn resubscribe(&self) -> RouterResult<()> {
+        self.subscribed.lock().unwrap().clear();
+        for sub in &self.subscriptions {
+            self.subscribe(&sub.topic, &sub.options).await?;
+        }
+        Ok(())
+    }
+
+    /// Process an event received from the channel returned by
+    /// `Client::subscriber`.
+    /// Messages are passed to [dispatch](#method.dispatch), and its
+    /// errors are returned as `RouterError::Dispatch`.
+    /// Every successful connection after the first causes the routes
+    /// to be [re-subscribed](#method.resubscribe), so that the handlers
+    /// continue to receive messages after the client has reconnected,
+    /// unless the broker resumed the session, and with it the
+    /// subscriptions.
+    ///
+    /// ```no_run
+    /// use mosquitto_rs::router::{MqttRouter, RouterResult};
+    ///
+    /// async fn run(router: MqttRouter) -> RouterResult<()> {
+    ///   let events = router.client().subscriber().unwrap();
+    ///   while let Ok(event) = events.recv().await {
+    ///     if let Err(err) = router.handle_event(event, ()).await {
+    ///       eprintln!("{err:#}");
+    ///     }
+    ///   }
+    ///   Ok(())
+    /// }
+    /// ```
+    pub async fn handle_event(&self, event: Event, state: S) -> RouterResult<()> {
+        match event {
+            Event::Message(message) => {
+                self.dispatch(message, state).await?;
+                Ok(())
+            }
+            Event::Connected(status) => {
+                if status.is_successful() && self.reconnected(self.client.session_present()) {
+                    self.resubscribe().await?;
+                }
+                Ok(())
+            }
+            Event::Disconnected(_) | Event::Reconnecting { .. } => Ok(()),
+        }
+    }
+
+    /// Run the dispatch loop: receive the events from the client's
+    /// [subscriber](crate::Client::subscriber) channel and pass them to
+    /// [handle_event](#method.handle_event), until `shutdown` resolves
+    /// or the channel is closed because the client has disconnected.
+    ///
+    /// Errors from dispatching individual messages don't stop the loop;
+    /// they are passed to the hook set via [on_error](#method.on_error),
+    /// or logged if there is none.
+    ///
+    /// After the client has reconnected, every route is re-subscribed to
+    /// with its own options, as described for
+    /// [handle_event](#method.handle_event).  The routes whose
+    /// subscriptions fail, or aren't acknowledged within 10 seconds, are
+    /// retried with an exponential backoff, for up to 6 attempts, and
+    /// each failure is passed to the error hook along with the route and
+    /// its topic pattern.  Messages continue to be dispatched while the
+    /// routes are re-subscribed, and a further reconnect starts
+    /// re-subscribing afresh.
+    ///
+    /// Once `shutdown` resolves, no more messages are received; messages
+    /// that are queued in the channel are not handled.  `shutdown` is only
+    /// checked between messages, so the message that is being handled at
+    /// that time is handled to completion, subject to the route
+    /// [timeouts](RouteOptions::timeout).
+    ///
+    /// Fails with `RouterError::SubscriberTaken` if the subscriber
+    /// channel has already been taken from the client.
+    ///
+    /// ```no_run
+    /// use mosquitto_rs::router::{MqttRouter, RouterResult};
+    ///
+    /// async fn run(router: MqttRouter, stop: async_channel::Receiver<()>) -> RouterResult<()> {
+    ///   router
+    ///     .run((), async move {
+    ///       let _ = stop.recv().await;
+    ///     })
+    ///     .await
+    /// }
+    /// ```
+    pub async fn run(
+        self,
+        state: S,
+        shutdown: impl Future<Output = ()> + Send,
+    ) -> RouterResult<()> {
+        let events = self
+            .client
+            .subscriber()
+            .ok_or(RouterError::SubscriberTaken)?;
+        self.run_events(events, state, shutdown).await;
+        Ok(())
+    }
+
+    async fn run_events(
+        &self,
+        events: async_channel::Receiver<Event>,
+        state: S,
+        shutdown: impl Future<Output = ()> + Send,
+    ) {
+        let mut shutdown = std::pin::pin!(shutdown);
+        let mut resubscribe = None;
+        while let Some(event) =
+            alongside(next_event(&events, shutdown.as_mut()), &mut resubscribe).await
+        {
+            match event {
+                Event::Message(message) => {
+                    let dispatch = self.dispatch(message, state.clone());
+                    if let Err(err) = alongside(dispatch, &mut resubscribe).await {
+                        self.report_error(err);
+                    }
+                }
+                event => {
+                    if let Some(pending) = self.handle_other_event(event, state.clone()).await {
+                        resubscribe = Some(pending);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Like [run](#method.run), but rather than waiting for each
+    /// message to be handled before receiving the next, each dispatch
+    /// is passed to `spawner` to be run on your executor, so that a
+    /// slow handler doesn't hold up the others.  At most
+    /// `max_in_flight` messages are handled at the same time; when
+    /// that limit is reached, no more messages are received until one
+    /// of the handlers completes, or until its dispatch is dropped by
+    /// the executor without completing.  A limit of 0 is treated as 1.
+    ///
+    /// Messages are no longer guaranteed to be handled in the order in
+    /// which they were received, even for the same topic.
+    ///
+    /// Errors from the spawned handlers are passed to the hook set via
+    /// [on_error](#method.on_error), and the routes are re-subscribed
+    /// after a reconnect in the same way as for [run](#method.run).
+    /// Once the loop stops, this waits
+    /// for the handlers that are in flight to complete before returning,
+    /// for at most the [drain timeout](#method.set_drain_timeout) if one
+    /// has been set.
+    ///
+    /// ```no_run
+    /// use mosquitto_rs::router::{MqttRouter, RouterResult};
+    ///
+    /// async fn run(router: MqttRouter) -> RouterResult<()> {
+    ///   router
+    ///     .run_concurrent((), 16, |dispatch| smol::spawn(dispatch).detach(), std::future::pending())
+    ///     .await
+    /// }
+    /// ```
+    pub async fn run_concurrent(
+        self,
+        state: S,
+        max_in_flight: usize,
+        spawner: impl Fn(Pin<Box<dyn Future<Output = ()> + Send>>),
+        shutdown: impl Future<Output = ()> + Send,
+    ) -> RouterResult<()> {
+        let events = self
+            .client
+            .subscriber()
+            .ok_or(RouterError::SubscriberTaken)?;
+        Arc::new(self)
+            .run_events_concurrent(events, state, max_in_flight, spawner, shutdown)
+            .await;
+        Ok(())
+    }
+
+    async fn run_events_concurrent(
+        self: Arc<Self>,
+        events: async_channel::Receiver<Event>,
+        state: S,
+        max_in_flight: usize,
+        spawner: impl Fn(Pin<Box<dyn Future<Output = ()> + Send>>),
+        shutdown: impl Future<Output = ()> + Send,
+    ) {
+        // Each handler in flight holds a slot in this channel
+        let max_in_flight = max_in_flight.max(1);
+        let (acquire, release) = async_channel::bounded(max_in_flight);
+
+        let mut shutdown = std::pin::pin!(shutdown);
+        let mut resubscribe = None;
+        while let Some(event) =
+            alongside(next_event(&events, shutdown.as_mut()), &mut resubscribe).await
+        {
+            match event {
+                Event::Message(message) => {
+                    // Shutdown may be signalled while waiting for a
+                    // handler to complete
+                    let slot = until_shutdown(acquire.send(()), shutdown.as_mut());
+                    if !matches!(alongside(slot, &mut resubscribe).await, Some(Ok(()))) {
+                        break;
+                    }
+                    let router = Arc::clone(&self);
+                    let state = state.clone();
+                    let slot = InFlightSlot(release.clone());
+                    spawner(Box::pin(async move {
+                        let _slot = slot;
+                        if let Err(err) = router.dispatch(message, state).await {
+                            router.report_error(err);
+                        }
+                    }));
+                }
+                event => {
+                    if let Some(pending) = self.handle_other_event(event, state.clone()).await {
+                        resubscribe = Some(pending);
+                    }
+                }
+            }
+        }
+        drop(resubscribe);
+
+        // Wait for the handlers in flight to release their slots
+        let drain = async {
+            for _ in 0..max_in_flight {
+                let _ = acquire.send(()).await;
+            }
+        };
+        match self.drain_timeout {
+            Some(timeout) => {
+                if crate::timer::timeout(timeout, drain).await.is_none() {
+                    log::warn!(
+                        "MqttRouter: handlers are still running {timeout:?} after shutdown; \
+                         no longer waiting for them"
+                    );
+                }
+            }
+            None => drain.await,
+        }
+    }
+
+    /// Returns true if a successful connection is a reconnect after
+    /// which the routes need to be re-subscribed, because the broker
+    /// didn't resume the session that held their subscriptions
+    fn reconnected(&self, session_present: bool) -> bool {
+        self.connected.swap(true, Ordering::SeqCst) && !session_present
+    }
+
+    /// Handle an event other than a message in the run loops.
+    /// Returns the future that re-subscribes the routes after a
+    /// reconnect, for the loop to drive, replacing any earlier one.
+    async fn handle_other_event(&self, event: Event, state: S) -> Option<Resubscribe<'_>> {
+        match event {
+            Event::Connected(status) if status.is_successful() => {
+                if self.reconnected(self.client.session_present()) {
+                    return Some(Box::pin(self.resubscribe_with_retry()));
+                }
+            }
+            event => {
+                if let Err(error) = self.handle_event(event, state).await {
+                    self.report_error(match error {
+                        RouterError::Dispatch(err) => *err,
+                        error => DispatchError {
+                            route: None,
+                            topic: String::new(),
+                            meta: MessageMeta::default(),
+                            error,
+                        },
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Re-subscribe every route after a reconnect, retrying those that
+    /// failed or timed out after each of the [resubscribe_delays], and
+    /// passing the failures to the error hook
+    async fn resubscribe_with_retry(&self) {
+        self.subscribed.lock().unwrap().clear();
+        let mut pending: Vec<&RouteSubscription> = self.subscriptions.iter().collect();
+        let mut delays = resubscribe_delays();
+        loop {
+            let mut failed = vec![];
+            for sub in pending {
+                let subscribe = self.subscribe(&sub.topic, &sub.options);
+                let result = crate::timer::timeout(RESUBSCRIBE_TIMEOUT, subscribe)
+                    .await
+                    .unwrap_or_else(|| Err(crate::Error::Timeout(RESUBSCRIBE_TIMEOUT).into()));
+                if let Err(error) = result {
+                    self.report_error(DispatchError {
+                        route: Some(sub.path.clone()),
+                        topic: sub.topic.clone(),
+                        meta: MessageMeta::default(),
+                        error,
+                    });
+                    failed.push(sub);
+                }
+            }
+            if failed.is_empty() {
+                return;
+            }
+            let Some(delay) = delays.next() else {
+                log::error!(
+                    "MqttRouter: giving up re-subscribing {} route(s) after {RESUBSCRIBE_ATTEMPTS} attempts",
+                    failed.len()
+                );
+                return;
+            };
+            crate::timer::sleep(delay).await;
+            pending = failed;
+        }
+    }
+
+    fn report_error(&self, err: DispatchError) {
+        if let RouterError::Duplicate = err.error {
+            log::debug!("MqttRouter: {err}");
+            return;
+        }
+        match &self.error_hook {
+            Some(hook) => hook(err),
+            None => log::error!("MqttRouter: {err:#}"),
+        }
+    }
+
+    /// Set a function to be called with the errors that occur while
+    /// dispatching messages in [run](#method.run) or
+    /// [run_concurrent](#method.run_concurrent), such as a handler
+    /// returning an error.  The default is to log them.
+    ///
+    /// Failures to re-subscribe a route after a reconnect are also passed
+    /// to the hook; for those, `route` and `topic` are the route and the
+    /// topic pattern that it subscribes to, and `meta` is the default.
+    /// Any other failure to handle an event that isn't a message is
+    /// passed with no route and an empty topic.
+    ///
+    /// ```rust
+    /// use mosquitto_rs::Client;
+    /// use mosquitto_rs::router::MqttRouter;
+    ///
+    /// fn setup_router() -> anyhow::Result<()> {
+    ///   let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+    ///   router.on_error(|err| {
+    ///     let route = err.route.as_deref().unwrap_or("fallback");
+    ///     log::error!("{route} failed for {} (mid {}): {:#}", err.topic, err.meta.mid, err.error);
+    ///   });
+    ///   Ok(())
+    /// }
+    /// ```
+    pub fn on_error(&mut self, hook: impl Fn(DispatchError) + Send + Sync + 'static) {
+        self.error_hook.replace(Box::new(hook));
+    }
+
+    /// Add a middleware layer that wraps every handler, including the
+    /// [fallback](#method.fallback).  `layer` is called with the request
+    /// and the [Next] part of the chain; it can inspect the request, for
+    /// example via [Request::route] and [Request::message], and then
+    /// either call `next.run(request)` to continue to the handler, or
+    /// return without doing so to skip it.
+    ///
+    /// Layers run in the order in which they were added, so the first
+    /// layer is the outermost.
+    ///
+    /// ```rust
+    /// use mosquitto_rs::Client;
+    /// use mosquitto_rs::router::{MqttRouter, Next, Request};
+    /// use std::time::Instant;
+    ///
+    /// fn setup_router() -> anyhow::Result<()> {
+    ///   let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+    ///   router.layer(|request: Request<()>, next: Next<()>| async move {
+    ///     if request.message().payload.len() > 4096 {
+    ///       anyhow::bail!("payload too large");
+    ///     }
+    ///     let route = request.route().unwrap_or("fallback").to_string();
+    ///     let start = Instant::now();
+    ///     let result = next.run(request).await;
+    ///     log::debug!("{route} took {:?}", start.elapsed());
+    ///     result
+    ///   });
+    ///   Ok(())
+    /// }
+    /// ```
+    pub fn layer<F, Fut>(&mut self, layer: F)
+    where
+        F: Fn(Request<S>, Next<S, R>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = MqttHandlerResult<R>> + Send + 'static,
+    {
+        let layer: Arc<LayerFn<S, R>> =
+            Arc::new(move |request, next| -> HandlerFuture<R> { Box::pin(layer(request, next)) });
+        let mut layers = self.layers.to_vec();
+        layers.push(layer);
+        self.layers = layers.into();
+    }
+
+    /// Skip dispatching QoS 1 and QoS 2 messages with the same message
+    /// id, topic and payload as one that was dispatched within `window`.
+    /// The broker may redeliver such messages, for example after a
+    /// reconnect, and this allows handlers to see them only once.
+    /// Message ids are reused once a message has been acknowledged, so
+    /// `window` should be kept short to avoid skipping a new message
+    /// that repeats the payload of an earlier one.  Payloads are compared
+    /// in the same way as for `Message::dup`.
+    /// `dispatch` fails with `RouterError::Duplicate` for the skipped
+    /// messages, which [run](#method.run) doesn't report as errors.
+    pub fn deduplicate(&mut self, window: Duration) {
+        self.dedup.replace(Mutex::new(RecentMessages::new(window)));
+    }
+
+    /// Register a handler for messages whose topic doesn't match any
+    /// route.  The subscriptions made for the routes can be broader than
+    /// the routes themselves, so such messages can be expected; without
+    /// a fallback, `dispatch` fails with `RouterError::MatchError` for them.
+    ///
+    /// The handler may use any of the extractors other than [Params]
+    /// and [Rest], as there is no matching route to populate them.
+    ///
+    /// ```rust
+    /// use mosquitto_rs::Client;
+    /// use mosquitto_rs::router::{MqttRouter, Topic};
+    ///
+    /// async fn unmatched(Topic(topic): Topic) -> anyhow::Result<()> {
+    ///   log::debug!("ignoring message for {topic}");
+    ///   Ok(())
+    /// }
+    ///
+    /// fn setup_router() -> anyhow::Result<()> {
+    ///   let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+    ///   router.fallback(unmatched);
+    ///   Ok(())
+    /// }
+    /// ```
+    pub fn fallback<T, F>(&mut self, handler: F)
+    where
+        F: MakeDispatcher<T, S, R>,
+    {
+        self.fallback.replace(F::make_dispatcher(handler));
+    }
+
+    /// Dispatch an mqtt message to a registered handler.
+    /// A panic in the handler, or in a middleware [layer](#method.layer),
+    /// is caught and returned as `RouterError::HandlerPanicked`, so that
+    /// it doesn't stop [run](#method.run) from handling later messages.
+    /// The panic is still reported by the panic hook, which prints it
+    /// by default.
+    /// If [deduplicate](#method.deduplicate) has been enabled, duplicate
+    /// messages are skipped, failing with `RouterError::Duplicate`.
+    /// Messages that don't match any route are passed to the
+    /// [fallback](#method.fallback) handler, if any.
+    ///
+    /// On success, the value produced by the handler is returned.
+    /// The returned [DispatchError] identifies the route and the message
+    /// that failed alongside the underlying error.
+    ///
+    /// In [RoutingMode::Wildcard], the handlers of all of the matching
+    /// routes are called one after the other, even if one of them fails;
+    /// the first error is returned, and any others are logged.
+    /// If none of them fail, the value produced by the handler of the
+    /// first matching route is returned, and those produced by the
+    /// others are discarded; use [RoutingMode::Paths] if every value
+    /// matters.
+    pub async fn dispatch(&self, message: Message, state: S) -> Result<R, DispatchError> {
+        let topic = message.topic.to_string();
+        let meta = MessageMeta::new(&message);
+        let failed = |route: Option<&Arc<str>>, error| DispatchError {
+            route: route.map(|route| route.to_string()),
+            topic: topic.clone(),
+            meta,
+            error,
+        };
+        if let Some(dedup) = &self.dedup {
+            if dedup.lock().unwrap().is_repeat(&message) {
+                return Err(failed(None, RouterError::Duplicate));
+            }
+        }
+
+        if self.mode == RoutingMode::Wildcard {
+            let matched: Vec<&WildcardRoute<S, R>> = self
+                .wildcard_routes
+                .iter()
+                .filter(|route| route.filter.matches(&topic))
+                .collect();
+            if matched.is_empty() {
+                return self
+                    .dispatch_unmatched(message, state, matchit::MatchError::NotFound)
+                    .await;
+            }
+            let mut result = None;
+            for route in matched {
+                let params = route.params(&topic);
+                let dispatcher = &route.dispatcher;
+                match self
+                    .call(dispatcher, params, message.clone(), state.clone())
+                    .await
+                {
+                    Ok(value) => {
+                        if result.is_none() {
+                            result = Some(Ok(value));
+                        }
+                    }
+                    Err(error) => {
+                        let err = failed(dispatcher.route.as_ref(), error);
+                        match result {
+                            Some(Err(_)) => log::error!("MqttRouter: {err:#}"),
+                            _ => result = Some(Err(err)),
+                        }
+                    }
+                }
+            }
+            return result.expect("at least one route matched");
+        }
+
+        let matched = match self.router.at(&topic) {
+            Ok(matched) => matched,
+            Err(err) => return self.dispatch_unmatched(message, state, err).await,
+        };
+
+        let params = {
+            let mut value_map = serde_json::Map::new();
+
+            for (k, v) in matched.params.iter() {
+                value_map.insert(k.into(), v.into());
+            }
+
+            if value_map.is_empty() {
+                serde_json::Value::Null
+            } else {
+                serde_json::Value::Object(value_map)
+            }
+        };
+
+        self.call(matched.value, params, message, state)
+            .await
+            .map_err(|error| failed(matched.value.route.as_ref(), error))
+    }
+
+    /// Pass a message that didn't match any route to the fallback,
+    /// or fail with `err` if there is none
+    async fn dispatch_unmatched(
+        &self,
+        message: Message,
+        state: S,
+        err: matchit::MatchError,
+    ) -> Result<R, DispatchError> {
+        let failed = DispatchError {
+            route: None,
+            topic: message.topic.to_string(),
+            meta: MessageMeta::new(&message),
+            error: err.into(),
+        };
+        match &self.fallback {
+            Some(fallback) => self
+                .call(fallback, JsonValue::Null, message, state)
+                .await
+                .map_err(|error| DispatchError { error, ..failed }),
+            None => Err(failed),
+        }
+    }
+
+    /// Call `dispatcher` through the middleware layers
+    async fn call(
+        &self,
+        dispatcher: &Dispatcher<S, R>,
+        params: JsonValue,
+        message: Message,
+        state: S,
+    ) -> RouterResult<R> {
+        let request = dispatcher.request(params, message, state, Some(self.client.clone()));
+        let next = Next {
+            layers: Arc::clone(&self.layers),
+            index: 0,
+            handler: Arc::clone(&dispatcher.func),
+        };
+        let route = dispatcher.route.as_deref();
+        let timeout = dispatcher.timeout.or(self.default_timeout);
+        let run = async {
+            let run = catch_unwind(route, next.run(request));
+            let result = match timeout {
+                Some(timeout) => crate::timer::timeout(timeout, run).await.ok_or_else(|| {
+                    RouterError::HandlerTimeout {
+                        route: route.map(|route| route.to_string()),
+                        elapsed: timeout,
+                    }
+                })?,
+                None => run.await,
+            };
+            result?.map_err(handler_error)
+        };
+        if !self.metrics_enabled && self.metrics_sink.is_none() {
+            return run.await;
+        }
+
+        let start = Instant::now();
+        let result = run.await;
+        let elapsed = start.elapsed();
+        if self.metrics_enabled {
+            dispatcher.stats.record(elapsed, result.is_err());
+        }
+        if let Some(sink) = &self.metrics_sink {
+            sink.record(route, elapsed, result.as_ref().err());
+        }
+        result
+    }
+
+    /// Set the timeout for the handlers of the routes that don't specify
+    /// one via [RouteOptions::timeout], and for the [fallback](#method.fallback).
+    /// See [RouteOptions::timeout] for the caveats.
+    pub fn set_default_timeout(&mut self, timeout: Duration) {
+        self.default_timeout.replace(timeout);
+    }
+
+    /// Limit how long [run_concurrent](#method.run_concurrent) waits for
+    /// the handlers that are in flight to complete once it has stopped
+    /// receiving messages.  The default is to wait for them indefinitely;
+    /// use `Duration::ZERO` to return without waiting.
+    /// The handlers that are still running are left to complete on the
+    /// executor to which they were spawned; they are not cancelled.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout.replace(timeout);
+    }
+
+    /// Start recording the number of dispatches, the number of errors
+    /// and a latency histogram for each route, which are returned by
+    /// [metrics](#method.metrics).  Metrics are not recorded by default,
+    /// so that routers that don't need them don't pay for them.
+    pub fn enable_metrics(&mut self) {
+        self.metrics_enabled = true;
+    }
+
+    /// Returns the metrics recorded since [enable_metrics](#method.enable_metrics)
+    /// was called for each of the routes, in the order in which they
+    /// were registered, followed by the fallback handler, if any.
+    ///
+    /// ```rust
+    /// use mosquitto_rs::router::MqttRouter;
+    ///
+    /// fn report(router: &MqttRouter) {
+    ///   for metrics in router.metrics() {
+    ///     println!(
+    ///       "{}: {} dispatches, {} errors, mean latency {:?}",
+    ///       metrics.route.as_deref().unwrap_or("fallback"),
+    ///       metrics.dispatches,
+    ///       metrics.errors,
+    ///       metrics.latency.mean()
+    ///     );
+    ///   }
+    /// }
+    /// ```
+    pub fn metrics(&self) -> Vec<RouteMetrics> {
+        let mut metrics: Vec<_> = self
+            .subscriptions
+            .iter()
+            .map(|sub| sub.stats.metrics(Some(sub.path.clone())))
+            .collect();
+        if let Some(fallback) = &self.fallback {
+            metrics.push(fallback.stats.metrics(None));
+        }
+        metrics
+    }
+
+    /// Pass the measurements for every dispatch to `sink`, in addition to
+    /// recording them for [metrics](#method.metrics) if that is enabled
+    pub fn set_metrics_sink(&mut self, sink: impl MetricsSink + 'static) {
+        self.metrics_sink.replace(Box::new(sink));
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+}
+
+/// Build a concrete topic from `route`, written in the same syntax as
+/// for [MqttRouter::route], by substituting the values of the fields of
+/// `params` for the route parameters.  This is the reverse of routing,
+/// allowing the topic shape to be defined once and used both to handle
+/// messages and to publish them.
+///
+/// `params` may be anything that serializes to a map, such as a struct
+/// or a `HashMap`, whose values are strings, numbers or booleans;
+/// fields that aren't parameters of the route are ignored.
+/// Fails with `RouterError::FillRoute` if a parameter is missing, or if
+/// its value is empty or contains `+` or `#`, or `/` other than in a
+/// `{*catch-all}` parameter.
+///
+/// ```rust
+/// use mosquitto_rs::router::{fill_route, RouterResult};
+/// use std::collections::HashMap;
+///
+/// fn command_topic(id: &str) -> RouterResult<String> {
+///   fill_route("devices/{id}/command", &HashMap::from([("id", id)]))
+/// }
+/// ```
+pub fn fill_route(route: &str, params: &impl serde::Serialize) -> RouterResult<String> {
+    let invalid = |reason: String| RouterError::FillRoute {
+        route: route.to_string(),
+        reason,
+    };
+    let params = match serde_json::to_value(params)? {
+        JsonValue::Object(params) => params,
+        JsonValue::Null => serde_json::Map::new(),
+        _ => {
+            return Err(invalid(
+                "the parameters must serialize to a map".to_string(),
+            ))
+        }
+    };
+
+    let path = parse_route(route)?.path;
+    let mut topic = String::new();
+    let mut chars = path.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '}' if chars.peek() == Some(&c) => {
+                chars.next();
+                topic.push(c);
+            }
+            '{' => {
+                let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                let (name, catch_all) = match name.strip_prefix('*') {
+                    Some(name) => (name, true),
+                    None => (name.as_str(), false),
+                };
+                let value = match params.get(name) {
+                    Some(JsonValue::String(s)) => s.clone(),
+                    Some(v @ (JsonValue::Number(_) | JsonValue::Bool(_))) => v.to_string(),
+                    Some(_) => {
+                        return Err(invalid(format!(
+                            "the value of parameter {name} must be a string, number or boolean"
+                        )))
+                    }
+                    None => return Err(invalid(format!("missing parameter {name}"))),
+                };
+                if value.is_empty() {
+                    return Err(invalid(format!("parameter {name} is empty")));
+                }
+                let forbidden: &[char] = if catch_all {
+                    &['+', '#']
+                } else {
+                    &['/', '+', '#']
+                };
+                if value.contains(forbidden) {
+                    return Err(invalid(format!(
+                        "parameter {name} must not contain {}: {value:?}",
+                        forbidden
+                            .iter()
+                            .map(char::to_string)
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    )));
+                }
+                topic.push_str(&value);
+            }
+            c => topic.push(c),
+        }
+    }
+
+    // The route itself may contain escaped wildcards, which are
+    // not valid in a topic that is published to
+    if topic.contains(['+', '#']) {
+        return Err(invalid(format!("{topic} contains a wildcard")));
+    }
+    Ok(topic)
+}
+
+/// A helper to deserialize from a string into any type that
+/// implements FromStr
+pub fn parse_deser<'de, D, T: FromStr>(d: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    <T as FromStr>::Err: std::fmt::Display,
+{
+    use serde::de::Error;
+    let s = String::deserialize(d)?;
+    s.parse::<T>()
+        .map_err(|err| D::Error::custom(format!("parsing {s}: {err:#}")))
+}
+
+/// A route translated for use with the router and the broker
+#[derive(Debug, PartialEq, Eq)]
+struct ParsedRoute {
+    /// The route in the syntax used by matchit
+    path: String,
+    /// The corresponding mqtt topic pattern
+    topic: String,
+    /// The name of the `{*catch-all}` parameter, if any
+    catch_all: Option<String>,
+}
+
+/// Convert a Router route into the matchit route syntax and the
+/// corresponding mqtt topic.
+/// `{foo}` is replaced by `+` in the topic, a final `{*foo}` by `#`,
+/// and `{{` and `}}` are escaped literal braces.
+/// A backslash escapes the following character, so `\:` is a literal
+/// colon.  A level containing an escaped `\+` or `\#`, or a parameter
+/// alongside other characters, is subscribed to as `+`.  The deprecated `:foo` syntax is translated to `{foo}`.
+fn parse_route(route: &str) -> RouterResult<ParsedRoute> {
+    let invalid = |reason: &str| RouterError::InvalidRoute {
+        route: route.to_string(),
+        reason: reason.to_string(),
+    };
+    let mut path = String::new();
+    let mut topic = String::new();
+    let mut legacy = false;
+    let mut catch_all = None;
+    let mut level_start = 0;
+    let mut widen_level = false;
+    let mut chars = route.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let c = chars
+                    .next()
+                    .ok_or_else(|| invalid("unterminated \\ escape"))?;
+                match c {
+                    '{' => path.push_str("{{"),
+                    '}' => path.push_str("}}"),
+                    '+' | '#' => {
+                        widen_level = true;
+                        path.push(c);
+                    }
+                    c => path.push(c),
+                }
+                topic.push(c);
+            }
+            '/' => {
+                end_level(&mut topic, level_start, &mut widen_level);
+                path.push('/');
+                topic.push('/');
+                level_start = topic.len();
+            }
+            '+' | '#' => {
+                return Err(invalid(
+                    "+ and # are mqtt wildcards; use {parameter} or {*catch-all} \
+                     to match topic levels, or escape them with \\",
+                ))
+            }
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                path.push_str("{{");
+                topic.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                path.push_str("}}");
+                topic.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some('{' | '/') | None => return Err(invalid("unterminated {parameter}")),
+                        Some(c) => name.push(c),
+                    }
+                }
+                if name.is_empty() {
+                    return Err(invalid("empty {parameter} name"));
+                }
+                if let Some(rest) = name.strip_prefix('*') {
+                    if rest.is_empty() {
+                        return Err(invalid("empty {*catch-all} name"));
+                    }
+                    if !(path.is_empty() || path.ends_with('/')) || chars.peek().is_some() {
+                        return Err(invalid("{*catch-all} must be the entire final level"));
+                    }
+                    catch_all.replace(rest.to_string());
+                    path.push('{');
+                    path.push_str(&name);
+                    path.push('}');
+                    topic.push('#');
+                    continue;
+                }
+                path.push('{');
+                path.push_str(&name);
+                path.push('}');
+                topic.push('+');
+                widen_level = true;
+            }
+            '}' => return Err(invalid("unmatched }; use }} for a literal brace")),
+            ':' => {
+                legacy = true;
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|&c| c != '/') {
+                    name.push(c);
+                }
+                if name.is_empty() {
+                    return Err(invalid(
+                        "empty :parameter name; use \\: for a literal colon",
+                    ));
+                }
+                path.push('{');
+                path.push_str(&name);
+                path.push('}');
+                topic.push('+');
+                widen_level = true;
+            }
+            c => {
+                path.push(c);
+                topic.push(c);
+            }
+        }
+    }
+    end_level(&mut topic, level_start, &mut widen_level);
+    if legacy {
+        log::warn!("route {route}: the :parameter syntax is deprecated, use {{parameter}} instead");
+    }
+    Ok(ParsedRoute {
+        path,
+        topic,
+        catch_all,
+    })
+}
+
+/// Convert a route of a router in `RoutingMode::Wildcard` into the mqtt
+/// subscription filter, along with the parameter name of each level of
+/// the filter that has one
+fn parse_wildcard_route(route: &str) -> RouterResult<(ParsedRoute, Vec<Option<String>>)> {
+    let invalid = |reason: String| RouterError::InvalidRoute {
+        route: route.to_string(),
+        reason,
+    };
+    let mut levels = vec![];
+    let mut names = vec![];
+    let mut catch_all = None;
+    for level in route.split('/') {
+        let name = match level.strip_prefix('{').and_then(|l| l.strip_suffix('}')) {
+            Some(name) => name,
+            None => {
+                if level.contains(['{', '}']) {
+                    return Err(invalid(
+                        "{parameter} must be the entire level of a filter".to_string(),
+                    ));
+                }
+                levels.push(level);
+                names.push(None);
+                continue;
+            }
+        };
+        let (name, level) = match name.strip_prefix('*') {
+            Some(name) => {
+                catch_all.replace(name.to_string());
+                (name, "#")
+            }
+            None => (name, "+"),
+        };
+        if name.is_empty() || name.contains(['{', '}']) {
+            return Err(invalid(format!("invalid parameter name {name:?}")));
+        }
+        levels.push(level);
+        names.push(Some(name.to_string()));
+    }
+    let topic = levels.join("/");
+    TopicFilter::new(&topic).map_err(|err| invalid(err.to_string()))?;
+    Ok((
+        ParsedRoute {
+            path: route.to_string(),
+            topic,
+            catch_all,
+        },
+        names,
+    ))
+}
+
+/// A topic level that contains a literal `+` or `#`, or a parameter
+/// alongside other characters, can't be expressed in an mqtt
+/// subscription, so replace the level that started at `level_start`
+/// with `+` and leave it to the router to match it
+fn end_level(topic: &mut String, level_start: usize, widen_level: &mut bool) {
+    if std::mem::take(widen_level) {
+        topic.truncate(level_start);
+        topic.push('+');
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Build a request for `message`, as though it matched a route
+    /// without parameters, for testing extractors directly
+    fn test_request<S>(message: Message, state: S) -> Request<S> {
+        Request {
+            params: JsonValue::Null,
+            message,
+            state,
+            rest: None,
+            client: None,
+            route: None,
+        }
+    }
+
+    #[test]
+    fn test_route_to_topic() {
+        for (route, expected_path, expected_topic) in [
+            ("hello/:there", "hello/{there}", "hello/+"),
+            ("a/:b/foo", "a/{b}/foo", "a/+/foo"),
+            ("hello", "hello", "hello"),
+            ("who\\:", "who:", "who:"),
+            (
+                "device\\:1234/state",
+                "device:1234/state",
+                "device:1234/state",
+            ),
+            ("a\\\\b", "a\\b", "a\\b"),
+            ("a/\\{b\\}", "a/{{b}}", "a/{b}"),
+            ("a/{b}/c", "a/{b}/c", "a/+/c"),
+            ("{x}", "{x}", "+"),
+            ("a/{{literal}}", "a/{{literal}}", "a/{literal}"),
+            ("a/:b/{c}", "a/{b}/{c}", "a/+/+"),
+            ("a/\\+", "a/+", "a/+"),
+            ("a/\\#", "a/#", "a/+"),
+            ("a/b\\+c/d", "a/b+c/d", "a/+/d"),
+            ("\\#b/c", "#b/c", "+/c"),
+            ("a/x\\+\\#y\\:z", "a/x+#y:z", "a/+"),
+            ("a\\+/:b", "a+/{b}", "+/+"),
+            ("device\\:{id}/state", "device:{id}/state", "+/state"),
+            ("a/{b}.json", "a/{b}.json", "a/+"),
+        ] {
+            let parsed = parse_route(route).unwrap();
+            assert_eq!(
+                parsed,
+                ParsedRoute {
+                    path: expected_path.to_string(),
+                    topic: expected_topic.to_string(),
+                    catch_all: None,
+                },
+                "route={route}"
+            );
+        }
+
+        for (route, expected_topic) in [("a/{*rest}", "a/#"), ("{*rest}", "#")] {
+            assert_eq!(
+                parse_route(route).unwrap(),
+                ParsedRoute {
+                    path: route.to_string(),
+                    topic: expected_topic.to_string(),
+                    catch_all: Some("rest".to_string()),
+                },
+                "route={route}"
+            );
+        }
+
+        for route in [
+            "a/{b",
+            "a/b}",
+            "a/{}/c",
+            "a/{b/c}",
+            "a/{*}",
+            "a/{*rest}/c",
+            "a/b{*rest}",
+            "who:",
+            "a/:/b",
+            "a\\",
+            "a/+/b",
+            "a/#",
+        ] {
+            assert!(
+                matches!(parse_route(route), Err(RouterError::InvalidRoute { .. })),
+                "route={route}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_fill_route() -> RouterResult<()> {
+        #[derive(serde::Serialize)]
+        struct Device<'a> {
+            id: &'a str,
+            unit: u32,
+            enabled: bool,
+            rest: &'a str,
+        }
+        let device = Device {
+            id: "d1",
+            unit: 7,
+            enabled: true,
+            rest: "a/b",
+        };
+        for (route, expected) in [
+            ("status", "status"),
+            ("devices/{id}/command", "devices/d1/command"),
+            ("devices/:id/{unit}", "devices/d1/7"),
+            ("flags/{enabled}", "flags/true"),
+            ("files/{id}/{*rest}", "files/d1/a/b"),
+            ("a/{{literal}}/{id}", "a/{literal}/d1"),
+            ("device\\:{id}/state", "device:d1/state"),
+        ] {
+            assert_eq!(fill_route(route, &device)?, expected, "{route}");
+        }
+        assert_eq!(fill_route("status", &())?, "status");
+
+        let map = |id: &str| HashMap::from([("id".to_string(), id.to_string())]);
+        for (route, params) in [
+            ("devices/{id}/{missing}", map("d1")),
+            ("devices/{id}", map("")),
+            ("devices/{id}", map("a/b")),
+            ("devices/{id}", map("a+")),
+            ("files/{*id}", map("a/#")),
+            ("legacy/a\\+b/{id}", map("d1")),
+        ] {
+            assert!(
+                matches!(
+                    fill_route(route, &params),
+                    Err(RouterError::FillRoute { .. })
+                ),
+                "{route} {params:?}"
+            );
+        }
+        assert_eq!(fill_route("files/{*id}", &map("a/b"))?, "files/a/b");
+        assert!(matches!(
+            fill_route("devices/{id}", &vec!["d1"]),
+            Err(RouterError::FillRoute { .. })
+        ));
+        assert!(matches!(
+            fill_route("devices/{id", &map("d1")),
+            Err(RouterError::InvalidRoute { .. })
+        ));
+
+        async fn handler(_: Topic) -> anyhow::Result<()> {
+            Ok(())
+        }
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        let dispatcher = MakeDispatcher::make_dispatcher(handler);
+        router.insert_route(
+            parse_route("devices/{id}/command")?,
+            dispatcher,
+            RouteOptions::default(),
+        )?;
+        assert_eq!(
+            router.topic_for("devices/{id}/command", &map("d1"))?,
+            "devices/d1/command"
+        );
+        assert!(matches!(
+            router.topic_for("device/{id}/command", &map("d1")),
+            Err(RouterError::RouteNotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn routing() -> RouterResult<()> {
+        let mut router = Router::new();
+        router.insert(parse_route("pv2mqtt/home")?.path, "Welcome!")?;
+        router.insert(parse_route("pv2mqtt/users/{name}/{id}")?.path, "A User")?;
+        router.insert(parse_route("pv2mqtt/groups/:group")?.path, "A Group")?;
+        router.insert(parse_route("pv2mqtt/{{literal}}")?.path, "Literal")?;
+
+        let matched = router.at("pv2mqtt/users/foo/978")?;
+        assert_eq!(matched.params.get("id"), Some("978"));
+        assert_eq!(*matched.value, "A User");
+
+        let matched = router.at("pv2mqtt/groups/admin")?;
+        assert_eq!(matched.params.get("group"), Some("admin"));
+        assert_eq!(*matched.value, "A Group");
+
+        let matched = router.at("pv2mqtt/{literal}")?;
+        assert_eq!(*matched.value, "Literal");
+
+        router.insert(parse_route("legacy/a\\+b/\\#")?.path, "Escaped")?;
+        router.insert(parse_route("device\\:{id}/state")?.path, "Device")?;
+        let matched = router.at("legacy/a+b/#")?;
+        assert_eq!(*matched.value, "Escaped");
+        assert!(router.at("legacy/axb/#").is_err());
+        assert!(router.at("legacy/a+b/c").is_err());
+        let matched = router.at("device:1234/state")?;
+        assert_eq!(matched.params.get("id"), Some("1234"));
+        assert_eq!(*matched.value, "Device");
+
+        Ok(())
+    }
+
+    #[test]
+    fn catch_all() -> RouterResult<()> {
+        let (tx, rx) = async_channel::unbounded();
+        let handler = move |Rest(rest): Rest, Params(params): Params<JsonValue>| {
+            let tx = tx.clone();
+            async move {
+                tx.send((rest, params)).await?;
+                Ok(())
+            }
+        };
+
+        let route = parse_route("sensors/{*rest}")?;
+        let mut dispatcher = MakeDispatcher::make_dispatcher(handler);
+        dispatcher.catch_all = route.catch_all;
+        let mut router = Router::new();
+        router.insert(route.path, dispatcher)?;
+
+        let matched = router.at("sensors/kitchen/temperature")?;
+        let params = serde_json::json!({"rest": matched.params.get("rest")});
+        smol::block_on(matched.value.call(params, Message::default(), ()))?;
+        let (rest, params) = rx.try_recv().unwrap();
+        assert_eq!(rest, "kitchen/temperature");
+        assert_eq!(params["rest"], "kitchen/temperature");
+
+        let request = test_request(Message::default(), ());
+        assert!(matches!(
+            Rest::from_request(&request),
+            Err(RouterError::NoCatchAll)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn unroute() -> RouterResult<()> {
+        async fn handler(_: Topic) -> anyhow::Result<()> {
+            Ok(())
+        }
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        let mut ids = vec![];
+        for path in ["devices/{id}", "devices/\\+", "status", "events/{*rest}"] {
+            let dispatcher = MakeDispatcher::make_dispatcher(handler);
+            let options = RouteOptions {
+                qos: QoS::AtLeastOnce,
+                ..Default::default()
+            };
+            ids.push(router.insert_route(parse_route(path)?, dispatcher, options)?);
+        }
+
+        let info = |id, path: &str, topic: &str| RouteInfo {
+            id,
+            path: path.to_string(),
+            topic: topic.to_string(),
+            qos: QoS::AtLeastOnce,
+        };
+        assert_eq!(
+            router.routes(),
+            vec![
+                info(ids[0], "devices/{id}", "devices/+"),
+                info(ids[1], "devices/+", "devices/+"),
+                info(ids[2], "status", "status"),
+                info(ids[3], "events/{*rest}", "events/#"),
+            ]
+        );
+        let debug = format!("{router:?}");
+        for info in router.routes() {
+            assert!(debug.contains(&format!("{info:?}")), "{debug}");
+        }
+
+        // Routes can be removed by id, in any order
+        assert_eq!(router.remove_route(ids[3])?, Some("events/#".to_string()));
+        assert!(router.router.at("events/a/b").is_err());
+        assert!(matches!(
+            router.remove_route(ids[3]),
+            Err(RouterError::RouteNotFound(_))
+        ));
+        assert_eq!(
+            router.routes(),
+            vec![
+                info(ids[0], "devices/{id}", "devices/+"),
+                info(ids[1], "devices/+", "devices/+"),
+                info(ids[2], "status", "status"),
+            ]
+        );
+
+        // Both remaining routes subscribe to devices/+
+        assert_eq!(router.remove_route("devices/{id}")?, None);
+        assert!(router.router.at("devices/abc").is_err());
+        assert!(router.router.at("devices/+").is_ok());
+        assert_eq!(
+            router.remove_route("devices/\\+")?,
+            Some("devices/+".to_string())
+        );
+        assert_eq!(router.remove_route("status")?, Some("status".to_string()));
+
+        assert!(matches!(
+            router.remove_route("status"),
+            Err(RouterError::RouteNotFound(_))
+        ));
+        assert!(router.routes().is_empty());
+        assert!(format!("{router:?}").starts_with("MqttRouter { routes: [],"));
+        Ok(())
+    }
+
+    #[test]
+    fn wildcard_routes() -> RouterResult<()> {
+        for (route, expected_topic, expected_names) in [
+            ("sensors/+/temp/#", "sensors/+/temp/#", vec![None; 4]),
+            (
+                "sensors/{id}/temp",
+                "sensors/+/temp",
+                vec![None, Some("id"), None],
+            ),
+            ("a/{*rest}", "a/#", vec![None, Some("rest")]),
+        ] {
+            let (parsed, names) = parse_wildcard_route(route)?;
+            assert_eq!(parsed.topic, expected_topic, "{route}");
+            assert_eq!(
+                names,
+                expected_names
+                    .into_iter()
+                    .map(|name| name.map(str::to_string))
+                    .collect::<Vec<_>>(),
+                "{route}"
+            );
+        }
+        for route in ["a/{id}x", "a/#/b", "a/{*rest}/b", "a/{}", "a/b+"] {
+            assert!(
+                matches!(
+                    parse_wildcard_route(route),
+                    Err(RouterError::InvalidRoute { .. })
+                ),
+                "{route}"
+            );
+        }
+
+        let (tx, rx) = async_channel::unbounded();
+        let handler = |label: &'static str| {
+            let tx = tx.clone();
+            move |ParamsMap(params): ParamsMap| {
+                let tx = tx.clone();
+                async move {
+                    tx.send((label, params.into_iter().collect(), None)).await?;
+                    Ok(())
+                }
+            }
+        };
+        let logs = {
+            let tx = tx.clone();
+            move |ParamsMap(params): ParamsMap, Rest(rest): Rest| {
+                let tx = tx.clone();
+                async move {
+                    tx.send(("logs", params.into_iter().collect(), Some(rest)))
+                        .await?;
+                    Ok(())
+                }
+            }
+        };
+        let mut router = <MqttRouter>::new_wildcard(Client::with_auto_id()?);
+        assert_eq!(router.routing_mode(), RoutingMode::Wildcard);
+        for (path, label) in [("sensors/{id}/temp/#", "temp"), ("sensors/#", "all")] {
+            router.insert_route(
+                router.parse(path)?,
+                MakeDispatcher::make_dispatcher(handler(label)),
+                RouteOptions::default(),
+            )?;
+        }
+        router.insert_route(
+            router.parse("logs/{*rest}")?,
+            MakeDispatcher::make_dispatcher(logs),
+            RouteOptions::default(),
+        )?;
+        let err = router
+            .insert_route(
+                router.parse("sensors/#")?,
+                MakeDispatcher::make_dispatcher(handler("again")),
+                RouteOptions::default(),
+            )
+            .unwrap_err();
+        assert!(matches!(err, RouterError::RouteConflict { .. }));
+
+        let dispatch = |router: &MqttRouter, topic: &str| {
+            let msg = Message {
+                topic: topic.into(),
+                ..Default::default()
+            };
+            smol::block_on(router.dispatch(msg, ())).map_err(RouterError::from)
+        };
+        let id = |id: &str| vec![("id".to_string(), id.to_string())];
+
+        // Every matching route is dispatched to, in order
+        dispatch(&router, "sensors/kitchen/temp/celsius")?;
+        assert_eq!(rx.try_recv().unwrap(), ("temp", id("kitchen"), None));
+        assert_eq!(rx.try_recv().unwrap(), ("all", vec![], None));
+        assert!(rx.try_recv().is_err());
+
+        dispatch(&router, "logs")?;
+        let rest = |rest: &str| vec![("rest".to_string(), rest.to_string())];
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            ("logs", rest(""), Some("".to_string()))
+        );
+        dispatch(&router, "logs/a/b")?;
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            ("logs", rest("a/b"), Some("a/b".to_string()))
+        );
+
+        assert!(matches!(
+            dispatch(&router, "other"),
+            Err(RouterError::Dispatch(err))
+                if matches!(err.error, RouterError::MatchError(_))
+        ));
+
+        // Routes are removed by their filter
+        assert_eq!(
+            router.remove_route("sensors/#")?,
+            Some("sensors/#".to_string())
+        );
+        dispatch(&router, "sensors/kitchen/temp")?;
+        assert_eq!(rx.try_recv().unwrap(), ("temp", id("kitchen"), None));
+        assert!(rx.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn resubscribe_after_reconnect() -> RouterResult<()> {
+        assert_eq!(
+            resubscribe_delays().collect::<Vec<_>>(),
+            [500, 1000, 2000, 4000, 8000].map(Duration::from_millis)
+        );
+
+        async fn handler(_: Topic) -> anyhow::Result<()> {
+            Ok(())
+        }
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        router.insert_route(
+            parse_route("a/{b}")?,
+            MakeDispatcher::make_dispatcher(handler),
+            RouteOptions::default(),
+        )?;
+        let connected = || Event::Connected(crate::ConnectionStatus(0));
+        smol::block_on(async {
+            // The first connection doesn't re-subscribe
+            assert!(router.handle_other_event(connected(), ()).await.is_none());
+            // A reconnect re-subscribes, while the messages continue to
+            // be handled
+            let mut resubscribe = router.handle_other_event(connected(), ()).await;
+            assert!(resubscribe.is_some());
+            assert_eq!(alongside(std::future::ready(1), &mut resubscribe).await, 1);
+            assert!(resubscribe.is_some());
+        });
+        // A resumed session still has the subscriptions
+        assert!(!router.reconnected(true));
+        assert!(router.reconnected(false));
+        Ok(())
+    }
+
+    #[test]
+    fn overlapping_routes() -> RouterResult<()> {
+        async fn handler(_: Topic) -> anyhow::Result<()> {
+            Ok(())
+        }
+        let mut router = <MqttRouter>::new_wildcard(Client::with_auto_id()?);
+        let options = |qos| RouteOptions {
+            qos,
+            ..Default::default()
+        };
+        let temp = options(QoS::AtLeastOnce);
+        assert_eq!(
+            router.covering_subscription("sensors/+/temperature/#", &temp),
+            ("sensors/+/temperature/#", &temp)
+        );
+        for (path, qos) in [
+            ("sensors/{sensor}/temperature/#", QoS::AtLeastOnce),
+            ("sensors/#", QoS::AtLeastOnce),
+            ("#", QoS::AtMostOnce),
+        ] {
+            let dispatcher = MakeDispatcher::make_dispatcher(handler);
+            router.insert_route(router.parse(path)?, dispatcher, options(qos))?;
+        }
+
+        // Both routes receive a message to sensors/a/temperature through
+        // the single subscription to sensors/#, rather than each being
+        // dispatched the copy for the other's subscription too
+        let (topic, covering) = router.covering_subscription("sensors/+/temperature/#", &temp);
+        assert_eq!((topic, covering.qos), ("sensors/#", QoS::AtLeastOnce));
+        // A lower QoS, or different options, need their own subscription
+        let exactly_once = options(QoS::ExactlyOnce);
+        assert_eq!(
+            router.covering_subscription("sensors/x", &exactly_once).0,
+            "sensors/x"
+        );
+        let no_local = RouteOptions {
+            no_local: true,
+            ..options(QoS::AtMostOnce)
+        };
+        assert_eq!(
+            router.covering_subscription("sensors/x", &no_local).0,
+            "sensors/x"
+        );
+        assert_eq!(
+            router
+                .covering_subscription("other", &options(QoS::AtMostOnce))
+                .0,
+            "#"
+        );
+        let shared = options(QoS::AtMostOnce).shared_group("workers");
+        assert_eq!(
+            router
+                .covering_subscription("$share/workers/other", &shared)
+                .0,
+            "$share/workers/other"
+        );
+
+        // Removing the broader route leaves the narrower one to subscribe
+        router.remove_route("sensors/#")?;
+        assert_eq!(
+            router
+                .covering_subscription("sensors/+/temperature/#", &temp)
+                .0,
+            "sensors/+/temperature/#"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn shared_subscriptions() -> RouterResult<()> {
+        async fn handler(_: Topic) -> anyhow::Result<()> {
+            Ok(())
+        }
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        let options = |qos| RouteOptions {
+            qos,
+            ..Default::default()
+        };
+        let mut ids = vec![];
+        for path in ["temp-{id}", "hum-{id}"] {
+            let dispatcher = MakeDispatcher::make_dispatcher(handler);
+            let options = options(QoS::AtLeastOnce);
+            ids.push(router.insert_route(parse_route(path)?, dispatcher, options)?);
+        }
+        assert!(router
+            .subscribed_topic("+", &options(QoS::AtMostOnce))
+            .is_none());
+
+        // Record the subscription as if the broker had accepted it
+        router.subscribed.lock().unwrap().insert(
+            "+".to_string(),
+            SubscribedTopic {
+                qos: QoS::AtLeastOnce,
+                options: SubscriptionOptions::default(),
+                granted: GrantedQoS::Granted(QoS::AtLeastOnce),
+            },
+        );
+        for qos in [QoS::AtMostOnce, QoS::AtLeastOnce] {
+            assert_eq!(
+                router.subscribed_topic("+", &options(qos)),
+                Some(GrantedQoS::Granted(QoS::AtLeastOnce))
+            );
+        }
+        // A higher QoS or different options need a new subscription
+        assert!(router
+            .subscribed_topic("+", &options(QoS::ExactlyOnce))
+            .is_none());
+        let no_local = RouteOptions {
+            qos: QoS::AtLeastOnce,
+            no_local: true,
+            ..Default::default()
+        };
+        assert!(router.subscribed_topic("+", &no_local).is_none());
+
+        // The subscription is forgotten along with the last route using it
+        assert_eq!(router.remove_route(ids[0])?, None);
+        assert!(router
+            .subscribed_topic("+", &options(QoS::AtLeastOnce))
+            .is_some());
+        assert_eq!(router.remove_route(ids[1])?, Some("+".to_string()));
+        assert!(router
+            .subscribed_topic("+", &options(QoS::AtLeastOnce))
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn params_map() -> RouterResult<()> {
+        let (tx, rx) = async_channel::unbounded();
+        let handler = move |ParamsMap(params): ParamsMap| {
+            let tx = tx.clone();
+            async move {
+                tx.send(params).await?;
+                Ok(())
+            }
+        };
+
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        for path in [
+            "status",
+            "devices/{id}",
+            "devices/{id}/{field}",
+            "logs/{source}/{*rest}",
+        ] {
+            let dispatcher = MakeDispatcher::make_dispatcher(handler.clone());
+            router.insert_route(parse_route(path)?, dispatcher, RouteOptions::default())?;
+        }
+
+        for (topic, expected) in [
+            ("status", vec![]),
+            ("devices/d1", vec![("id", "d1")]),
+            ("devices/d1/temp", vec![("id", "d1"), ("field", "temp")]),
+            (
+                "logs/kernel/2024/01",
+                vec![("source", "kernel"), ("rest", "2024/01")],
+            ),
+        ] {
+            let msg = Message {
+                topic: topic.into(),
+                ..Default::default()
+            };
+            smol::block_on(router.dispatch(msg, ()))?;
+            let expected: HashMap<String, String> = expected
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            assert_eq!(rx.try_recv().unwrap(), expected, "{topic}");
+        }
+
+        let mut request = Request {
+            params: serde_json::json!({"id": "d1"}),
+            ..test_request(Message::default(), ())
+        };
+        assert_eq!(request.param("id"), Some("d1"));
+        assert_eq!(request.param("field"), None);
+        request.params = JsonValue::Null;
+        assert_eq!(request.param("id"), None);
+        assert!(ParamsMap::from_request(&request)?.0.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn async_extractors() -> RouterResult<()> {
+        type Log = Arc<Mutex<Vec<&'static str>>>;
+
+        struct Slow;
+        impl FromRequestAsync<Log> for Slow {
+            async fn from_request_async(request: &Request<Log>) -> RouterResult<Self> {
+                crate::timer::sleep(Duration::from_millis(1)).await;
+                request.state().lock().unwrap().push("slow");
+                Ok(Self)
+            }
+        }
+
+        struct Fails;
+        impl FromRequestAsync<Log> for Fails {
+            async fn from_request_async(request: &Request<Log>) -> RouterResult<Self> {
+                request.state().lock().unwrap().push("fails");
+                Err(RouterError::NoClient)
+            }
+        }
+
+        struct Sync;
+        impl FromRequest<Log> for Sync {
+            fn from_request(request: &Request<Log>) -> RouterResult<Self> {
+                request.state().lock().unwrap().push("sync");
+                Ok(Self)
+            }
+        }
+
+        async fn ok(_: Slow, _: Sync, State(log): State<Log>) -> anyhow::Result<()> {
+            log.lock().unwrap().push("handler");
+            Ok(())
+        }
+        async fn fail(_: Slow, _: Fails, State(log): State<Log>) -> anyhow::Result<()> {
+            log.lock().unwrap().push("handler");
+            Ok(())
+        }
+
+        let mut router = MqttRouter::<Log>::new(Client::with_auto_id()?);
+        for (path, dispatcher) in [
+            ("ok", MakeDispatcher::make_dispatcher(ok)),
+            ("fail", MakeDispatcher::make_dispatcher(fail)),
+        ] {
+            router.insert_route(parse_route(path)?, dispatcher, RouteOptions::default())?;
+        }
+        let msg = |topic: &str| Message {
+            topic: topic.into(),
+            ..Default::default()
+        };
+
+        // The arguments are extracted from left to right
+        let log = Log::default();
+        smol::block_on(router.dispatch(msg("ok"), Arc::clone(&log)))?;
+        assert_eq!(*log.lock().unwrap(), vec!["slow", "sync", "handler"]);
+
+        // and extraction stops at the first failure
+        let log = Log::default();
+        assert!(matches!(
+            smol::block_on(router.dispatch(msg("fail"), Arc::clone(&log))),
+            Err(DispatchError {
+                error: RouterError::NoClient,
+                ..
+            })
+        ));
+        assert_eq!(*log.lock().unwrap(), vec!["slow", "fails"]);
+        Ok(())
+    }
+
+    #[test]
+    fn nest() -> RouterResult<()> {
+        let (tx, rx) = async_channel::unbounded();
+        let handler = move |Topic(topic): Topic, ParamsMap(params): ParamsMap| {
+            let tx = tx.clone();
+            async move {
+                let mut params: Vec<_> = params.into_iter().collect();
+                params.sort();
+                tx.send((topic, params)).await?;
+                Ok(())
+            }
+        };
+        let mut fragment = RouterFragment::new();
+        fragment
+            .route("{id}/set", handler.clone())
+            .route("", handler.clone());
+
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        let nested = router.pending_routes("home/{room}/lights/", fragment)?;
+        router.register_pending(Some("home/{room}/lights/"), nested)?;
+        assert_eq!(
+            router
+                .routes()
+                .into_iter()
+                .map(|info| (info.path, info.topic))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "home/{room}/lights/{id}/set".to_string(),
+                    "home/+/lights/+/set".to_string()
+                ),
+                (
+                    "home/{room}/lights".to_string(),
+                    "home/+/lights".to_string()
+                ),
+            ]
+        );
+
+        let msg = Message {
+            topic: "home/kitchen/lights/l1/set".into(),
+            ..Default::default()
+        };
+        smol::block_on(router.dispatch(msg, ()))?;
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            (
+                "home/kitchen/lights/l1/set".to_string(),
+                vec![
+                    ("id".to_string(), "l1".to_string()),
+                    ("room".to_string(), "kitchen".to_string())
+                ]
+            )
+        );
+
+        // A conflicting route names the prefix and the nested path
+        // and leaves none of the routes of the fragment registered
+        let mut fragment = RouterFragment::new();
+        fragment
+            .route("{id}/get", handler.clone())
+            .route("{other}/set", handler.clone());
+        let nested = router.pending_routes("home/{room}/lights", fragment)?;
+        let err = router
+            .register_pending(Some("home/{room}/lights"), nested)
+            .unwrap_err();
+        assert_eq!(router.routes().len(), 2);
+        assert!(err.is_misconfiguration());
+        assert!(matches!(
+            &err,
+            RouterError::NestInsertError { prefix, path, .. }
+                if prefix == "home/{room}/lights" && path == "{other}/set"
+        ));
+
+        // Invalid routes are rejected before anything is registered
+        let mut fragment = RouterFragment::new();
+        fragment.route("{*rest}/x", handler);
+        assert!(matches!(
+            router.pending_routes("home", fragment),
+            Err(RouterError::InvalidRoute { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn merge() -> RouterResult<()> {
+        let (tx, rx) = async_channel::unbounded();
+        let handler = move |Topic(topic): Topic| {
+            let tx = tx.clone();
+            async move {
+                tx.send(topic).await?;
+                Ok(())
+            }
+        };
+        let mut lights = RouterFragment::new();
+        lights.route("lights/{id}/set", handler.clone());
+        let mut sensors = RouterFragment::new();
+        sensors
+            .route("sensors/{id}", handler.clone())
+            .route("sensors/{id}/battery", handler.clone());
+
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        router.register(lights)?;
+        let ids = router.register(sensors)?;
+        assert_eq!(ids.len(), 2);
+        assert_eq!(
+            router
+                .routes()
+                .into_iter()
+                .map(|info| info.path)
+                .collect::<Vec<_>>(),
+            vec!["lights/{id}/set", "sensors/{id}", "sensors/{id}/battery"]
+        );
+
+        for topic in ["lights/l1/set", "sensors/s1/battery"] {
+            let msg = Message {
+                topic: topic.into(),
+                ..Default::default()
+            };
+            smol::block_on(router.dispatch(msg, ()))?;
+            assert_eq!(rx.try_recv().unwrap(), topic);
+        }
+
+        // A conflicting route names both routes, and leaves none of
+        // the routes of the fragment registered
+        let mut fragment = RouterFragment::new();
+        fragment
+            .route("switches/{id}", handler.clone())
+            .route("sensors/{name}", handler);
+        let err = router.register(fragment).unwrap_err();
+        assert!(err.is_misconfiguration());
+        assert!(matches!(
+            &err,
+            RouterError::RouteConflict { route, existing }
+                if route == "sensors/{name}" && existing == "sensors/{id}"
+        ));
+        assert_eq!(router.routes().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn fallback() -> RouterResult<()> {
+        let (tx, rx) = async_channel::unbounded();
+        let handler = move |Topic(topic): Topic| {
+            let tx = tx.clone();
+            async move {
+                tx.send(topic).await?;
+                Ok(())
+            }
+        };
+
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        let msg = Message {
+            topic: "unrouted/topic".into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            smol::block_on(router.dispatch(msg.clone(), ())),
+            Err(DispatchError {
+                error: RouterError::MatchError(_),
+                ..
+            })
+        ));
+
+        router.fallback(handler);
+        smol::block_on(router.dispatch(msg, ()))?;
+        assert_eq!(rx.try_recv().unwrap(), "unrouted/topic");
+        Ok(())
+    }
+
+    #[test]
+    fn typed_results() -> RouterResult<()> {
+        async fn double(Payload(number): Payload<u32>) -> anyhow::Result<u32> {
+            Ok(number * 2)
+        }
+
+        async fn levels(Topic(topic): Topic) -> anyhow::Result<u32> {
+            Ok(topic.split('/').count() as u32)
+        }
+
+        let msg = |topic: &str, payload: &str, mid| Message {
+            topic: topic.into(),
+            payload: payload.as_bytes().into(),
+            qos: QoS::AtLeastOnce,
+            mid,
+            ..Default::default()
+        };
+
+        let mut router = MqttRouter::<(), u32>::new(Client::with_auto_id()?);
+        router.insert_route(
+            parse_route("math/double")?,
+            MakeDispatcher::make_dispatcher(double),
+            RouteOptions::default(),
+        )?;
+        router.fallback(|Topic(topic): Topic| async move { anyhow::Ok(topic.len() as u32) });
+        router.layer(|request: Request<()>, next: Next<(), u32>| async move {
+            Ok(next.run(request).await? * 10)
+        });
+        router.deduplicate(Duration::from_secs(60));
+
+        let dispatch =
+            |message| smol::block_on(router.dispatch(message, ())).map_err(RouterError::from);
+        assert_eq!(dispatch(msg("math/double", "3", 1))?, 60);
+        assert_eq!(dispatch(msg("other", "", 2))?, 50);
+        // Duplicates are told apart from the values of the handlers
+        assert!(matches!(
+            dispatch(msg("math/double", "3", 1)),
+            Err(RouterError::Dispatch(err)) if matches!(err.error, RouterError::Duplicate)
+        ));
+        assert!(matches!(
+            dispatch(msg("math/double", "nope", 3)),
+            Err(RouterError::Dispatch(err))
+                if matches!(err.error, RouterError::PayloadParseFailed { .. })
+        ));
+
+        // The first matching route produces the value in wildcard mode
+        let mut router = MqttRouter::<(), u32>::new_wildcard(Client::with_auto_id()?);
+        for (route, dispatcher) in [
+            ("math/+", MakeDispatcher::make_dispatcher(levels)),
+            ("math/double", MakeDispatcher::make_dispatcher(double)),
+        ] {
+            router.insert_route(router.parse(route)?, dispatcher, RouteOptions::default())?;
+        }
+        let message = msg("math/double", "5", 0);
+        assert_eq!(smol::block_on(router.dispatch(message, ()))?, 2);
+
+        // Handlers of routers with the default R still return responses
+        assert!(().into_response()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn payload_extractors() -> RouterResult<()> {
+        let mut request = test_request(
+            Message {
+                payload: b"hello".as_slice().into(),
+                ..Default::default()
+            },
+            (),
+        );
+        let Bytes(bytes) = Bytes::from_request(&request)?;
+        assert!(Arc::ptr_eq(&bytes, &request.message.payload));
+        assert_eq!(Vec::<u8>::from_request(&request)?, b"hello");
+        assert_eq!(String::from_request(&request)?, "hello");
+
+        request.message.payload = [0xff, 0xfe].as_slice().into();
+        assert_eq!(Vec::<u8>::from_request(&request)?, [0xff, 0xfe]);
+        assert!(matches!(
+            String::from_request(&request),
+            Err(RouterError::PayloadIsNotUtf8)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn utf8_extractors() -> RouterResult<()> {
+        let mut request = test_request(
+            Message {
+                payload: "héllo".as_bytes().into(),
+                ..Default::default()
+            },
+            (),
+        );
+        assert_eq!(Utf8::from_request(&request)?.0, "héllo");
+        assert_eq!(Utf8Lossy::from_request(&request)?.0, "héllo");
+
+        request.message.payload = b"temp=\xff21.5C and some more".as_slice().into();
+        assert_eq!(
+            Utf8Lossy::from_request(&request)?.0,
+            "temp=\u{fffd}21.5C and some more"
+        );
+        match Utf8::from_request(&request) {
+            Err(RouterError::InvalidUtf8 { offset, hexdump }) => {
+                assert_eq!(offset, 5);
+                assert_eq!(hexdump, "74 65 6d 70 3d [ff] 32 31 2e 35 43 20 61 6e ..");
+            }
+            _ => panic!("expected InvalidUtf8"),
+        }
+
+        assert_eq!(hexdump_around(b"\xff", 0), "[ff]");
+        assert_eq!(
+            hexdump_around(b"0123456789\xc3", 10),
+            ".. 32 33 34 35 36 37 38 39 [c3]"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn message_meta_extractors() -> RouterResult<()> {
+        let request = test_request(
+            Message {
+                mid: 42,
+                dup: true,
+                qos: QoS::AtLeastOnce,
+                retain: true,
+                ..Default::default()
+            },
+            (),
+        );
+        let meta = MessageMeta::from_request(&request)?;
+        assert_eq!(meta.qos, QoS::AtLeastOnce);
+        assert!(meta.retain);
+        assert_eq!(meta.mid, 42);
+        assert!(meta.dup);
+        let Retained(retained) = Retained::from_request(&request)?;
+        assert!(retained);
+        Ok(())
+    }
+
+    #[test]
+    fn message_extractor() -> RouterResult<()> {
+        let request = Request {
+            params: serde_json::json!({"room": "kitchen"}),
+            ..test_request(
+                Message {
+                    topic: "sensors/kitchen".into(),
+                    payload: b"hello".as_slice().into(),
+                    ..Default::default()
+                },
+                42,
+            )
+        };
+        let message = Message::from_request(&request)?;
+        assert_eq!(&message, request.message());
+        assert!(Arc::ptr_eq(&message.payload, &request.message().payload));
+        assert_eq!(request.params()["room"], "kitchen");
+        assert_eq!(*request.state(), 42);
+        Ok(())
+    }
+
+    #[test]
+    fn optional_extractors() -> RouterResult<()> {
+        let mut request = test_request(
+            Message {
+                payload: b"12".as_slice().into(),
+                ..Default::default()
+            },
+            (),
+        );
+        assert_eq!(
+            Option::<Payload<u32>>::from_request(&request)?.map(|p| p.0),
+            Some(12)
+        );
+        assert_eq!(
+            Result::<Payload<u32>, RouterError>::from_request(&request)?
+                .unwrap()
+                .0,
+            12
+        );
+
+        request.message.payload = b"twelve".as_slice().into();
+        assert!(Option::<Payload<u32>>::from_request(&request)?.is_none());
+        assert!(matches!(
+            Result::<Payload<u32>, RouterError>::from_request(&request)?,
+            Err(RouterError::PayloadParseFailed { .. })
+        ));
+
+        // A misconfigured route is not hidden by Option, but
+        // is passed through to a handler that asks for a Result
+        assert!(matches!(
+            Option::<Rest>::from_request(&request),
+            Err(RouterError::NoCatchAll)
+        ));
+        assert!(matches!(
+            Result::<Rest, RouterError>::from_request(&request)?,
+            Err(RouterError::NoCatchAll)
+        ));
+
+        // The failure is reported to the handler rather than failing
+        // the dispatch
+        let (tx, rx) = async_channel::unbounded();
+        let handler = move |number: Option<Payload<u32>>, Utf8Lossy(text): Utf8Lossy| {
+            let tx = tx.clone();
+            async move {
+                tx.send((number.map(|p| p.0), text)).await?;
+                Ok(())
+            }
+        };
+        let dispatcher: Dispatcher = MakeDispatcher::make_dispatcher(handler);
+        smol::block_on(dispatcher.call(JsonValue::Null, request.message.clone(), ()))?;
+        assert_eq!(rx.try_recv().unwrap(), (None, "twelve".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn publisher_extractor() -> RouterResult<()> {
+        let mut request = test_request(Message::default(), ());
+        assert!(matches!(
+            Publisher::from_request(&request),
+            Err(RouterError::NoClient)
+        ));
+
+        let (tx, rx) = async_channel::unbounded();
+        let handler = move |publisher: Publisher| {
+            let tx = tx.clone();
+            async move {
+                tx.send(format!("{:?}", publisher.client())).await?;
+                Ok(())
+            }
+        };
+        let mut router = <MqttRouter>::new(Client::with_id("router", true)?);
+        router.fallback(handler);
+        smol::block_on(router.dispatch(Message::default(), ()))?;
+        assert!(rx.try_recv().unwrap().contains("\"router\""));
+
+        request.client = Some(router.client().clone());
+        assert!(Publisher::from_request(&request).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn responses() -> RouterResult<()> {
+        assert_eq!(().into_handler_result()?, ((), vec![]));
+        let response = Response::publish("a", "hello")
+            .qos(QoS::AtLeastOnce)
+            .retain(true);
+        assert_eq!(
+            anyhow::Ok(vec![response.clone()]).into_response()?,
+            vec![response.clone()]
+        );
+        assert_eq!(
+            Some(response.clone()).into_response()?,
+            vec![response.clone()]
+        );
+        assert!(anyhow::Result::<()>::Err(anyhow::anyhow!("failed"))
+            .into_handler_result()
+            .is_err());
+        assert_eq!(
+            anyhow::Ok(vec![response.clone()]).into_handler_result()?,
+            ((), vec![response])
+        );
+        assert_eq!(anyhow::Ok(42).into_handler_result()?, (42, vec![]));
+
+        async fn reply(Topic(topic): Topic) -> anyhow::Result<Response> {
+            Ok(Response::publish(format!("{topic}\0reply"), "pong"))
+        }
+
+        // There's no client to publish the response
+        let dispatcher: Dispatcher = MakeDispatcher::make_dispatcher(reply);
+        let err =
+            smol::block_on(dispatcher.call(JsonValue::Null, Message::default(), ())).unwrap_err();
+        assert!(matches!(handler_error(err), RouterError::NoClient));
+
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        router.fallback(reply);
+        let message = Message {
+            topic: "ping".into(),
+            ..Default::default()
+        };
+        match smol::block_on(router.dispatch(message, ())) {
+            Err(DispatchError {
+                error: RouterError::PublishResponse { topic, .. },
+                ..
+            }) => assert_eq!(topic, "ping\0reply"),
+            _ => panic!("expected PublishResponse"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn replies() -> RouterResult<()> {
+        let mut request = Message {
+            topic: "rpc/add".into(),
+            properties: vec![
+                Property::ResponseTopic("rpc/add/reply".to_string()),
+                Property::CorrelationData(b"42".to_vec()),
+            ],
+            ..Default::default()
+        };
+        let reply = Response::reply("2").qos(QoS::AtLeastOnce);
+        assert_eq!(
+            reply.to_publish(&request)?,
+            PublishBuilder::new("rpc/add/reply")
+                .payload("2")
+                .qos(QoS::AtLeastOnce)
+                .correlation_data(b"42".to_vec())
+        );
+        // An explicit topic is used as-is
+        assert_eq!(
+            Response::publish("log", "added").to_publish(&request)?,
+            PublishBuilder::new("log").payload("added")
+        );
+
+        request.properties.clear();
+        match reply.to_publish(&request) {
+            Err(RouterError::NoResponseTopic { request_topic }) => {
+                assert_eq!(request_topic, "rpc/add")
+            }
+            _ => panic!("expected NoResponseTopic"),
+        }
+
+        async fn add(Payload(number): Payload<u32>) -> anyhow::Result<Response> {
+            Ok(Response::reply((number + 1).to_string()))
+        }
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        router.fallback(add);
+        request.payload = b"1".as_slice().into();
+        assert!(matches!(
+            smol::block_on(router.dispatch(request, ())),
+            Err(DispatchError {
+                error: RouterError::NoResponseTopic { .. },
+                ..
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn run() -> RouterResult<()> {
+        let (tx, rx) = async_channel::unbounded();
+        let handler = move |Topic(topic): Topic| {
+            let tx = tx.clone();
+            async move {
+                if topic == "fail" {
+                    anyhow::bail!("failed");
+                }
+                tx.send(topic).await?;
+                Ok(())
+            }
+        };
+        let errors = Arc::new(Mutex::new(vec![]));
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        router.fallback(handler);
+        router.on_error({
+            let errors = Arc::clone(&errors);
+            move |err| errors.lock().unwrap().push(err.error.to_string())
+        });
+
+        // Errors are reported and don't stop the loop, which ends when
+        // the channel is closed
+        let (events_tx, events) = async_channel::unbounded();
+        for topic in ["a", "fail", "b"] {
+            events_tx
+                .try_send(Event::Message(Message {
+                    topic: topic.into(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        events_tx.close();
+        smol::block_on(router.run_events(events, (), std::future::pending()));
+        assert_eq!(rx.try_recv().unwrap(), "a");
+        assert_eq!(rx.try_recv().unwrap(), "b");
+        assert_eq!(*errors.lock().unwrap(), vec!["failed".to_string()]);
+
+        // Shutdown stops the loop even though the channel remains open
+        let (_events_tx, events) = async_channel::unbounded();
+        smol::block_on(router.run_events(events, (), std::future::ready(())));
+
+        let events = router.client().subscriber().unwrap();
+        assert!(matches!(
+            smol::block_on(router.run((), std::future::ready(()))),
+            Err(RouterError::SubscriberTaken)
+        ));
+        drop(events);
+        Ok(())
+    }
+
+    #[test]
+    fn handler_panics() -> RouterResult<()> {
+        let (tx, rx) = async_channel::unbounded();
+        let handler = move |Topic(topic): Topic| {
+            let tx = tx.clone();
+            async move {
+                if topic == "panic" {
+                    panic!("bad route");
+                }
+                tx.send(topic).await?;
+                Ok(())
+            }
+        };
+        let errors = Arc::new(Mutex::new(vec![]));
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        router.fallback(handler);
+        router.on_error({
+            let errors = Arc::clone(&errors);
+            move |err| errors.lock().unwrap().push(err.error)
+        });
+
+        let (events_tx, events) = async_channel::unbounded();
+        for topic in ["a", "panic", "b"] {
+            events_tx
+                .try_send(Event::Message(Message {
+                    topic: topic.into(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        events_tx.close();
+        smol::block_on(router.run_events(events, (), std::future::pending()));
+        assert_eq!(rx.try_recv().unwrap(), "a");
+        assert_eq!(rx.try_recv().unwrap(), "b");
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            &errors[0],
+            RouterError::HandlerPanicked { route: None, payload } if payload == "bad route"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn shared_routes() -> RouterResult<()> {
+        async fn handler(_: Topic) -> anyhow::Result<()> {
+            Ok(())
+        }
+        let shared = RouteOptions::default().shared_group("workers");
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+
+        // Rejected before subscribing for a v3 client, or a bad group
+        assert!(matches!(
+            smol::block_on(router.route_with("commands/{id}", shared.clone(), handler)),
+            Err(RouterError::SharedSubscriptionRequiresV5)
+        ));
+        router
+            .client()
+            .set_option(&crate::ClientOption::ProtocolVersion(ProtocolVersion::V5))?;
+        for group in ["", "a/b", "a+", "#"] {
+            let options = RouteOptions::default().shared_group(group);
+            assert!(matches!(
+                options.check_shared_group(router.client()),
+                Err(RouterError::InvalidSharedGroup { .. })
+            ));
+        }
+        shared.check_shared_group(router.client())?;
+
+        // The subscription has the prefix, but the route doesn't
+        let dispatcher = MakeDispatcher::make_dispatcher(handler);
+        router.insert_route(parse_route("commands/{id}")?, dispatcher, shared.clone())?;
+        let dispatcher = MakeDispatcher::make_dispatcher(handler);
+        router.insert_route(parse_route("status")?, dispatcher, RouteOptions::default())?;
+        assert_eq!(
+            router
+                .routes()
+                .into_iter()
+                .map(|info| (info.path, info.topic))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "commands/{id}".to_string(),
+                    "$share/workers/commands/+".to_string()
+                ),
+                ("status".to_string(), "status".to_string()),
+            ]
+        );
+        assert!(router.router.at("commands/1").is_ok());
+        assert!(router.router.at("$share/workers/commands/1").is_err());
+
+        assert_eq!(
+            router.remove_route("commands/{id}")?,
+            Some("$share/workers/commands/+".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn handler_timeouts() -> RouterResult<()> {
+        async fn hang(_: Topic) -> anyhow::Result<()> {
+            std::future::pending().await
+        }
+        async fn quick(_: Topic) -> anyhow::Result<()> {
+            Ok(())
+        }
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        let options = RouteOptions::default().timeout(Duration::from_millis(10));
+        let dispatcher = MakeDispatcher::make_dispatcher(hang);
+        router.insert_route(parse_route("hang")?, dispatcher, options.clone())?;
+        let dispatcher = MakeDispatcher::make_dispatcher(quick);
+        router.insert_route(parse_route("quick")?, dispatcher, options)?;
+        router.fallback(hang);
+
+        let message = |topic: &str| Message {
+            topic: topic.into(),
+            ..Default::default()
+        };
+        let err = smol::block_on(router.dispatch(message("hang"), ())).unwrap_err();
+        match err.error {
+            RouterError::HandlerTimeout { route, elapsed } => {
+                assert_eq!(route.as_deref(), Some("hang"));
+                assert_eq!(elapsed, Duration::from_millis(10));
+            }
+            err => panic!("expected HandlerTimeout, got {err:#}"),
+        }
+        smol::block_on(router.dispatch(message("quick"), ()))?;
+
+        // The default applies to the fallback
+        router.set_default_timeout(Duration::from_millis(10));
+        let err = smol::block_on(router.dispatch(message("other"), ())).unwrap_err();
+        assert!(matches!(
+            err.error,
+            RouterError::HandlerTimeout { route: None, .. }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn run_concurrent() -> RouterResult<()> {
+        // The handler for "a" can only complete once the handler for
+        // "b" has run, so this deadlocks unless they run concurrently
+        let (tx, rx) = async_channel::unbounded();
+        let handler = move |Topic(topic): Topic| {
+            let (tx, rx) = (tx.clone(), rx.clone());
+            async move {
+                match topic.as_str() {
+                    "a" => rx.recv().await?,
+                    "fail" => anyhow::bail!("failed"),
+                    _ => tx.send(()).await?,
+                }
+                Ok(())
+            }
+        };
+        let errors = Arc::new(Mutex::new(vec![]));
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        router.fallback(handler);
+        router.on_error({
+            let errors = Arc::clone(&errors);
+            move |err| errors.lock().unwrap().push(err.error.to_string())
+        });
+
+        let (events_tx, events) = async_channel::unbounded();
+        for topic in ["a", "fail", "b"] {
+            events_tx
+                .try_send(Event::Message(Message {
+                    topic: topic.into(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        events_tx.close();
+        smol::block_on(Arc::new(router).run_events_concurrent(
+            events,
+            (),
+            2,
+            |dispatch| smol::spawn(dispatch).detach(),
+            std::future::pending(),
+        ));
+        assert_eq!(*errors.lock().unwrap(), vec!["failed".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn run_concurrent_dropped_dispatch() -> RouterResult<()> {
+        // An executor that drops the dispatches without running them
+        // must not leave the loop waiting for their slots
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        router.fallback(|_: Topic| async { anyhow::Ok(()) });
+
+        let (events_tx, events) = async_channel::unbounded();
+        for topic in ["a", "b", "c"] {
+            events_tx
+                .try_send(Event::Message(Message {
+                    topic: topic.into(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        events_tx.close();
+        let dropped = std::cell::Cell::new(0);
+        smol::block_on(Arc::new(router).run_events_concurrent(
+            events,
+            (),
+            1,
+            |dispatch| {
+                dropped.set(dropped.get() + 1);
+                drop(dispatch);
+            },
+            std::future::pending(),
+        ));
+        assert_eq!(dropped.get(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn run_concurrent_drain_timeout() -> RouterResult<()> {
+        // The handler never completes, but signals the shutdown, which
+        // must stop the loop even though it is waiting for a free slot
+        let (started_tx, started_rx) = async_channel::unbounded();
+        let handler = move |_: Topic| {
+            let started_tx = started_tx.clone();
+            async move {
+                started_tx.send(()).await?;
+                std::future::pending::<()>().await;
+                Ok(())
+            }
+        };
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        router.fallback(handler);
+        router.set_drain_timeout(Duration::from_millis(10));
+
+        let (events_tx, events) = async_channel::unbounded();
+        for topic in ["a", "b"] {
+            events_tx
+                .try_send(Event::Message(Message {
+                    topic: topic.into(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        let start = Instant::now();
+        smol::block_on(Arc::new(router).run_events_concurrent(
+            events,
+            (),
+            1,
+            |dispatch| smol::spawn(dispatch).detach(),
+            async move {
+                let _ = started_rx.recv().await;
+            },
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        Ok(())
+    }
+
+    #[test]
+    fn metrics() -> RouterResult<()> {
+        #[derive(Default)]
+        struct Sink(Mutex<Vec<(Option<String>, bool)>>);
+        impl MetricsSink for Arc<Sink> {
+            fn record(&self, route: Option<&str>, _: Duration, error: Option<&RouterError>) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((route.map(|route| route.to_string()), error.is_some()));
+            }
+        }
+
+        async fn handler(Payload(_): Payload<u8>) -> anyhow::Result<()> {
+            Ok(())
+        }
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        let dispatcher = MakeDispatcher::make_dispatcher(handler);
+        router.insert_route(
+            parse_route("level/{id}")?,
+            dispatcher,
+            RouteOptions::default(),
+        )?;
+        let message = |topic: &str, payload: &[u8]| Message {
+            topic: topic.into(),
+            payload: payload.into(),
+            ..Default::default()
+        };
+
+        // Nothing is recorded until metrics are enabled
+        smol::block_on(router.dispatch(message("level/1", b"1"), ()))?;
+        assert_eq!(router.metrics()[0].dispatches, 0);
+
+        router.enable_metrics();
+        let sink = Arc::new(Sink::default());
+        router.set_metrics_sink(Arc::clone(&sink));
+        router.fallback(handler);
+        smol::block_on(router.dispatch(message("level/1", b"1"), ()))?;
+        smol::block_on(router.dispatch(message("level/2", b"x"), ())).unwrap_err();
+        smol::block_on(router.dispatch(message("other", b"1"), ()))?;
+
+        let metrics = router.metrics();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0].route.as_deref(), Some("level/{id}"));
+        assert_eq!(metrics[0].dispatches, 2);
+        assert_eq!(metrics[0].errors, 1);
+        assert_eq!(metrics[0].latency.counts.iter().sum::<u64>(), 2);
+        assert!(metrics[0].latency.mean().is_some());
+        assert_eq!(metrics[1].route, None);
+        assert_eq!(metrics[1].dispatches, 1);
+        assert_eq!(metrics[1].errors, 0);
+
+        assert_eq!(
+            *sink.0.lock().unwrap(),
+            vec![
+                (Some("level/{id}".to_string()), false),
+                (Some("level/{id}".to_string()), true),
+                (None, false),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dispatch_errors() -> RouterResult<()> {
+        async fn handler(Payload(_): Payload<u8>) -> anyhow::Result<()> {
+            Ok(())
+        }
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        let dispatcher = MakeDispatcher::make_dispatcher(handler);
+        router.insert_route(
+            parse_route("level/{id}")?,
+            dispatcher,
+            RouteOptions::default(),
+        )?;
+
+        let message = |topic: &str| Message {
+            topic: topic.into(),
+            payload: b"high".as_slice().into(),
+            qos: QoS::AtLeastOnce,
+            mid: 7,
+            ..Default::default()
+        };
+        let err = smol::block_on(router.dispatch(message("level/1"), ())).unwrap_err();
+        assert_eq!(err.route.as_deref(), Some("level/{id}"));
+        assert_eq!(err.topic, "level/1");
+        assert_eq!(err.meta.mid, 7);
+        assert_eq!(err.meta.qos, QoS::AtLeastOnce);
+        assert!(matches!(err.error, RouterError::PayloadParseFailed { .. }));
+        assert!(err
+            .to_string()
+            .starts_with("failed to handle message on level/1 for route level/{id}: "));
+
+        let err = smol::block_on(router.dispatch(message("other"), ())).unwrap_err();
+        assert_eq!(err.route, None);
+        assert!(matches!(err.error, RouterError::MatchError(_)));
+
+        // handle_event wraps the same context
+        match smol::block_on(router.handle_event(Event::Message(message("other")), ())) {
+            Err(RouterError::Dispatch(err)) => assert_eq!(err.topic, "other"),
+            _ => panic!("expected RouterError::Dispatch"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn layers() -> RouterResult<()> {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let handler = {
+            let calls = Arc::clone(&calls);
+            move |Topic(topic): Topic| {
+                calls.lock().unwrap().push(format!("handler {topic}"));
+                async { Ok(()) }
+            }
+        };
+        let mut router = <MqttRouter>::new(Client::with_auto_id()?);
+        let dispatcher = MakeDispatcher::make_dispatcher(handler.clone());
+        router.insert_route(
+            parse_route("devices/{id}")?,
+            dispatcher,
+            RouteOptions::default(),
+        )?;
+        router.fallback(handler);
+        for name in ["outer", "inner"] {
+            let calls = Arc::clone(&calls);
+            router.layer(move |request: Request<()>, next: Next<()>| {
+                let calls = Arc::clone(&calls);
+                async move {
+                    let route = request.route().unwrap_or("fallback").to_string();
+                    calls.lock().unwrap().push(format!("{name} {route}"));
+                    if request.message().payload.is_empty() {
+                        anyhow::bail!("empty payload");
+                    }
+                    next.run(request).await
+                }
+            });
+        }
+
+        let message = |topic: &str, payload: &[u8]| Message {
+            topic: topic.into(),
+            payload: payload.into(),
+            ..Default::default()
+        };
+        smol::block_on(router.dispatch(message("devices/1", b"on"), ()))?;
+        smol::block_on(router.dispatch(message("other", b"on"), ()))?;
+        assert_eq!(
+            std::mem::take(&mut *calls.lock().unwrap()),
+            vec![
+                "outer devices/{id}",
+                "inner devices/{id}",
+                "handler devices/1",
+                "outer fallback",
+                "inner fallback",
+                "handler other",
+            ]
+        );
+
+        // The outer layer short-circuits the rest of the chain
+        let err = smol::block_on(router.dispatch(message("devices/1", b""), ())).unwrap_err();
+        assert_eq!(err.error.to_string(), "empty payload");
+        assert_eq!(*calls.lock().unwrap(), vec!["outer devices/{id}"]);
+        Ok(())
+    }
+
+    #[test]
+    fn tuple_extractor() -> RouterResult<()> {
+        let request = test_request(
+            Message {
+                topic: "sensors/temp".into(),
+                payload: b"21.5".as_slice().into(),
+                ..Default::default()
+            },
+            (),
+        );
+        let (Topic(topic), Payload(value), Bytes(bytes)): (Topic, Payload<f64>, Bytes) =
+            FromRequest::from_request(&request)?;
+        assert_eq!(topic, "sensors/temp");
+        assert_eq!(value, 21.5);
+        assert_eq!(&*bytes, b"21.5");
+
+        let result: RouterResult<(Topic, Payload<i32>)> = FromRequest::from_request(&request);
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn message_id_extractor() -> RouterResult<()> {
+        let request = test_request(
+            Message {
+                mid: 42,
+                ..Default::default()
+            },
+            (),
+        );
+        let MessageIdExtractor(mid) = MessageIdExtractor::from_request(&request)?;
+        assert_eq!(mid, 42);
+        Ok(())
+    }
+
+    #[test]
+    fn response_extractors() -> RouterResult<()> {
+        let mut request = test_request(Message::default(), ());
+        let ResponseTopic(topic) = ResponseTopic::from_request(&request)?;
+        let CorrelationData(data) = CorrelationData::from_request(&request)?;
+        assert_eq!(topic, None);
+        assert_eq!(data, None);
 
+        request.message.properties = vec![
+            Property::ContentType("text/plain".to_string()),
+            Property::ResponseTopic("reply/here".to_string()),
+            Property::CorrelationData(vec![1, 2, 3]),
+        ];
+        let ResponseTopic(topic) = ResponseTopic::from_request(&request)?;
+        let CorrelationData(data) = CorrelationData::from_request(&request)?;
+        assert_eq!(topic.as_deref(), Some("reply/here"));
+        assert_eq!(data, Some(vec![1, 2, 3]));
         Ok(())
     }
 }
@@ -0,0 +1,151 @@
+use crate::{Client, Error, Message, QoS};
+use async_channel::Receiver;
+use futures_core::Stream;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// A stream of the JSON payloads of the messages that match a filter,
+/// deserialized into `T`; see [Client::typed_stream].
+///
+/// Each item is the topic of a message paired with its payload, or
+/// `Error::Decode` if the payload couldn't be deserialized; decode
+/// errors don't end the stream.  The stream ends when the session
+/// of the client ends.
+pub struct TypedStream<T> {
+    rx: Pin<Box<Receiver<Arc<Message>>>>,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> TypedStream<T> {
+    pub(crate) fn new(rx: Receiver<Arc<Message>>) -> Self {
+        Self {
+            rx: Box::pin(rx),
+            _type: PhantomData,
+        }
+    }
+
+    /// Wait for the next item, or None once the stream has ended.
+    /// This allows consuming the stream without a `StreamExt` trait.
+    pub async fn next(&mut self) -> Option<Result<(String, T), Error>> {
+        let message = self.rx.recv().await.ok()?;
+        Some(decode(&message))
+    }
+}
+
+fn decode<T: DeserializeOwned>(message: &Message) -> Result<(String, T), Error> {
+    match serde_json::from_slice(&message.payload) {
+        Ok(value) => Ok((message.topic.to_string(), value)),
+        Err(source) => Err(Error::Decode {
+            topic: message.topic.to_string(),
+            source,
+        }),
+    }
+}
+
+impl<T: DeserializeOwned> Stream for TypedStream<T> {
+    type Item = Result<(String, T), Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.rx
+            .as_mut()
+            .poll_next(cx)
+            .map(|message| message.map(|message| decode(&message)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.rx.size_hint()
+    }
+}
+
+impl Client {
+    /// Subscribe to `filter` and return a stream of the messages that
+    /// match it, with their JSON payloads deserialized into `T`.
+    ///
+    /// The messages are routed in the same way as for
+    /// [filtered_subscriber](#method.filtered_subscriber), so they are
+    /// not delivered to the [subscriber](#method.subscriber) channel.
+    /// The subscription remains in place when the stream is dropped.
+    ///
+    /// ```no_run
+    /// use mosquitto_rs::*;
+    ///
+    /// #[derive(serde::Deserialize)]
+    /// struct Reading {
+    ///     celsius: f64,
+    /// }
+    ///
+    /// async fn temperatures(client: &Client) -> Result<(), Error> {
+    ///     let mut readings = client
+    ///         .typed_stream::<Reading>("sensors/+/temperature", QoS::AtMostOnce)
+    ///         .await?;
+    ///     while let Some(item) = readings.next().await {
+    ///         match item {
+    ///             Ok((topic, reading)) => println!("{topic}: {}", reading.celsius),
+    ///             Err(err) => eprintln!("{err:#}"),
+    ///         }
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    #[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+    pub async fn typed_stream<T: DeserializeOwned>(
+        &self,
+        filter: &str,
+        qos: QoS,
+    ) -> Result<TypedStream<T>, Error> {
+        // Route the messages before subscribing, so that none are missed
+        let rx = self.filtered_subscriber(filter)?;
+        self.subscribe(filter, qos).await?;
+        Ok(TypedStream::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn typed_stream() {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Reading {
+            celsius: f64,
+        }
+
+        let (tx, rx) = async_channel::unbounded();
+        let mut stream = TypedStream::<Reading>::new(rx);
+        for (topic, payload) in [
+            ("a", "{\"celsius\": 21.5}"),
+            ("b", "nope"),
+            ("c", "{\"celsius\": 3}"),
+        ] {
+            tx.try_send(Arc::new(Message {
+                topic: topic.into(),
+                payload: payload.as_bytes().into(),
+                ..Default::default()
+            }))
+            .unwrap();
+        }
+        tx.close();
+
+        smol::block_on(async {
+            assert_eq!(
+                stream.next().await.unwrap().unwrap(),
+                ("a".to_string(), Reading { celsius: 21.5 })
+            );
+            // Decode errors don't end the stream
+            match stream.next().await {
+                Some(Err(Error::Decode { topic, .. })) => assert_eq!(topic, "b"),
+                other => panic!("unexpected {other:?}"),
+            }
+            let item = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await;
+            assert_eq!(
+                item.unwrap().unwrap(),
+                ("c".to_string(), Reading { celsius: 3.0 })
+            );
+            assert!(stream.next().await.is_none());
+        });
+    }
+}
@@ -0,0 +1,255 @@
+//! A minimal timer, so that the router can apply timeouts without
+//! depending on any particular async runtime.
+//! A single thread sleeps until the earliest deadline and then wakes
+//! the futures that are waiting for it.
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+struct Entry {
+    fired: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Entry {
+    fn fire(&self) {
+        self.fired.store(true, AtomicOrdering::SeqCst);
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// An entry in the timer heap.  The entry is held weakly so that the
+/// heap doesn't keep a Sleep that was dropped early alive; the heap
+/// slots of such entries are purged by `Timer::cancel`.
+struct Deadline {
+    at: Instant,
+    /// Breaks ties between entries with the same deadline
+    seq: u64,
+    entry: Weak<Entry>,
+}
+
+impl PartialEq for Deadline {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Deadline {}
+
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Deadline {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+struct Timer {
+    deadlines: Mutex<BinaryHeap<Reverse<Deadline>>>,
+    changed: Condvar,
+    next_seq: AtomicU64,
+    /// The number of Sleeps that were dropped before their deadline
+    /// since the heap was last purged
+    cancelled: AtomicUsize,
+}
+
+lazy_static::lazy_static! {
+    static ref TIMER: Arc<Timer> = Timer::start();
+}
+
+impl Timer {
+    fn start() -> Arc<Self> {
+        let timer = Arc::new(Self {
+            deadlines: Mutex::new(BinaryHeap::new()),
+            changed: Condvar::new(),
+            next_seq: AtomicU64::new(0),
+            cancelled: AtomicUsize::new(0),
+        });
+        let thread_timer = Arc::clone(&timer);
+        std::thread::Builder::new()
+            .name("mosquitto-timer".to_string())
+            .spawn(move || thread_timer.run())
+            .expect("failed to spawn the timer thread");
+        timer
+    }
+
+    fn run(&self) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let mut expired = vec![];
+            while let Some(Reverse(deadline)) = deadlines.peek() {
+                if deadline.at > now {
+                    break;
+                }
+                if let Some(Reverse(deadline)) = deadlines.pop() {
+                    expired.push(deadline.entry);
+                }
+            }
+
+            if !expired.is_empty() {
+                // Wake outside of the lock, in case a waker polls its
+                // future inline and that registers another deadline
+                drop(deadlines);
+                for entry in expired.iter().filter_map(Weak::upgrade) {
+                    entry.fire();
+                }
+                deadlines = self.deadlines.lock().unwrap();
+                continue;
+            }
+
+            deadlines = match deadlines.peek() {
+                Some(Reverse(deadline)) => {
+                    let wait = deadline.at.saturating_duration_since(now);
+                    self.changed.wait_timeout(deadlines, wait).unwrap().0
+                }
+                None => self.changed.wait(deadlines).unwrap(),
+            };
+        }
+    }
+
+    fn register(&self, at: Instant, entry: &Arc<Entry>) {
+        let seq = self.next_seq.fetch_add(1, AtomicOrdering::Relaxed);
+        self.deadlines.lock().unwrap().push(Reverse(Deadline {
+            at,
+            seq,
+            entry: Arc::downgrade(entry),
+        }));
+        self.changed.notify_one();
+    }
+
+    /// Note that a registered entry has been dropped before its deadline.
+    /// Once such entries make up half of the heap, they are removed from
+    /// it, so that many short lived timeouts with distant deadlines don't
+    /// grow the heap without bound, at an amortized cost of O(1) each.
+    fn cancel(&self) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let cancelled = self.cancelled.fetch_add(1, AtomicOrdering::Relaxed) + 1;
+        if cancelled * 2 > deadlines.len() {
+            deadlines.retain(|Reverse(deadline)| deadline.entry.strong_count() > 0);
+            self.cancelled.store(0, AtomicOrdering::Relaxed);
+        }
+    }
+}
+
+/// A future that resolves once `duration` has elapsed
+pub(crate) struct Sleep {
+    at: Instant,
+    entry: Option<Arc<Entry>>,
+}
+
+pub(crate) fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        at: Instant::now() + duration,
+        entry: None,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.at {
+            return Poll::Ready(());
+        }
+        let at = self.at;
+        let entry = self.entry.get_or_insert_with(|| {
+            let entry = Arc::new(Entry {
+                fired: AtomicBool::new(false),
+                waker: Mutex::new(Some(cx.waker().clone())),
+            });
+            TIMER.register(at, &entry);
+            entry
+        });
+        // Store the waker before checking whether the deadline has
+        // fired, so that a concurrent fire can't be missed
+        entry.waker.lock().unwrap().replace(cx.waker().clone());
+        if entry.fired.load(AtomicOrdering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            let fired = entry.fired.load(AtomicOrdering::SeqCst);
+            drop(entry);
+            if !fired {
+                TIMER.cancel();
+            }
+        }
+    }
+}
+
+/// Run `future` until it completes or `duration` elapses, whichever
+/// happens first.  Returns None if the time elapsed, in which case
+/// `future` is dropped without being polled again.
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    let mut future = std::pin::pin!(future);
+    let mut sleep = std::pin::pin!(sleep(duration));
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        sleep.as_mut().poll(cx).map(|()| None)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timeouts() {
+        let start = Instant::now();
+        smol::block_on(sleep(Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        assert_eq!(
+            smol::block_on(timeout(Duration::from_secs(10), async { 42 })),
+            Some(42)
+        );
+        assert_eq!(
+            smol::block_on(timeout(
+                Duration::from_millis(10),
+                std::future::pending::<()>()
+            )),
+            None
+        );
+
+        // An earlier deadline registered after a later one still fires first
+        let later = sleep(Duration::from_secs(60));
+        let mut later = std::pin::pin!(later);
+        assert!(smol::block_on(smol::future::poll_once(later.as_mut())).is_none());
+        let start = Instant::now();
+        smol::block_on(sleep(Duration::from_millis(10)));
+        assert!(start.elapsed() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn cancelled() {
+        // Timeouts that don't expire don't accumulate in the heap
+        for _ in 0..1000 {
+            let result = smol::block_on(timeout(Duration::from_secs(60), async { 42 }));
+            assert_eq!(result, Some(42));
+            let mut pending = Box::pin(sleep(Duration::from_secs(60)));
+            assert!(smol::block_on(smol::future::poll_once(pending.as_mut())).is_none());
+        }
+        assert!(TIMER.deadlines.lock().unwrap().len() < 100);
+    }
+}
@@ -0,0 +1,986 @@
+#[cfg(feature = "openssl")]
+use crate::log;
+use crate::{Error, TlsVersion};
+#[cfg(feature = "openssl")]
+use openssl::hash::MessageDigest;
+#[cfg(feature = "openssl")]
+use openssl::ssl::{SslContext, SslFiletype, SslMethod, SslVerifyMode, SslVersion};
+#[cfg(feature = "openssl")]
+use openssl::x509::{X509StoreContext, X509StoreContextRef, X509VerifyResult};
+#[cfg(feature = "openssl")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "openssl")]
+use std::io::Write;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "openssl")]
+use std::sync::{Arc, Mutex};
+
+/// Controls how pinned server certificate fingerprints interact with
+/// the usual validation of the certificate chain against the trusted CAs.
+#[cfg_attr(docsrs, doc(cfg(feature = "openssl")))]
+#[cfg(feature = "openssl")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PinMode {
+    /// The server certificate must both chain to a trusted CA and
+    /// match one of the pinned fingerprints.
+    #[default]
+    InAdditionToCa,
+    /// The server certificate is accepted if it matches one of the
+    /// pinned fingerprints, regardless of the outcome of CA validation.
+    /// This allows the use of self-signed server certificates.
+    InsteadOfCa,
+}
+
+/// Details of the TLS session negotiated with the broker, as returned
+/// by [tls_session_info](struct.Client.html#method.tls_session_info).
+/// Useful for diagnosing TLS issues, such as those caused by middleboxes
+/// that intercept connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsSessionInfo {
+    /// The protocol version, such as `"TLSv1.3"`
+    pub version: String,
+    /// The name of the cipher suite, such as `"TLS_AES_256_GCM_SHA384"`
+    pub cipher: String,
+    /// The DER encoded certificate chain presented by the broker,
+    /// starting with its own certificate
+    pub peer_cert_der: Vec<Vec<u8>>,
+    /// The negotiated ALPN protocol, if any
+    pub alpn: Option<String>,
+}
+
+#[cfg(feature = "openssl")]
+impl TlsSessionInfo {
+    pub(crate) fn from_ssl(ssl: &openssl::ssl::SslRef) -> Self {
+        Self {
+            version: ssl.version_str().to_string(),
+            cipher: ssl
+                .current_cipher()
+                .map(|cipher| cipher.name().to_string())
+                .unwrap_or_default(),
+            peer_cert_der: ssl
+                .peer_cert_chain()
+                .map(|chain| chain.iter().filter_map(|cert| cert.to_der().ok()).collect())
+                .unwrap_or_default(),
+            alpn: ssl
+                .selected_alpn_protocol()
+                .map(|proto| String::from_utf8_lossy(proto).into_owned()),
+        }
+    }
+}
+
+/// Identifies a client private key that is held by an OpenSSL engine,
+/// such as a PKCS#11 hardware security module, for use with
+/// [TlsConfig::engine](struct.TlsConfig.html#method.engine).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EngineConfig {
+    /// The id of the OpenSSL engine, such as `"pkcs11"`
+    pub engine_id: String,
+    /// The engine specific identifier for the private key, such as
+    /// a PKCS#11 URI
+    pub key_uri: String,
+    /// The hex encoded SHA-1 hash of the password needed to access
+    /// the key, if any.  This is passed to the engine directly.
+    pub kpass_sha1: Option<String>,
+}
+
+impl EngineConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if self.engine_id.is_empty() {
+            return Err(Error::Tls("engine_id must not be empty".to_string()));
+        }
+        if self.key_uri.is_empty() {
+            return Err(Error::Tls("engine key_uri must not be empty".to_string()));
+        }
+        if let Some(kpass) = &self.kpass_sha1 {
+            if kpass.len() != 40 || !kpass.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(Error::Tls(
+                    "engine kpass_sha1 must be a SHA-1 hash of 40 hex digits".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The TLS settings for a [Client](struct.Client.html), applied via
+/// [configure_tls_config](struct.Client.html#method.configure_tls_config).
+///
+/// Collecting the settings together allows them to be validated as a
+/// whole and applied in the order that libmosquitto requires.
+///
+/// Some settings (pinned fingerprints, key logging and offering more
+/// than one ALPN protocol) require the `openssl` feature; when used,
+/// the client is given an OpenSSL context built from these settings.
+///
+/// ```no_run
+/// use mosquitto_rs::*;
+///
+/// fn setup(client: &Client) -> Result<(), Error> {
+///     let mut config = TlsConfig::new();
+///     config
+///         .ca_file("/etc/mosquitto/ca.pem")
+///         .client_cert("/etc/mosquitto/client.pem")
+///         .client_key("/etc/mosquitto/client.key")
+///         .min_version(TlsVersion::V1_2);
+///     client.configure_tls_config(&config)
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    pub(crate) ca_file: Option<PathBuf>,
+    pub(crate) ca_path: Option<PathBuf>,
+    pub(crate) client_cert: Option<PathBuf>,
+    pub(crate) client_key: Option<PathBuf>,
+    pub(crate) key_password: Option<String>,
+    pub(crate) min_version: Option<TlsVersion>,
+    pub(crate) ciphers: Option<String>,
+    pub(crate) alpn: Vec<String>,
+    pub(crate) insecure: bool,
+    pub(crate) use_os_certs: bool,
+    pub(crate) system_roots: bool,
+    pub(crate) psk: Option<(String, String)>,
+    pub(crate) engine: Option<EngineConfig>,
+    #[cfg(feature = "openssl")]
+    pins: Vec<[u8; 32]>,
+    #[cfg(feature = "openssl")]
+    pin_mode: PinMode,
+    #[cfg(feature = "openssl")]
+    keylog_file: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the CA certificates in the PEM encoded file at `path`.
+    pub fn ca_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.ca_file.replace(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Trust the PEM encoded CA certificates in the directory at `path`.
+    /// The directory must have been prepared using `openssl rehash`.
+    pub fn ca_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.ca_path.replace(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Authenticate using the PEM encoded client certificate at `path`.
+    /// Requires `client_key`.
+    pub fn client_cert<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.client_cert.replace(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// The PEM encoded private key for the `client_cert`.
+    pub fn client_key<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.client_key.replace(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// The password used to decrypt the `client_key`, if it is encrypted.
+    pub fn key_password(&mut self, password: &str) -> &mut Self {
+        self.key_password.replace(password.to_string());
+        self
+    }
+
+    /// The minimum TLS protocol version to accept.
+    /// The default is determined by libmosquitto.
+    pub fn min_version(&mut self, version: TlsVersion) -> &mut Self {
+        self.min_version.replace(version);
+        self
+    }
+
+    /// An OpenSSL cipher list string, such as
+    /// `"ECDHE-ECDSA-AES256-GCM-SHA384"`.
+    pub fn ciphers(&mut self, ciphers: &str) -> &mut Self {
+        self.ciphers.replace(ciphers.to_string());
+        self
+    }
+
+    /// Offer the ALPN protocols in `protocols`, in order of preference.
+    /// Offering more than one protocol requires the `openssl` feature;
+    /// the protocol chosen by the broker is then available from
+    /// [negotiated_alpn](struct.Client.html#method.negotiated_alpn)
+    /// once connected.
+    pub fn alpn<P: AsRef<str>>(&mut self, protocols: &[P]) -> &mut Self {
+        self.alpn = protocols.iter().map(|p| p.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Disable verification of the server hostname in the server
+    /// certificate.  This applies both when the settings are handled by
+    /// libmosquitto and when an OpenSSL context is built from them.
+    ///
+    /// **WARNING**: this makes the connection vulnerable to
+    /// man-in-the-middle attacks, and must never be enabled in production.
+    pub fn insecure(&mut self, insecure: bool) -> &mut Self {
+        self.insecure = insecure;
+        self
+    }
+
+    /// Trust the CA certificates in the operating system trust store.
+    /// Cannot be combined with `ca_file` or `ca_path`.
+    /// Requires libmosquitto 2.0.11 or later.
+    pub fn use_os_certs(&mut self, use_os_certs: bool) -> &mut Self {
+        self.use_os_certs = use_os_certs;
+        self
+    }
+
+    /// Trust the CA certificates of the platform, as found in the PEM
+    /// bundle files and directories that OpenSSL uses.
+    ///
+    /// With libmosquitto 2.0.11 or later this is equivalent to
+    /// `use_os_certs`.  With older versions the CA bundle is located
+    /// by checking the `SSL_CERT_FILE` and `SSL_CERT_DIR` environment
+    /// variables and then the well known bundle locations of Linux
+    /// distributions, the BSDs, macOS and Homebrew, failing with an
+    /// error that lists the locations that were probed if none are found.
+    ///
+    /// Only those files are consulted: the macOS Keychain and the
+    /// Windows certificate store are not read, so roots that have been
+    /// added to them are not trusted.  There are no well known bundle
+    /// locations on Windows, so `SSL_CERT_FILE` must be set there.
+    ///
+    /// Cannot be combined with `ca_file` or `ca_path`.
+    pub fn system_roots(&mut self) -> &mut Self {
+        self.system_roots = true;
+        self
+    }
+
+    /// Use TLS with a pre-shared key rather than certificates.
+    /// `psk` is the key in hex format with no leading "0x", and `identity`
+    /// is the identity of this client.
+    /// Cannot be combined with certificate based settings.
+    pub fn psk(&mut self, psk: &str, identity: &str) -> &mut Self {
+        self.psk.replace((psk.to_string(), identity.to_string()));
+        self
+    }
+
+    /// Use a client private key that is held by an OpenSSL engine,
+    /// in place of `client_key`.  The engine is loaded when the config
+    /// is applied, and the key when connecting.  Requires `client_cert`.
+    ///
+    /// For example, using a key held by SoftHSM via the libp11
+    /// PKCS#11 engine:
+    ///
+    /// ```no_run
+    /// use mosquitto_rs::*;
+    ///
+    /// fn setup(client: &Client) -> Result<(), Error> {
+    ///     let mut config = TlsConfig::new();
+    ///     config
+    ///         .ca_file("ca.pem")
+    ///         .client_cert("client.pem")
+    ///         .engine(EngineConfig {
+    ///             engine_id: "pkcs11".to_string(),
+    ///             key_uri: "pkcs11:token=softhsm;object=client;type=private".to_string(),
+    ///             kpass_sha1: None,
+    ///         });
+    ///     client.configure_tls_config(&config)
+    /// }
+    /// ```
+    pub fn engine(&mut self, engine: EngineConfig) -> &mut Self {
+        self.engine.replace(engine);
+        self
+    }
+
+    /// Pin the SHA-256 fingerprint of the server's leaf certificate.
+    /// May be called multiple times to accept any one of several
+    /// certificates, for example while rotating them.
+    #[cfg_attr(docsrs, doc(cfg(feature = "openssl")))]
+    #[cfg(feature = "openssl")]
+    pub fn pin_server_fingerprint(&mut self, sha256: [u8; 32]) -> &mut Self {
+        self.pins.push(sha256);
+        self
+    }
+
+    /// Controls whether pinned fingerprints are checked in addition to,
+    /// or instead of, validating the chain against the trusted CAs.
+    /// The default is `PinMode::InAdditionToCa`.
+    #[cfg_attr(docsrs, doc(cfg(feature = "openssl")))]
+    #[cfg(feature = "openssl")]
+    pub fn pin_mode(&mut self, mode: PinMode) -> &mut Self {
+        self.pin_mode = mode;
+        self
+    }
+
+    /// Append the TLS session secrets to the file at `path`, in the
+    /// NSS key log format understood by Wireshark, so that captured
+    /// traffic can be decrypted for debugging.
+    ///
+    /// **WARNING**: anyone that can read this file can decrypt the
+    /// connection.  It is created readable only by the current user,
+    /// and is never enabled other than by calling this method; in
+    /// particular the `SSLKEYLOGFILE` environment variable is ignored.
+    #[cfg_attr(docsrs, doc(cfg(feature = "openssl")))]
+    #[cfg(feature = "openssl")]
+    pub fn keylog_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.keylog_file.replace(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Returns true if the settings can only be applied by building
+    /// an OpenSSL context, rather than by libmosquitto
+    pub(crate) fn needs_ssl_context(&self) -> bool {
+        #[cfg(feature = "openssl")]
+        if !self.pins.is_empty() || self.keylog_file.is_some() {
+            return true;
+        }
+        self.alpn.len() > 1
+    }
+
+    /// Check for combinations of settings that cannot be used together
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let invalid = |reason: &str| Err(Error::Tls(reason.to_string()));
+        let has_ca = self.ca_file.is_some() || self.ca_path.is_some();
+        let os_roots = self.use_os_certs || self.system_roots;
+
+        if let Some(engine) = &self.engine {
+            engine.validate()?;
+            if self.client_key.is_some() {
+                return invalid("engine cannot be combined with client_key");
+            }
+            if self.client_cert.is_none() {
+                return invalid("engine requires client_cert");
+            }
+            if self.needs_ssl_context() {
+                return invalid(
+                    "engine cannot be combined with settings that require an OpenSSL context",
+                );
+            }
+        } else if self.client_cert.is_some() != self.client_key.is_some() {
+            return invalid("client_cert and client_key must be specified together");
+        }
+        if self.key_password.is_some() && self.client_key.is_none() {
+            return invalid("key_password requires client_key");
+        }
+        if has_ca && os_roots {
+            return invalid(
+                "use_os_certs and system_roots cannot be combined with ca_file or ca_path",
+            );
+        }
+        if self.psk.is_some() {
+            if self.client_cert.is_some() {
+                return invalid("psk cannot be combined with a client certificate");
+            }
+            if has_ca || os_roots {
+                return invalid("psk cannot be combined with CA certificates");
+            }
+            if self.needs_ssl_context() {
+                return invalid(
+                    "psk cannot be combined with settings that require an OpenSSL context",
+                );
+            }
+        }
+        if self.needs_ssl_context() {
+            if !cfg!(feature = "openssl") {
+                return invalid("offering multiple ALPN protocols requires the openssl feature");
+            }
+        } else if !has_ca && !os_roots && self.psk.is_none() {
+            return invalid(
+                "one of ca_file, ca_path, use_os_certs, system_roots or psk is required",
+            );
+        }
+        Ok(())
+    }
+
+    /// Build the OpenSSL context for these settings.
+    /// The fingerprint of a server certificate that fails to match the
+    /// pins is recorded in `pin_mismatch`.
+    #[cfg(feature = "openssl")]
+    pub(crate) fn build_ssl_context(
+        &self,
+        pin_mismatch: Arc<Mutex<Option<String>>>,
+    ) -> Result<SslContext, Error> {
+        let tls_err = |err: openssl::error::ErrorStack| Error::Tls(err.to_string());
+        let mut builder = SslContext::builder(SslMethod::tls_client()).map_err(tls_err)?;
+
+        fn path_err(what: &str, path: &Path, err: impl std::fmt::Display) -> Error {
+            Error::Tls(format!("{what} {}: {err}", path.display()))
+        }
+
+        if let Some(ca_file) = &self.ca_file {
+            builder
+                .set_ca_file(ca_file)
+                .map_err(|err| path_err("ca_file", ca_file, err))?;
+        }
+        if let Some(ca_path) = &self.ca_path {
+            builder
+                .cert_store_mut()
+                .add_lookup(openssl::x509::store::X509Lookup::hash_dir())
+                .and_then(|lookup| lookup.add_dir(&ca_path.to_string_lossy(), SslFiletype::PEM))
+                .map_err(|err| path_err("ca_path", ca_path, err))?;
+        }
+        if self.use_os_certs || (self.ca_file.is_none() && self.ca_path.is_none()) {
+            builder.set_default_verify_paths().map_err(tls_err)?;
+        }
+
+        if let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key) {
+            builder
+                .set_certificate_chain_file(cert)
+                .map_err(|err| path_err("client_cert", cert, err))?;
+            match &self.key_password {
+                Some(password) => {
+                    let pem = std::fs::read(key).map_err(|err| path_err("client_key", key, err))?;
+                    let pkey = openssl::pkey::PKey::private_key_from_pem_passphrase(
+                        &pem,
+                        password.as_bytes(),
+                    )
+                    .map_err(|err| path_err("client_key", key, err))?;
+                    builder
+                        .set_private_key(&pkey)
+                        .map_err(|err| path_err("client_key", key, err))?;
+                }
+                None => builder
+                    .set_private_key_file(key, SslFiletype::PEM)
+                    .map_err(|err| path_err("client_key", key, err))?,
+            }
+            builder
+                .check_private_key()
+                .map_err(|err| path_err("client_key", key, err))?;
+        }
+
+        if let Some(version) = self.min_version {
+            let version = match version {
+                TlsVersion::V1_1 => SslVersion::TLS1_1,
+                TlsVersion::V1_2 => SslVersion::TLS1_2,
+                TlsVersion::V1_3 => SslVersion::TLS1_3,
+            };
+            builder
+                .set_min_proto_version(Some(version))
+                .map_err(tls_err)?;
+        }
+        if let Some(ciphers) = &self.ciphers {
+            builder
+                .set_cipher_list(ciphers)
+                .map_err(|err| Error::Tls(format!("ciphers {ciphers}: {err}")))?;
+        }
+
+        let pins = self.pins.clone();
+        let mode = self.pin_mode;
+        let insecure = self.insecure;
+        builder.set_verify_callback(SslVerifyMode::PEER, move |preverify_ok, ctx| {
+            let ok = if pins.is_empty() {
+                preverify_ok
+            } else {
+                verify_pinned(&pins, mode, &pin_mismatch, preverify_ok, ctx)
+            };
+            ok && (insecure || verify_host(ctx))
+        });
+
+        if !self.alpn.is_empty() {
+            builder
+                .set_alpn_protos(&encode_alpn(&self.alpn)?)
+                .map_err(tls_err)?;
+        }
+
+        if let Some(path) = &self.keylog_file {
+            let file = Mutex::new(open_keylog_file(path)?);
+            log::warn!("TLS session secrets are being logged to {}", path.display());
+            builder.set_keylog_callback(move |_ssl, line| {
+                let _ = writeln!(file.lock().unwrap(), "{line}");
+            });
+        }
+
+        Ok(builder.build())
+    }
+}
+
+/// Annotate an error from the TLS configuration `step` that failed,
+/// including the reasons from the OpenSSL error queue when available
+pub(crate) fn tls_step_error(step: &str, err: Error) -> Error {
+    #[cfg(feature = "openssl")]
+    {
+        let stack = openssl::error::ErrorStack::get();
+        if !stack.errors().is_empty() {
+            return Error::Tls(format!("{step}: {err}: {stack}"));
+        }
+    }
+    Error::Tls(format!("{step}: {err}"))
+}
+
+/// Drain the OpenSSL error queue of the calling thread, returning the
+/// human readable reason for each of the queued errors, oldest first.
+/// The queue is only accessible when the `openssl` feature is enabled,
+/// which links against the same OpenSSL as libmosquitto.
+#[cfg(feature = "openssl")]
+pub(crate) fn drain_openssl_errors() -> Vec<String> {
+    openssl::error::ErrorStack::get()
+        .errors()
+        .iter()
+        .map(|err| err.to_string())
+        .collect()
+}
+
+#[cfg(not(feature = "openssl"))]
+pub(crate) fn drain_openssl_errors() -> Vec<String> {
+    vec![]
+}
+
+/// The first libmosquitto version that supports `MOSQ_OPT_TLS_USE_OS_CERTS`,
+/// encoded in the same way as `LibraryVersion::version`
+pub(crate) const OS_CERTS_MIN_VERSION: c_int = 2_000_011;
+
+/// CA bundle files, in the order that they are probed by `find_system_roots`
+const CA_FILE_CANDIDATES: &[&str] = &[
+    // Debian, Ubuntu, Arch, Gentoo
+    "/etc/ssl/certs/ca-certificates.crt",
+    // Fedora, RHEL
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/pki/ca-trust/extracted/pem/tls-ca-bundle.pem",
+    // openSUSE
+    "/etc/ssl/ca-bundle.pem",
+    // Alpine, macOS, the BSDs
+    "/etc/ssl/cert.pem",
+    // Homebrew on macOS
+    "/opt/homebrew/etc/openssl@3/cert.pem",
+    "/usr/local/etc/openssl@3/cert.pem",
+    "/usr/local/etc/openssl/cert.pem",
+];
+
+/// CA certificate directories, probed after `CA_FILE_CANDIDATES`
+const CA_PATH_CANDIDATES: &[&str] = &["/etc/ssl/certs", "/etc/pki/tls/certs"];
+
+/// The location of the platform CA certificates, suitable for passing
+/// as the `ca_file` and `ca_path` parameters of `configure_tls`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct SystemRoots {
+    pub ca_file: Option<PathBuf>,
+    pub ca_path: Option<PathBuf>,
+}
+
+/// Locate the platform CA certificates, for use with versions of
+/// libmosquitto that cannot do so themselves
+pub(crate) fn find_system_roots() -> Result<SystemRoots, Error> {
+    let mut files: Vec<PathBuf> = std::env::var_os("SSL_CERT_FILE")
+        .map(PathBuf::from)
+        .into_iter()
+        .collect();
+    files.extend(CA_FILE_CANDIDATES.iter().map(PathBuf::from));
+    let mut dirs: Vec<PathBuf> = std::env::var_os("SSL_CERT_DIR")
+        .map(PathBuf::from)
+        .into_iter()
+        .collect();
+    dirs.extend(CA_PATH_CANDIDATES.iter().map(PathBuf::from));
+    probe_system_roots(&files, &dirs)
+}
+
+fn probe_system_roots(files: &[PathBuf], dirs: &[PathBuf]) -> Result<SystemRoots, Error> {
+    if let Some(file) = files.iter().find(|file| file.is_file()) {
+        return Ok(SystemRoots {
+            ca_file: Some(file.clone()),
+            ca_path: None,
+        });
+    }
+    if let Some(dir) = dirs.iter().find(|dir| dir.is_dir()) {
+        return Ok(SystemRoots {
+            ca_file: None,
+            ca_path: Some(dir.clone()),
+        });
+    }
+    let probed = files
+        .iter()
+        .chain(dirs)
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>();
+    Err(Error::Tls(format!(
+        "unable to locate the system CA certificates; probed: {}",
+        if probed.is_empty() {
+            "nothing".to_string()
+        } else {
+            probed.join(", ")
+        }
+    )))
+}
+
+#[cfg(feature = "openssl")]
+/// Encode a list of ALPN protocols in the wire format expected by
+/// OpenSSL, where each protocol is prefixed by its length
+fn encode_alpn(protocols: &[String]) -> Result<Vec<u8>, Error> {
+    let mut wire = vec![];
+    for proto in protocols {
+        let len = u8::try_from(proto.len())
+            .ok()
+            .filter(|&len| len > 0)
+            .ok_or_else(|| Error::Tls(format!("invalid ALPN protocol {proto:?}")))?;
+        wire.push(len);
+        wire.extend_from_slice(proto.as_bytes());
+    }
+    Ok(wire)
+}
+
+#[cfg(feature = "openssl")]
+fn open_keylog_file(path: &Path) -> Result<File, Error> {
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .map_err(|err| Error::Tls(format!("keylog_file {}: {err}", path.display())))
+}
+
+#[cfg(feature = "openssl")]
+/// The verify callback used when fingerprints are pinned.
+/// OpenSSL calls this for each certificate in the chain, working down
+/// to the leaf at depth 0, which is the one that is pinned.
+fn verify_pinned(
+    pins: &[[u8; 32]],
+    mode: PinMode,
+    pin_mismatch: &Mutex<Option<String>>,
+    preverify_ok: bool,
+    ctx: &mut X509StoreContextRef,
+) -> bool {
+    if mode == PinMode::InAdditionToCa && !preverify_ok {
+        return false;
+    }
+    if ctx.error_depth() != 0 {
+        return true;
+    }
+    let Some(digest) = ctx
+        .current_cert()
+        .and_then(|cert| cert.digest(MessageDigest::sha256()).ok())
+    else {
+        return false;
+    };
+    if pins.iter().any(|pin| pin[..] == digest[..]) {
+        return true;
+    }
+    let presented = hex_fingerprint(&digest);
+    log::error!("server certificate fingerprint {presented} does not match any pin");
+    pin_mismatch.lock().unwrap().replace(presented);
+    false
+}
+
+#[cfg(feature = "openssl")]
+/// Check that the server certificate matches the host that is being
+/// connected to.  libmosquitto only does this with the contexts that
+/// it builds itself, so it must be done by the verify callback of any
+/// context that we build.  The host is the SNI name that libmosquitto
+/// sets for the connection.
+/// Certificates other than the leaf at depth 0 are accepted.
+pub(crate) fn verify_host(ctx: &mut X509StoreContextRef) -> bool {
+    use foreign_types::ForeignTypeRef;
+
+    if ctx.error_depth() != 0 {
+        return true;
+    }
+    let host = X509StoreContext::ssl_idx()
+        .ok()
+        .and_then(|idx| ctx.ex_data(idx))
+        .and_then(|ssl| ssl.servername(openssl::ssl::NameType::HOST_NAME))
+        .map(str::to_string);
+    let matched = match (&host, ctx.current_cert()) {
+        (Some(host), Some(cert)) => {
+            let cert = cert.as_ptr();
+            let matched = match std::ffi::CString::new(host.as_str()) {
+                Ok(ip) if host.parse::<std::net::IpAddr>().is_ok() => unsafe {
+                    openssl_sys::X509_check_ip_asc(cert, ip.as_ptr(), 0)
+                },
+                _ => unsafe {
+                    openssl_sys::X509_check_host(
+                        cert,
+                        host.as_ptr().cast(),
+                        host.len(),
+                        0,
+                        std::ptr::null_mut(),
+                    )
+                },
+            };
+            matched == 1
+        }
+        _ => false,
+    };
+    if !matched {
+        log::error!(
+            "server certificate does not match the host {}",
+            host.as_deref().unwrap_or("(unknown)")
+        );
+        ctx.set_error(unsafe {
+            X509VerifyResult::from_raw(openssl_sys::X509_V_ERR_HOSTNAME_MISMATCH)
+        });
+    }
+    matched
+}
+
+#[cfg(feature = "openssl")]
+/// Format a fingerprint as colon separated hex bytes, as shown
+/// by `openssl x509 -fingerprint`
+fn hex_fingerprint(digest: &[u8]) -> String {
+    digest
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn system_roots() {
+        let dir = std::env::temp_dir();
+        let missing = vec![dir.join("mosquitto-rs-no-such-bundle.pem")];
+        let err = probe_system_roots(&missing, &[dir.join("mosquitto-rs-no-such-dir")])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("mosquitto-rs-no-such-bundle.pem"), "{err}");
+        assert!(err.contains("mosquitto-rs-no-such-dir"), "{err}");
+
+        assert_eq!(
+            probe_system_roots(&missing, std::slice::from_ref(&dir)).unwrap(),
+            SystemRoots {
+                ca_file: None,
+                ca_path: Some(dir.clone()),
+            }
+        );
+
+        let bundle = dir.join(format!("mosquitto-rs-bundle-{}.pem", std::process::id()));
+        std::fs::write(&bundle, b"").unwrap();
+        let files = vec![missing[0].clone(), bundle.clone()];
+        let roots = probe_system_roots(&files, &[dir]).unwrap();
+        std::fs::remove_file(&bundle).unwrap();
+        assert_eq!(roots.ca_file, Some(bundle));
+        assert_eq!(roots.ca_path, None);
+
+        let mut config = TlsConfig::new();
+        config.system_roots();
+        config.validate().unwrap();
+        config.ca_file("ca.pem");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate() {
+        let mut config = TlsConfig::new();
+        assert!(config.validate().is_err());
+        config.ca_file("ca.pem");
+        config.validate().unwrap();
+
+        config.use_os_certs(true);
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("use_os_certs"), "{err}");
+
+        let mut config = TlsConfig::new();
+        config.use_os_certs(true).client_cert("cert.pem");
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("together"), "{err}");
+        config.client_key("key.pem").key_password("secret");
+        config.validate().unwrap();
+
+        config.psk("deadbeef", "me");
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("client certificate"), "{err}");
+
+        let mut config = TlsConfig::new();
+        config.psk("deadbeef", "me");
+        config.validate().unwrap();
+        config.ca_path("/etc/ssl/certs");
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("CA certificates"), "{err}");
+
+        let mut config = TlsConfig::new();
+        config.alpn(&["mqtt", "x-amzn-mqtt-ca"]);
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "openssl"));
+    }
+
+    #[test]
+    fn validate_engine() {
+        let engine = EngineConfig {
+            engine_id: "pkcs11".to_string(),
+            key_uri: "pkcs11:object=client".to_string(),
+            kpass_sha1: Some("a94a8fe5ccb19ba61c4c0873d391e987982fbbd3".to_string()),
+        };
+        let mut config = TlsConfig::new();
+        config.ca_file("ca.pem").engine(engine.clone());
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("requires client_cert"), "{err}");
+        config.client_cert("client.pem");
+        config.validate().unwrap();
+
+        config.engine(EngineConfig {
+            kpass_sha1: Some("a94a8fe5".to_string()),
+            ..engine.clone()
+        });
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("40 hex digits"), "{err}");
+
+        config.engine(EngineConfig {
+            engine_id: String::new(),
+            ..engine.clone()
+        });
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("engine_id"), "{err}");
+
+        config.engine(engine).client_key("client.key");
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("client_key"), "{err}");
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn fingerprint_format() {
+        assert_eq!(hex_fingerprint(&[0x0a, 0xff, 0x10]), "0A:FF:10");
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn build_context() {
+        let mismatch = Arc::new(Mutex::new(None));
+        let mut config = TlsConfig::new();
+        config.build_ssl_context(mismatch.clone()).unwrap();
+
+        config
+            .pin_server_fingerprint([1; 32])
+            .pin_mode(PinMode::InsteadOfCa);
+        config.build_ssl_context(mismatch.clone()).unwrap();
+
+        config.ca_file("/does/not/exist.pem");
+        let err = config.build_ssl_context(mismatch).unwrap_err();
+        assert!(err.to_string().contains("/does/not/exist.pem"), "{err}");
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn keylog_file() {
+        let path = std::env::temp_dir().join(format!("mosquitto-rs-keylog-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut config = TlsConfig::new();
+        config.keylog_file(&path);
+        config
+            .build_ssl_context(Arc::new(Mutex::new(None)))
+            .unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        config.keylog_file("/does/not/exist/keylog");
+        let err = config
+            .build_ssl_context(Arc::new(Mutex::new(None)))
+            .unwrap_err();
+        assert!(err.to_string().contains("keylog_file"), "{err}");
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn alpn() {
+        let protos = ["mqtt".to_string(), "x-amzn-mqtt-ca".to_string()];
+        assert_eq!(encode_alpn(&protos).unwrap(), b"\x04mqtt\x0ex-amzn-mqtt-ca");
+        assert!(encode_alpn(&["".to_string()]).is_err());
+        assert!(encode_alpn(&["x".repeat(256)]).is_err());
+
+        let mut config = TlsConfig::new();
+        config.alpn(&protos);
+        config
+            .build_ssl_context(Arc::new(Mutex::new(None)))
+            .unwrap();
+    }
+
+    /// Generates a self-signed certificate for `broker.example` and
+    /// 127.0.0.1, and its key
+    #[cfg(feature = "openssl")]
+    fn broker_cert() -> (
+        openssl::x509::X509,
+        openssl::pkey::PKey<openssl::pkey::Private>,
+    ) {
+        use openssl::asn1::Asn1Time;
+        use openssl::pkey::PKey;
+        use openssl::rsa::Rsa;
+        use openssl::x509::extension::SubjectAlternativeName;
+        use openssl::x509::{X509NameBuilder, X509};
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "broker.example").unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("broker.example")
+            .ip("127.0.0.1")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (cert.build(), key)
+    }
+
+    /// Perform a TLS handshake with a local server presenting `cert`,
+    /// using `ctx` and `host` as the SNI name, as libmosquitto does.
+    /// Returns true if the client accepted the server certificate.
+    #[cfg(feature = "openssl")]
+    fn handshake(
+        ctx: &SslContext,
+        cert: &openssl::x509::X509,
+        key: &openssl::pkey::PKey<openssl::pkey::Private>,
+        host: &str,
+    ) -> bool {
+        use openssl::ssl::{Ssl, SslAcceptor};
+        use std::net::{TcpListener, TcpStream};
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(cert).unwrap();
+        acceptor.set_private_key(key).unwrap();
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = acceptor.accept(stream);
+        });
+
+        let mut ssl = Ssl::new(ctx).unwrap();
+        ssl.set_hostname(host).unwrap();
+        let result = ssl.connect(TcpStream::connect(addr).unwrap());
+        server.join().unwrap();
+        result.is_ok()
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn verify_hostname() {
+        let (cert, key) = broker_cert();
+        let ca_file =
+            std::env::temp_dir().join(format!("mosquitto-rs-broker-{}.pem", std::process::id()));
+        std::fs::write(&ca_file, cert.to_pem().unwrap()).unwrap();
+
+        let mut config = TlsConfig::new();
+        config.ca_file(&ca_file).alpn(&["mqtt", "x-amzn-mqtt-ca"]);
+        let ctx = config
+            .build_ssl_context(Arc::new(Mutex::new(None)))
+            .unwrap();
+        assert!(handshake(&ctx, &cert, &key, "broker.example"));
+        assert!(handshake(&ctx, &cert, &key, "127.0.0.1"));
+        // The certificate is trusted, but was issued for another host
+        assert!(!handshake(&ctx, &cert, &key, "attacker.example"));
+        assert!(!handshake(&ctx, &cert, &key, "127.0.0.2"));
+
+        config.insecure(true);
+        let ctx = config
+            .build_ssl_context(Arc::new(Mutex::new(None)))
+            .unwrap();
+        assert!(handshake(&ctx, &cert, &key, "attacker.example"));
+        std::fs::remove_file(&ca_file).unwrap();
+    }
+}
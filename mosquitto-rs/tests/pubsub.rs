@@ -37,3 +37,154 @@ fn pubsub() -> anyhow::Result<()> {
         Ok(())
     })
 }
+
+#[test]
+fn connect_async() -> anyhow::Result<()> {
+    let Some(server) = mqtt_server() else {
+        println!("Skipping because there is no MQTT_SERVER");
+        return Ok(());
+    };
+    smol::block_on(async {
+        let client = Client::with_auto_id()?;
+        let rc = client
+            .connect_async(&server, 1883, std::time::Duration::from_secs(5), None)
+            .await?;
+        assert!(rc.is_successful());
+
+        client
+            .publish("test/connect_async", "woot", QoS::AtMostOnce, false)
+            .await?;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn subscribe_many() -> anyhow::Result<()> {
+    let Some(server) = mqtt_server() else {
+        println!("Skipping because there is no MQTT_SERVER");
+        return Ok(());
+    };
+    smol::block_on(async {
+        let client = Client::with_auto_id()?;
+        client
+            .connect(&server, 1883, std::time::Duration::from_secs(5), None)
+            .await?;
+
+        let granted = client
+            .subscribe_many(&["test/many/a", "test/many/b"], QoS::AtLeastOnce)
+            .await?;
+        assert_eq!(
+            granted,
+            vec![
+                (
+                    "test/many/a".to_string(),
+                    GrantedQoS::Granted(QoS::AtLeastOnce)
+                ),
+                (
+                    "test/many/b".to_string(),
+                    GrantedQoS::Granted(QoS::AtLeastOnce)
+                ),
+            ]
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn publish_many() -> anyhow::Result<()> {
+    let Some(server) = mqtt_server() else {
+        println!("Skipping because there is no MQTT_SERVER");
+        return Ok(());
+    };
+    smol::block_on(async {
+        let client = Client::with_auto_id()?;
+        client
+            .connect(&server, 1883, std::time::Duration::from_secs(5), None)
+            .await?;
+
+        let results = client
+            .publish_many(
+                &["test/many/1", "test/many/2", "test/many/3"],
+                b"woot",
+                QoS::AtLeastOnce,
+                false,
+            )
+            .await;
+        assert_eq!(results.len(), 3);
+        for result in results {
+            result?;
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(feature = "router")]
+#[test]
+fn router_resubscribes_after_reconnect() -> anyhow::Result<()> {
+    use mosquitto_rs::router::{MqttRouter, State, Topic};
+    use smol::channel::Sender;
+    use std::time::Duration;
+
+    let Some(server) = mqtt_server() else {
+        println!("Skipping because there is no MQTT_SERVER");
+        return Ok(());
+    };
+
+    async fn received(State(tx): State<Sender<String>>, Topic(topic): Topic) -> anyhow::Result<()> {
+        tx.send(topic).await?;
+        Ok(())
+    }
+
+    smol::block_on(async {
+        let keep_alive = Duration::from_secs(5);
+        let id = format!("mosquitto-rs-resubscribe-{}", std::process::id());
+        let client = Client::with_id(&id, true)?;
+        let delay = Duration::from_millis(100);
+        client.set_reconnect_delay(delay, delay, false)?;
+        client.connect(&server, 1883, keep_alive, None).await?;
+
+        let prefix = format!("test/resubscribe/{}", std::process::id());
+        let mut router = MqttRouter::<Sender<String>>::new(client.clone());
+        router.route(format!("{prefix}/{{id}}"), received).await?;
+        let (tx, rx) = smol::channel::unbounded();
+        let (stop_tx, stop_rx) = smol::channel::bounded::<()>(1);
+        let run = smol::spawn(router.run(tx, async move {
+            let _ = stop_rx.recv().await;
+        }));
+
+        // Taking over the client id makes the broker disconnect the
+        // router's client, which then reconnects with a new session
+        // that has none of its subscriptions
+        let intruder = Client::with_id(&id, true)?;
+        intruder.connect(&server, 1883, keep_alive, None).await?;
+        drop(intruder);
+
+        // The handler is only called again once the route has been
+        // re-subscribed to
+        let publisher = Client::with_auto_id()?;
+        publisher.connect(&server, 1883, keep_alive, None).await?;
+        let topic = format!("{prefix}/sensor");
+        let mut delivered = None;
+        for _ in 0..50 {
+            publisher
+                .publish(&topic, "hello", QoS::AtMostOnce, false)
+                .await?;
+            delivered = smol::future::or(async { rx.recv().await.ok() }, async {
+                smol::Timer::after(Duration::from_millis(200)).await;
+                None
+            })
+            .await;
+            if delivered.is_some() {
+                break;
+            }
+        }
+        assert_eq!(delivered, Some(topic));
+
+        stop_tx.send(()).await?;
+        run.await?;
+        Ok(())
+    })
+}